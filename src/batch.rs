@@ -0,0 +1,132 @@
+//! Atomic batches of appends to a hypercore
+use crate::{tree::MerkleTreeChangeset, AppendOutcome, Hypercore, HypercoreError};
+
+/// Appends to a [`Hypercore`] staged in memory, created with [`Hypercore::batch`]. Staged
+/// values can be read through the batch, and become visible in the hypercore all at once
+/// with [`Batch::flush`], which signs them and stores them as one atomic oplog entry.
+/// [`Batch::discard`], or dropping the batch, leaves the hypercore untouched.
+#[derive(Debug)]
+pub struct Batch<'a> {
+    core: &'a mut Hypercore,
+    changeset: MerkleTreeChangeset,
+    // Encrypted if the hypercore is
+    blocks: Vec<Vec<u8>>,
+    batch_length: usize,
+}
+
+impl<'a> Batch<'a> {
+    pub(crate) fn new(core: &'a mut Hypercore) -> Self {
+        let changeset = core.tree.changeset();
+        Self {
+            core,
+            changeset,
+            blocks: vec![],
+            batch_length: 0,
+        }
+    }
+
+    /// Length of the hypercore with the staged values
+    pub fn length(&self) -> u64 {
+        self.changeset.length
+    }
+
+    /// Byte length of the hypercore with the staged values
+    pub fn byte_length(&self) -> u64 {
+        self.changeset.byte_length - self.changeset.length * self.core.padding()
+    }
+
+    /// Stages a value to be appended
+    pub fn append(&mut self, data: &[u8]) -> Result<AppendOutcome, HypercoreError> {
+        self.append_batch([data])
+    }
+
+    /// Stages a batch of values to be appended
+    pub fn append_batch<A: AsRef<[u8]>, B: AsRef<[A]>>(
+        &mut self,
+        batch: B,
+    ) -> Result<AppendOutcome, HypercoreError> {
+        if self.core.key_pair.secret.is_none() || self.core.manifest().is_static() {
+            return Err(HypercoreError::NotWritable);
+        }
+        let blocks = self
+            .core
+            .encrypt_batch(self.changeset.length, batch.as_ref());
+        for block in blocks {
+            self.batch_length += self.changeset.append(&block);
+            self.blocks.push(block.into_owned());
+        }
+        Ok(AppendOutcome {
+            length: self.length(),
+            byte_length: self.byte_length(),
+            durable: false,
+        })
+    }
+
+    /// Read value at given index, which is either staged in the batch or read from the
+    /// hypercore.
+    pub async fn get(&mut self, index: u64) -> Result<Option<Vec<u8>>, HypercoreError> {
+        let core_length = self.changeset.original_tree_length;
+        if index < core_length {
+            return self.core.get(index).await;
+        }
+        match self.blocks.get((index - core_length) as usize) {
+            Some(block) => Ok(Some(self.core.decrypt_block(index, block.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Signs the staged values and appends them to the hypercore as one atomic oplog entry
+    pub async fn flush(mut self) -> Result<AppendOutcome, HypercoreError> {
+        let mut durable = false;
+        if !self.blocks.is_empty() {
+            self.core.sign_changeset(&mut self.changeset, None)?;
+            durable = self
+                .core
+                .write_appended_changeset(&self.blocks, self.batch_length, self.changeset, true)
+                .await?;
+        }
+        let info = self.core.info();
+        Ok(AppendOutcome {
+            length: info.length,
+            byte_length: info.byte_length,
+            durable,
+        })
+    }
+
+    /// Drops the staged values without touching the hypercore
+    pub fn discard(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::tests::create_hypercore_with_data;
+
+    #[async_std::test]
+    async fn batch_flush_and_discard() -> Result<(), HypercoreError> {
+        let mut core = create_hypercore_with_data(2).await?;
+        let mut batch = core.batch();
+        assert_eq!(batch.append(b"a")?.length, 3);
+        let outcome = batch.append_batch([b"bb", b"cc"])?;
+        assert_eq!(outcome.length, 5);
+        assert_eq!(outcome.byte_length, 4 + 5);
+        assert_eq!(batch.get(0).await?, Some(b"#0".to_vec()));
+        assert_eq!(batch.get(3).await?, Some(b"bb".to_vec()));
+        assert_eq!(batch.get(5).await?, None);
+        batch.discard();
+        assert_eq!(core.info().length, 2);
+        assert_eq!(core.get(2).await?, None);
+
+        let mut batch = core.batch();
+        batch.append_batch([b"a", b"b"])?;
+        assert_eq!(batch.flush().await?.length, 4);
+        assert_eq!(core.info().length, 4);
+        assert_eq!(core.get(3).await?, Some(b"b".to_vec()));
+
+        // The tree is that of appending the values directly
+        let mut direct = create_hypercore_with_data(2).await?;
+        direct.append_batch([b"a", b"b"]).await?;
+        assert_eq!(direct.tree.head().roots, core.tree.head().roots);
+        Ok(())
+    }
+}
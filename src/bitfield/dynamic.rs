@@ -1,115 +1,160 @@
-use super::fixed::{FixedBitfield, FIXED_BITFIELD_BITS_LENGTH, FIXED_BITFIELD_LENGTH};
+use super::fixed::{FixedBitfield, FIXED_BITFIELD_BITS_LENGTH, FIXED_BITFIELD_BYTES_LENGTH};
+use super::rle;
 use crate::{
     common::{BitfieldUpdate, StoreInfo, StoreInfoInstruction, StoreInfoType},
-    Store,
+    HypercoreError, RleBitfield, Store,
 };
 use futures::future::Either;
-use std::{cell::RefCell, convert::TryInto};
+use std::{
+    cell::{Cell, RefCell},
+    convert::TryInto,
+};
 
 const DYNAMIC_BITFIELD_PAGE_SIZE: usize = 32768;
+/// How many consecutive missing pages are read from storage at once when scanning
+const DYNAMIC_BITFIELD_READ_AHEAD_PAGES: usize = 16;
+/// Most bits imported or exported at once, as the length of an imported bitfield comes
+/// from a peer
+pub(crate) const MAX_IMPORT_LENGTH: u64 = 1 << 24;
 
 /// Dynamic sized bitfield, uses a map of `FixedBitfield` elements.
 /// See:
 /// https://github.com/hypercore-protocol/hypercore/blob/master/lib/bitfield.js
 /// for reference.
+///
+/// Pages are loaded from storage on demand: operations that need a page that is not in
+/// memory return instructions to read it, which are given back with
+/// [`DynamicBitfield::load_pages`]. The least recently used pages are evicted when more than
+/// `max_loaded_pages` are in memory, except for dirty pages, which stay until flushed.
 #[derive(Debug)]
 pub(crate) struct DynamicBitfield {
     pages: intmap::IntMap<RefCell<FixedBitfield>>,
-    biggest_page_index: u64,
+    stored_length: u64,
+    max_loaded_pages: usize,
+    tick: Cell<u64>,
+    operation_start: Cell<u64>,
     unflushed: Vec<u64>,
 }
 
 impl DynamicBitfield {
-    pub(crate) fn open(info: Option<StoreInfo>) -> Either<StoreInfoInstruction, Self> {
+    /// Empty bitfield that is not backed by storage
+    pub(crate) fn new() -> Self {
+        Self {
+            pages: intmap::IntMap::new(),
+            stored_length: 0,
+            max_loaded_pages: usize::MAX,
+            tick: Cell::new(0),
+            operation_start: Cell::new(0),
+            unflushed: vec![],
+        }
+    }
+
+    /// Opens the bitfield from the size of its store, without reading any pages. At most
+    /// `memory_budget` bytes of clean pages are kept in memory.
+    pub(crate) fn open(
+        info: Option<StoreInfo>,
+        memory_budget: usize,
+    ) -> Either<StoreInfoInstruction, Self> {
         match info {
             None => Either::Left(StoreInfoInstruction::new_size(Store::Bitfield, 0)),
             Some(info) => {
-                if info.info_type == StoreInfoType::Size {
-                    let bitfield_store_length = info.length.unwrap();
-                    // Read only multiples of 4 bytes.
-                    let length = bitfield_store_length - (bitfield_store_length & 3);
-                    return Either::Left(StoreInfoInstruction::new_content(
-                        Store::Bitfield,
-                        0,
-                        length,
-                    ));
-                }
-                let data = info.data.expect("Did not receive bitfield store content");
-                let resumed = data.len() >= 4;
-                let mut biggest_page_index = 0;
-                if resumed {
-                    let mut pages: intmap::IntMap<RefCell<FixedBitfield>> = intmap::IntMap::new();
-                    let mut data_index = 0;
-                    while data_index < data.len() {
-                        let parent_index: u64 = (data_index / FIXED_BITFIELD_LENGTH) as u64;
-                        pages.insert(
-                            parent_index,
-                            RefCell::new(FixedBitfield::from_data(data_index, &data)),
-                        );
-                        if parent_index > biggest_page_index {
-                            biggest_page_index = parent_index;
-                        }
-                        data_index += FIXED_BITFIELD_LENGTH;
-                    }
-                    Either::Right(Self {
-                        pages,
-                        unflushed: vec![],
-                        biggest_page_index,
-                    })
-                } else {
-                    Either::Right(Self {
-                        pages: intmap::IntMap::new(),
-                        unflushed: vec![],
-                        biggest_page_index,
-                    })
-                }
+                assert_eq!(info.info_type, StoreInfoType::Size);
+                let bitfield_store_length = info.length.unwrap();
+                // Read only multiples of 4 bytes.
+                let stored_length = bitfield_store_length - (bitfield_store_length & 3);
+                Either::Right(Self {
+                    stored_length,
+                    max_loaded_pages: std::cmp::max(1, memory_budget / FIXED_BITFIELD_BYTES_LENGTH),
+                    ..Self::new()
+                })
             }
         }
     }
 
+    /// Loads pages read from storage with instructions returned by an operation, and evicts
+    /// the least recently used clean pages not used by the operation.
+    pub(crate) fn load_pages(&mut self, infos: &[StoreInfo]) {
+        let loaded_at = self.next_tick();
+        for info in infos {
+            let i = info.index / FIXED_BITFIELD_BYTES_LENGTH as u64;
+            if self.pages.contains_key(i) {
+                // Never replace a page in memory, it may have unflushed changes
+                continue;
+            }
+            let data = info.data.as_ref().expect("Did not receive bitfield page");
+            let mut page = FixedBitfield::from_data(0, data);
+            page.last_used = loaded_at;
+            self.pages.insert(i, RefCell::new(page));
+        }
+        self.evict(self.operation_start.get());
+    }
+
+    /// Number of pages in memory
+    #[allow(dead_code)]
+    pub(crate) fn loaded_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// First bit of the page an instruction returned by an operation reads
+    pub(crate) fn page_start(instruction: &StoreInfoInstruction) -> u64 {
+        instruction.index / FIXED_BITFIELD_BYTES_LENGTH as u64 * DYNAMIC_BITFIELD_PAGE_SIZE as u64
+    }
+
+    /// First bit after the page an instruction returned by an operation reads
+    pub(crate) fn page_end(instruction: &StoreInfoInstruction) -> u64 {
+        Self::page_start(instruction) + DYNAMIC_BITFIELD_PAGE_SIZE as u64
+    }
+
     /// Flushes pending changes, returns info slices to write to storage.
     pub(crate) fn flush(&mut self) -> Box<[StoreInfo]> {
         let mut infos_to_flush: Vec<StoreInfo> = Vec::with_capacity(self.unflushed.len());
         for unflushed_id in &self.unflushed {
             let mut p = self.pages.get_mut(*unflushed_id).unwrap().borrow_mut();
             let data = p.to_bytes();
-            infos_to_flush.push(StoreInfo::new_content(
-                Store::Bitfield,
-                *unflushed_id * data.len() as u64,
-                &data,
-            ));
+            let index = *unflushed_id * data.len() as u64;
+            infos_to_flush.push(StoreInfo::new_content(Store::Bitfield, index, &data));
+            self.stored_length = std::cmp::max(self.stored_length, index + data.len() as u64);
             p.dirty = false;
         }
         self.unflushed = vec![];
+        // Pages written to storage can now be evicted
+        self.evict(self.next_tick());
         infos_to_flush.into_boxed_slice()
     }
 
-    pub(crate) fn get(&self, index: u64) -> bool {
+    pub(crate) fn get(&self, index: u64) -> Either<Box<[StoreInfoInstruction]>, bool> {
+        self.start_operation();
         let j = index & (DYNAMIC_BITFIELD_PAGE_SIZE as u64 - 1);
         let i = (index - j) / DYNAMIC_BITFIELD_PAGE_SIZE as u64;
 
-        if !self.pages.contains_key(i) {
-            false
-        } else {
-            let p = self.pages.get(i).unwrap().borrow();
-            p.get(j.try_into().expect("Index should have fit into u32"))
+        match self.page(i) {
+            Some(p) => Either::Right(
+                p.borrow()
+                    .get(j.try_into().expect("Index should have fit into u32")),
+            ),
+            None if self.is_stored(i) => Either::Left(self.missing_pages(i..)),
+            None => Either::Right(false),
         }
     }
 
     #[allow(dead_code)]
-    pub(crate) fn set(&mut self, index: u64, value: bool) -> bool {
+    pub(crate) fn set(
+        &mut self,
+        index: u64,
+        value: bool,
+    ) -> Either<Box<[StoreInfoInstruction]>, bool> {
+        self.start_operation();
         let j = index & (DYNAMIC_BITFIELD_PAGE_SIZE as u64 - 1);
         let i = (index - j) / DYNAMIC_BITFIELD_PAGE_SIZE as u64;
 
         if !self.pages.contains_key(i) {
-            if value {
+            if self.is_stored(i) {
+                return Either::Left(self.missing_pages(i..));
+            } else if value {
                 self.pages.insert(i, RefCell::new(FixedBitfield::new()));
-                if i > self.biggest_page_index {
-                    self.biggest_page_index = i;
-                }
             } else {
                 // The page does not exist, but when setting false, that doesn't matter
-                return false;
+                return Either::Right(false);
             }
         }
 
@@ -120,10 +165,13 @@ impl DynamicBitfield {
             p.dirty = true;
             self.unflushed.push(i);
         }
-        changed
+        Either::Right(changed)
     }
 
-    pub(crate) fn update(&mut self, bitfield_update: &BitfieldUpdate) {
+    pub(crate) fn update(
+        &mut self,
+        bitfield_update: &BitfieldUpdate,
+    ) -> Either<Box<[StoreInfoInstruction]>, ()> {
         self.set_range(
             bitfield_update.start,
             bitfield_update.length,
@@ -131,22 +179,44 @@ impl DynamicBitfield {
         )
     }
 
-    pub(crate) fn set_range(&mut self, start: u64, length: u64, value: bool) {
-        let mut j = start & (DYNAMIC_BITFIELD_PAGE_SIZE as u64 - 1);
-        let mut i = (start - j) / (DYNAMIC_BITFIELD_PAGE_SIZE as u64);
+    /// Sets the range of bits. Pages in storage that are only partly in the range need to be
+    /// loaded first, pages fully in the range are overwritten without reading them.
+    pub(crate) fn set_range(
+        &mut self,
+        start: u64,
+        length: u64,
+        value: bool,
+    ) -> Either<Box<[StoreInfoInstruction]>, ()> {
+        self.start_operation();
+        if length == 0 {
+            return Either::Right(());
+        }
+        let page_size = DYNAMIC_BITFIELD_PAGE_SIZE as u64;
+        let end = start + length;
+        let mut partial_pages = vec![start / page_size];
+        if !end.is_multiple_of(page_size) && (end - 1) / page_size != start / page_size {
+            partial_pages.push((end - 1) / page_size);
+        }
+        let instructions: Vec<StoreInfoInstruction> = partial_pages
+            .into_iter()
+            .filter(|i| {
+                let page_start = i * page_size;
+                (start > page_start || end < page_start + page_size)
+                    && self.page(*i).is_none()
+                    && self.is_stored(*i)
+            })
+            .map(|i| self.page_instruction(i))
+            .collect();
+        if !instructions.is_empty() {
+            return Either::Left(instructions.into_boxed_slice());
+        }
+
+        let mut j = start & (page_size - 1);
+        let mut i = (start - j) / page_size;
         let mut length = length;
 
         while length > 0 {
-            if !self.pages.contains_key(i) {
-                self.pages.insert(i, RefCell::new(FixedBitfield::new()));
-                if i > self.biggest_page_index {
-                    self.biggest_page_index = i;
-                }
-            }
-            let mut p = self.pages.get_mut(i).unwrap().borrow_mut();
-
-            let end = std::cmp::min(j + length, DYNAMIC_BITFIELD_PAGE_SIZE as u64);
-
+            let end = std::cmp::min(j + length, page_size);
             let range_start: u32 = j
                 .try_into()
                 .expect("Range start should have fit into a u32");
@@ -154,116 +224,394 @@ impl DynamicBitfield {
                 .try_into()
                 .expect("Range end should have fit into a u32");
 
-            let changed = p.set_range(range_start, range_end, value);
-            if changed && !p.dirty {
-                p.dirty = true;
-                self.unflushed.push(i);
+            if !self.pages.contains_key(i) {
+                if self.is_stored(i) {
+                    // The whole page is replaced, so it needs to be written even if setting
+                    // the range doesn't change the empty page
+                    let mut page = FixedBitfield::new();
+                    page.dirty = true;
+                    self.pages.insert(i, RefCell::new(page));
+                    self.unflushed.push(i);
+                } else if value {
+                    self.pages.insert(i, RefCell::new(FixedBitfield::new()));
+                }
+            }
+            if let Some(p) = self.pages.get_mut(i) {
+                let mut p = p.borrow_mut();
+                let changed = p.set_range(range_start, range_end, value);
+                if changed && !p.dirty {
+                    p.dirty = true;
+                    self.unflushed.push(i);
+                }
             }
 
             j = 0;
             i += 1;
             length -= range_end as u64;
         }
+        Either::Right(())
     }
 
     /// Finds the first index of the value after given position. Returns None if not found.
-    pub(crate) fn index_of(&self, value: bool, position: u64) -> Option<u64> {
+    pub(crate) fn index_of(
+        &self,
+        value: bool,
+        position: u64,
+    ) -> Either<Box<[StoreInfoInstruction]>, Option<u64>> {
+        self.start_operation();
         let first_index = position & (DYNAMIC_BITFIELD_PAGE_SIZE as u64 - 1);
         let first_page = (position - first_index) / (DYNAMIC_BITFIELD_PAGE_SIZE as u64);
-
-        if value {
-            // For finding the first positive value, we only care about pages that are set,
-            // not pages that don't exist, as they can't possibly contain the value.
-
-            // To keep the common case fast, first try the same page as the position
-            if let Some(p) = self.pages.get(first_page) {
-                if let Some(index) = p.borrow().index_of(value, first_index as u32) {
-                    return Some(first_page * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + index as u64);
-                };
-            }
-
-            // It wasn't found on the first page, now get the keys that are bigger
-            // than the given index and sort them.
-            let mut keys: Vec<&u64> = self.pages.keys().filter(|key| **key > first_page).collect();
-            keys.sort();
-            for key in keys {
-                if let Some(p) = self.pages.get(*key) {
-                    if let Some(index) = p.borrow().index_of(value, 0) {
-                        return Some(key * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + index as u64);
-                    };
-                }
-            }
-        } else {
-            // Searching for the false value is easier as it is automatically hit on
-            // a missing page.
-            let mut i = first_page;
-            let mut j = first_index as u32;
-            while i == first_page || i <= self.biggest_page_index {
-                if let Some(p) = self.pages.get(i) {
+        let stored_pages = self.stored_pages();
+
+        // Pages that may be in storage are searched one by one. Searching for the false
+        // value is automatically hit on a missing page, so the same goes for it everywhere.
+        let mut i = first_page;
+        let mut j = first_index as u32;
+        while i < stored_pages || !value {
+            match self.page(i) {
+                Some(p) => {
                     if let Some(index) = p.borrow().index_of(value, j) {
-                        return Some(i * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + index as u64);
+                        return Either::Right(Some(
+                            i * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + index as u64,
+                        ));
                     };
-                } else {
-                    return Some(i * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + j as u64);
                 }
-                i += 1;
-                j = 0; // We start at the beginning of each page
+                None if self.is_stored(i) => return Either::Left(self.missing_pages(i..)),
+                None => {
+                    return Either::Right(Some(i * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + j as u64))
+                }
             }
+            i += 1;
+            j = 0; // We start at the beginning of each page
+        }
+
+        // For finding the first positive value past the stored pages, we only care about
+        // pages that are set, not pages that don't exist, as they can't possibly contain the
+        // value. Get the keys that are not smaller than the page and sort them.
+        let mut keys: Vec<u64> = self.pages.keys().copied().filter(|key| *key >= i).collect();
+        keys.sort();
+        for key in keys {
+            let position = if key == i { j } else { 0 };
+            if let Some(index) = self
+                .page(key)
+                .and_then(|p| p.borrow().index_of(value, position))
+            {
+                return Either::Right(Some(key * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + index as u64));
+            };
         }
-        None
+        Either::Right(None)
     }
 
     /// Finds the last index of the value before given position. Returns None if not found.
-    pub(crate) fn last_index_of(&self, value: bool, position: u64) -> Option<u64> {
+    pub(crate) fn last_index_of(
+        &self,
+        value: bool,
+        position: u64,
+    ) -> Either<Box<[StoreInfoInstruction]>, Option<u64>> {
+        self.start_operation();
         let last_index = position & (DYNAMIC_BITFIELD_PAGE_SIZE as u64 - 1);
         let last_page = (position - last_index) / (DYNAMIC_BITFIELD_PAGE_SIZE as u64);
+        let stored_pages = self.stored_pages();
 
-        if value {
-            // For finding the last positive value, we only care about pages that are set,
-            // not pages that don't exist, as they can't possibly contain the value.
-
-            // To keep the common case fast, first try the same page as the position
-            if let Some(p) = self.pages.get(last_page) {
-                if let Some(index) = p.borrow().last_index_of(value, last_index as u32) {
-                    return Some(last_page * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + index as u64);
-                };
-            }
-
-            // It wasn't found on the last page, now get the keys that are smaller
-            // than the given index and sort them.
-            let mut keys: Vec<&u64> = self.pages.keys().filter(|key| **key < last_page).collect();
-            keys.sort();
-            keys.reverse();
-
-            for key in keys {
-                if let Some(p) = self.pages.get(*key) {
-                    if let Some(index) = p
-                        .borrow()
-                        .last_index_of(value, FIXED_BITFIELD_BITS_LENGTH as u32 - 1)
+        let mut i = last_page;
+        let mut j = last_index as u32;
+        if i >= stored_pages {
+            if value {
+                // For finding the last positive value past the stored pages, we only care
+                // about pages that are set, not pages that don't exist, as they can't
+                // possibly contain the value. Get the keys that are not bigger than the
+                // page and sort them.
+                let mut keys: Vec<u64> = self
+                    .pages
+                    .keys()
+                    .copied()
+                    .filter(|key| *key <= i && *key >= stored_pages)
+                    .collect();
+                keys.sort();
+                keys.reverse();
+                for key in keys {
+                    let position = if key == i {
+                        j
+                    } else {
+                        FIXED_BITFIELD_BITS_LENGTH as u32 - 1
+                    };
+                    if let Some(index) = self
+                        .page(key)
+                        .and_then(|p| p.borrow().last_index_of(value, position))
                     {
-                        return Some(key * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + index as u64);
+                        return Either::Right(Some(
+                            key * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + index as u64,
+                        ));
                     };
                 }
+            } else {
+                // Searching for the false value is easier as it is automatically hit on
+                // a missing page.
+                while i >= stored_pages {
+                    match self.page(i) {
+                        Some(p) => {
+                            if let Some(index) = p.borrow().last_index_of(value, j) {
+                                return Either::Right(Some(
+                                    i * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + index as u64,
+                                ));
+                            };
+                        }
+                        None => {
+                            return Either::Right(Some(
+                                i * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + j as u64,
+                            ))
+                        }
+                    }
+                    if i == 0 {
+                        return Either::Right(None);
+                    }
+                    i -= 1;
+                    j = FIXED_BITFIELD_BITS_LENGTH as u32 - 1;
+                }
             }
-        } else {
-            // Searching for the false value is easier as it is automatically hit on
-            // a missing page.
-            let mut i = last_page;
-            let mut j = last_index as u32;
-            while i == last_page || i == 0 {
-                if let Some(p) = self.pages.get(i) {
+            if stored_pages == 0 {
+                return Either::Right(None);
+            }
+            i = stored_pages - 1;
+            j = FIXED_BITFIELD_BITS_LENGTH as u32 - 1;
+        }
+
+        // Pages that may be in storage are searched one by one down to the first
+        loop {
+            match self.page(i) {
+                Some(p) => {
                     if let Some(index) = p.borrow().last_index_of(value, j) {
-                        return Some(i * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + index as u64);
+                        return Either::Right(Some(
+                            i * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + index as u64,
+                        ));
                     };
-                } else {
-                    return Some(i * DYNAMIC_BITFIELD_PAGE_SIZE as u64 + j as u64);
                 }
-                i -= 1;
-                j = FIXED_BITFIELD_BITS_LENGTH as u32 - 1; // We start at end of each page
+                None => return Either::Left(self.missing_pages((0..=i).rev())),
+            }
+            if i == 0 {
+                return Either::Right(None);
+            }
+            i -= 1;
+            j = FIXED_BITFIELD_BITS_LENGTH as u32 - 1; // We start at end of each page
+        }
+    }
+
+    /// Exports the bits from `start` for `length` bits, run-length encoded for peers.
+    pub(crate) fn export(
+        &self,
+        start: u64,
+        length: u64,
+    ) -> Result<Either<Box<[StoreInfoInstruction]>, RleBitfield>, HypercoreError> {
+        validate_range(start, length)?;
+        self.start_operation();
+        let end = start + length;
+        let page_size = DYNAMIC_BITFIELD_PAGE_SIZE as u64;
+        if length > 0 {
+            let instructions: Vec<StoreInfoInstruction> = (start / page_size
+                ..=(end - 1) / page_size)
+                .filter(|i| self.page(*i).is_none() && self.is_stored(*i))
+                .map(|i| self.page_instruction(i))
+                .collect();
+            if !instructions.is_empty() {
+                return Ok(Either::Left(instructions.into_boxed_slice()));
+            }
+        }
+        let mut bytes: Vec<u8> = vec![0; length.div_ceil(8) as usize];
+        // Jump from one run of set bits to the next. All pages of the range are loaded, so
+        // a page to load means the run continues past the end.
+        let mut position = start;
+        while let Either::Right(Some(run_start)) = self.index_of(true, position) {
+            if run_start >= end {
+                break;
+            }
+            let run_end = match self.index_of(false, run_start) {
+                Either::Right(Some(index)) => std::cmp::min(index, end),
+                _ => end,
+            };
+            set_bits(&mut bytes, run_start - start, run_end - start);
+            position = run_end;
+        }
+        Ok(Either::Right(RleBitfield {
+            start,
+            length,
+            bitfield: rle::encode(&bytes),
+        }))
+    }
+
+    /// Imports bits exported with [`DynamicBitfield::export`], replacing the bits of the
+    /// range. Only for bitfields that are not backed by storage. Fails without changing any
+    /// bits if the bitfield is invalid or longer than [`MAX_IMPORT_LENGTH`].
+    pub(crate) fn import(&mut self, bitfield: &RleBitfield) -> Result<(), HypercoreError> {
+        validate_range(bitfield.start, bitfield.length)?;
+        let segments = rle::decode(&bitfield.bitfield, bitfield.length.div_ceil(8) as usize)?;
+
+        // Lengths and values of the runs of equal bits
+        let mut runs: Vec<(u64, bool)> = vec![];
+        let mut push_run = |length: u64, value: bool| match runs.last_mut() {
+            Some(last) if last.1 == value => last.0 += length,
+            _ => runs.push((length, value)),
+        };
+        for segment in segments {
+            match segment {
+                rle::Segment::Run { value, length } => push_run(length as u64 * 8, value),
+                rle::Segment::Literal(bytes) => {
+                    for byte in bytes {
+                        for bit in 0..8 {
+                            push_run(1, byte & (1 << bit) != 0);
+                        }
+                    }
+                }
             }
         }
 
-        None
+        let mut position = 0;
+        for (length, value) in runs {
+            // The last byte may have bits past the length
+            let length = std::cmp::min(length, bitfield.length - position);
+            if length == 0 {
+                break;
+            }
+            if let Either::Left(_) = self.set_range(bitfield.start + position, length, value) {
+                return Err(HypercoreError::InvalidOperation {
+                    context: "Can not import into a bitfield backed by storage".to_string(),
+                });
+            }
+            position += length;
+        }
+        Ok(())
+    }
+
+    /// Counts the set bits from start to end (exclusive). When pages need to be loaded, also
+    /// returns the count up to the first of them, from where counting continues.
+    pub(crate) fn count(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> Either<(u64, Box<[StoreInfoInstruction]>), u64> {
+        self.start_operation();
+        let page_size = DYNAMIC_BITFIELD_PAGE_SIZE as u64;
+        let page_count = |i: u64, p: &RefCell<FixedBitfield>| {
+            let page_start = i * page_size;
+            let from = start.saturating_sub(page_start) as u32;
+            let to = (std::cmp::min(end, page_start + page_size) - page_start) as u32;
+            p.borrow().count(from, to) as u64
+        };
+        let mut count = 0;
+        // Pages that may be in storage are counted in order
+        let mut i = start / page_size;
+        while i < self.stored_pages() && i * page_size < end {
+            match self.page(i) {
+                Some(p) => count += page_count(i, p),
+                None => return Either::Left((count, self.missing_pages(i..))),
+            }
+            i += 1;
+        }
+        // Past them, only looking at existing pages
+        for (key, p) in self.pages.iter() {
+            if *key >= i && key * page_size < end {
+                count += page_count(*key, p);
+            }
+        }
+        Either::Right(count)
+    }
+
+    /// Page in memory, marked as used
+    fn page(&self, i: u64) -> Option<&RefCell<FixedBitfield>> {
+        let page = self.pages.get(i)?;
+        page.borrow_mut().last_used = self.next_tick();
+        Some(page)
+    }
+
+    /// Pages used from here on are not evicted when loading the pages the operation needs
+    fn start_operation(&self) {
+        self.operation_start.set(self.next_tick());
+    }
+
+    fn next_tick(&self) -> u64 {
+        let tick = self.tick.get() + 1;
+        self.tick.set(tick);
+        tick
+    }
+
+    fn stored_pages(&self) -> u64 {
+        self.stored_length
+            .div_ceil(FIXED_BITFIELD_BYTES_LENGTH as u64)
+    }
+
+    /// Whether the page, when not in memory, needs to be read from storage
+    fn is_stored(&self, i: u64) -> bool {
+        i < self.stored_pages()
+    }
+
+    fn page_instruction(&self, i: u64) -> StoreInfoInstruction {
+        let index = i * FIXED_BITFIELD_BYTES_LENGTH as u64;
+        let length = std::cmp::min(
+            FIXED_BITFIELD_BYTES_LENGTH as u64,
+            self.stored_length - index,
+        );
+        StoreInfoInstruction::new_content(Store::Bitfield, index, length)
+    }
+
+    /// Instructions to read the pages in the given order that are missing from memory, up
+    /// to the first one that is not
+    fn missing_pages(&self, pages: impl Iterator<Item = u64>) -> Box<[StoreInfoInstruction]> {
+        let limit = std::cmp::min(self.max_loaded_pages, DYNAMIC_BITFIELD_READ_AHEAD_PAGES);
+        pages
+            .take_while(|i| !self.pages.contains_key(*i) && self.is_stored(*i))
+            .take(limit)
+            .map(|i| self.page_instruction(i))
+            .collect()
+    }
+
+    /// Evicts the least recently used clean pages last used before `before`, until at most
+    /// `max_loaded_pages` pages are in memory.
+    fn evict(&mut self, before: u64) {
+        if self.pages.len() <= self.max_loaded_pages {
+            return;
+        }
+        let mut clean: Vec<(u64, u64)> = self
+            .pages
+            .iter()
+            .map(|(i, p)| (*i, p.borrow()))
+            .filter(|(_, p)| !p.dirty && p.last_used < before)
+            .map(|(i, p)| (p.last_used, i))
+            .collect();
+        clean.sort_unstable();
+        let excess = self.pages.len() - self.max_loaded_pages;
+        for (_, i) in clean.into_iter().take(excess) {
+            self.pages.remove(i);
+        }
+    }
+}
+
+/// Checks the range of an imported or exported bitfield
+fn validate_range(start: u64, length: u64) -> Result<(), HypercoreError> {
+    if length > MAX_IMPORT_LENGTH {
+        return Err(HypercoreError::BadArgument {
+            context: format!(
+                "Bitfield of {length} blocks exceeds the maximum of {MAX_IMPORT_LENGTH}"
+            ),
+        });
+    }
+    if start.checked_add(length).is_none() {
+        return Err(HypercoreError::BadArgument {
+            context: "Bitfield range overflows".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Sets bits from `start` to `end` (exclusive) of bytes holding the lowest bit first
+fn set_bits(bytes: &mut [u8], start: u64, end: u64) {
+    let mut i = start;
+    while i < end {
+        if i.is_multiple_of(8) && end - i >= 8 {
+            bytes[(i / 8) as usize] = 0xff;
+            i += 8;
+        } else {
+            bytes[(i / 8) as usize] |= 1 << (i % 8);
+            i += 1;
+        }
     }
 }
 
@@ -273,128 +621,260 @@ mod tests {
 
     fn assert_value_range(bitfield: &DynamicBitfield, start: u64, length: u64, value: bool) {
         for i in start..start + length {
-            assert_eq!(bitfield.get(i), value);
+            assert_eq!(in_memory(bitfield.get(i)), value);
         }
     }
 
     fn get_dynamic_bitfield() -> DynamicBitfield {
-        match DynamicBitfield::open(Some(StoreInfo::new_content(Store::Bitfield, 0, &[]))) {
+        match DynamicBitfield::open(Some(StoreInfo::new_size(Store::Bitfield, 0, 0)), usize::MAX) {
             Either::Left(_) => panic!("Could not open bitfield"),
             Either::Right(bitfield) => bitfield,
         }
     }
 
+    /// Result of an operation that did not need pages from storage
+    fn in_memory<L, T>(result: Either<L, T>) -> T {
+        match result {
+            Either::Left(_) => panic!("Bitfield needs pages from storage"),
+            Either::Right(value) => value,
+        }
+    }
+
     #[test]
     fn bitfield_dynamic_get_and_set() {
         let mut bitfield = get_dynamic_bitfield();
         assert_value_range(&bitfield, 0, 9, false);
-        assert_eq!(bitfield.index_of(true, 0), None);
-        assert_eq!(bitfield.index_of(false, 0), Some(0));
-        assert_eq!(bitfield.last_index_of(true, 9), None);
-        assert_eq!(bitfield.last_index_of(false, 9), Some(9));
-        assert_eq!(bitfield.index_of(true, 10000000), None);
-        assert_eq!(bitfield.index_of(false, 10000000), Some(10000000));
-        assert_eq!(bitfield.last_index_of(true, 10000000), None);
-        assert_eq!(bitfield.last_index_of(false, 10000000), Some(10000000));
-
-        bitfield.set(0, true);
-        assert!(bitfield.get(0));
-        assert_eq!(bitfield.index_of(true, 0), Some(0));
-        assert_eq!(bitfield.index_of(false, 0), Some(1));
-        assert_eq!(bitfield.last_index_of(true, 9), Some(0));
-        assert_eq!(bitfield.last_index_of(false, 9), Some(9));
-        assert_eq!(bitfield.last_index_of(true, 10000000), Some(0));
-        assert_eq!(bitfield.last_index_of(false, 10000000), Some(10000000));
+        assert_eq!(in_memory(bitfield.index_of(true, 0)), None);
+        assert_eq!(in_memory(bitfield.index_of(false, 0)), Some(0));
+        assert_eq!(in_memory(bitfield.last_index_of(true, 9)), None);
+        assert_eq!(in_memory(bitfield.last_index_of(false, 9)), Some(9));
+        assert_eq!(in_memory(bitfield.index_of(true, 10000000)), None);
+        assert_eq!(
+            in_memory(bitfield.index_of(false, 10000000)),
+            Some(10000000)
+        );
+        assert_eq!(in_memory(bitfield.last_index_of(true, 10000000)), None);
+        assert_eq!(
+            in_memory(bitfield.last_index_of(false, 10000000)),
+            Some(10000000)
+        );
+
+        in_memory(bitfield.set(0, true));
+        assert!(in_memory(bitfield.get(0)));
+        assert_eq!(in_memory(bitfield.index_of(true, 0)), Some(0));
+        assert_eq!(in_memory(bitfield.index_of(false, 0)), Some(1));
+        assert_eq!(in_memory(bitfield.last_index_of(true, 9)), Some(0));
+        assert_eq!(in_memory(bitfield.last_index_of(false, 9)), Some(9));
+        assert_eq!(in_memory(bitfield.last_index_of(true, 10000000)), Some(0));
+        assert_eq!(
+            in_memory(bitfield.last_index_of(false, 10000000)),
+            Some(10000000)
+        );
 
         assert_value_range(&bitfield, 1, 63, false);
-        bitfield.set(31, true);
-        assert!(bitfield.get(31));
+        in_memory(bitfield.set(31, true));
+        assert!(in_memory(bitfield.get(31)));
 
         assert_value_range(&bitfield, 32, 32, false);
-        assert!(!bitfield.get(32));
-        bitfield.set(32, true);
-        assert!(bitfield.get(32));
+        assert!(!in_memory(bitfield.get(32)));
+        in_memory(bitfield.set(32, true));
+        assert!(in_memory(bitfield.get(32)));
         assert_value_range(&bitfield, 33, 31, false);
 
         assert_value_range(&bitfield, 32760, 8, false);
-        assert!(!bitfield.get(32767));
-        bitfield.set(32767, true);
-        assert!(bitfield.get(32767));
+        assert!(!in_memory(bitfield.get(32767)));
+        in_memory(bitfield.set(32767, true));
+        assert!(in_memory(bitfield.get(32767)));
         assert_value_range(&bitfield, 32760, 7, false);
 
         // Now for over one fixed bitfield values
-        bitfield.set(32768, true);
+        in_memory(bitfield.set(32768, true));
         assert_value_range(&bitfield, 32767, 2, true);
         assert_value_range(&bitfield, 32769, 9, false);
 
-        bitfield.set(10000000, true);
-        assert!(bitfield.get(10000000));
+        in_memory(bitfield.set(10000000, true));
+        assert!(in_memory(bitfield.get(10000000)));
         assert_value_range(&bitfield, 9999990, 10, false);
         assert_value_range(&bitfield, 10000001, 9, false);
-        assert_eq!(bitfield.index_of(false, 32767), Some(32769));
-        assert_eq!(bitfield.index_of(true, 32769), Some(10000000));
-        assert_eq!(bitfield.last_index_of(true, 9999999), Some(32768));
+        assert_eq!(in_memory(bitfield.index_of(false, 32767)), Some(32769));
+        assert_eq!(in_memory(bitfield.index_of(true, 32769)), Some(10000000));
+        assert_eq!(
+            in_memory(bitfield.last_index_of(true, 9999999)),
+            Some(32768)
+        );
+    }
+
+    #[test]
+    fn bitfield_dynamic_count() {
+        let mut bitfield = get_dynamic_bitfield();
+        assert_eq!(in_memory(bitfield.count(0, 10000000)), 0);
+        in_memory(bitfield.set_range(32760, 20, true));
+        in_memory(bitfield.set(10000000, true));
+        assert_eq!(in_memory(bitfield.count(0, 10000001)), 21);
+        assert_eq!(in_memory(bitfield.count(0, 10000000)), 20);
+        assert_eq!(in_memory(bitfield.count(32768, 32780)), 12);
+        assert_eq!(in_memory(bitfield.count(32770, 32770)), 0);
+    }
+
+    #[test]
+    fn bitfield_dynamic_last_index_of() {
+        let mut bitfield = get_dynamic_bitfield();
+        // No false value before a full first page
+        in_memory(bitfield.set_range(0, 32780, true));
+        assert_eq!(in_memory(bitfield.last_index_of(false, 5)), None);
+        assert_eq!(in_memory(bitfield.last_index_of(false, 32785)), Some(32785));
+
+        // The search continues through the full pages between the first and last one
+        in_memory(bitfield.set_range(0, 3 * 32768 + 10, true));
+        in_memory(bitfield.set(5, false));
+        assert_eq!(
+            in_memory(bitfield.last_index_of(false, 3 * 32768 + 5)),
+            Some(5)
+        );
+        assert_eq!(in_memory(bitfield.last_index_of(true, 5)), Some(4));
+    }
+
+    /// Runs the operation, loading the pages it needs from the store
+    fn with_store<T>(
+        bitfield: &mut DynamicBitfield,
+        store: &[u8],
+        mut operation: impl FnMut(&mut DynamicBitfield) -> Either<Box<[StoreInfoInstruction]>, T>,
+    ) -> T {
+        loop {
+            match operation(bitfield) {
+                Either::Left(instructions) => bitfield.load_pages(&read(store, &instructions)),
+                Either::Right(value) => return value,
+            }
+        }
+    }
+
+    fn read(store: &[u8], instructions: &[StoreInfoInstruction]) -> Vec<StoreInfo> {
+        instructions
+            .iter()
+            .map(|instruction| {
+                let start = instruction.index as usize;
+                let end = start + instruction.length.unwrap() as usize;
+                StoreInfo::new_content(Store::Bitfield, instruction.index, &store[start..end])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bitfield_dynamic_load_pages() {
+        let mut bitfield = get_dynamic_bitfield();
+        in_memory(bitfield.set_range(10, 5, true));
+        in_memory(bitfield.set_range(2 * 32768 + 3, 32768, true));
+        let mut store = vec![0; 4 * FIXED_BITFIELD_BYTES_LENGTH];
+        for info in bitfield.flush().iter() {
+            let data = info.data.as_ref().unwrap();
+            store[info.index as usize..info.index as usize + data.len()].copy_from_slice(data);
+        }
+
+        // Memory for two pages
+        let mut bitfield = match DynamicBitfield::open(
+            Some(StoreInfo::new_size(Store::Bitfield, 0, store.len() as u64)),
+            2 * FIXED_BITFIELD_BYTES_LENGTH,
+        ) {
+            Either::Left(_) => panic!("Could not open bitfield"),
+            Either::Right(bitfield) => bitfield,
+        };
+        assert_eq!(bitfield.loaded_pages(), 0);
+        // Pages fully in the range are overwritten without reading them
+        in_memory(bitfield.set_range(32768, 32768, false));
+        assert_eq!(bitfield.loaded_pages(), 1);
+
+        assert!(with_store(&mut bitfield, &store, |b| b.get(10)));
+        assert!(with_store(&mut bitfield, &store, |b| b.get(2 * 32768 + 3)));
+        // The dirty page is kept, the clean pages were evicted down to the budget
+        assert_eq!(bitfield.loaded_pages(), 3);
+        assert!(!in_memory(bitfield.get(32768)));
+        assert!(in_memory(bitfield.get(3 * 32768 + 2)));
+        assert!(matches!(bitfield.get(10), Either::Left(_)));
+
+        // Partly changed pages are read first, and are kept until flushed
+        with_store(&mut bitfield, &store, |b| b.set_range(0, 3, true));
+        assert_eq!(bitfield.loaded_pages(), 2);
+        assert!(in_memory(bitfield.get(0)));
+        let mut start = 0;
+        let mut count = 0;
+        loop {
+            match bitfield.count(start, 4 * 32768) {
+                Either::Left((counted, instructions)) => {
+                    count += counted;
+                    start = std::cmp::max(start, DynamicBitfield::page_start(&instructions[0]));
+                    bitfield.load_pages(&read(&store, &instructions));
+                }
+                Either::Right(counted) => {
+                    count += counted;
+                    break;
+                }
+            }
+        }
+        assert_eq!(count, 3 + 5 + 32768);
+
+        bitfield.flush();
+        assert!(bitfield.loaded_pages() <= 2);
     }
 
     #[test]
     fn bitfield_dynamic_set_range() {
         let mut bitfield = get_dynamic_bitfield();
-        bitfield.set_range(0, 2, true);
+        in_memory(bitfield.set_range(0, 2, true));
         assert_value_range(&bitfield, 0, 2, true);
         assert_value_range(&bitfield, 3, 61, false);
 
-        bitfield.set_range(2, 3, true);
+        in_memory(bitfield.set_range(2, 3, true));
         assert_value_range(&bitfield, 0, 5, true);
         assert_value_range(&bitfield, 5, 59, false);
 
-        bitfield.set_range(1, 3, false);
-        assert!(bitfield.get(0));
+        in_memory(bitfield.set_range(1, 3, false));
+        assert!(in_memory(bitfield.get(0)));
         assert_value_range(&bitfield, 1, 3, false);
         assert_value_range(&bitfield, 4, 1, true);
         assert_value_range(&bitfield, 5, 59, false);
 
-        bitfield.set_range(30, 30070, true);
+        in_memory(bitfield.set_range(30, 30070, true));
         assert_value_range(&bitfield, 5, 25, false);
         assert_value_range(&bitfield, 30, 100, true);
         assert_value_range(&bitfield, 30050, 50, true);
         assert_value_range(&bitfield, 31000, 50, false);
 
-        bitfield.set_range(32750, 18, true);
+        in_memory(bitfield.set_range(32750, 18, true));
         assert_value_range(&bitfield, 32750, 18, true);
 
-        bitfield.set_range(32765, 3, false);
+        in_memory(bitfield.set_range(32765, 3, false));
         assert_value_range(&bitfield, 32750, 15, true);
         assert_value_range(&bitfield, 32765, 3, false);
 
         // Now for over one fixed bitfield values
-        bitfield.set_range(32765, 15, true);
+        in_memory(bitfield.set_range(32765, 15, true));
         assert_value_range(&bitfield, 32765, 15, true);
         assert_value_range(&bitfield, 32780, 9, false);
-        bitfield.set_range(32766, 3, false);
+        in_memory(bitfield.set_range(32766, 3, false));
         assert_value_range(&bitfield, 32766, 3, false);
 
-        bitfield.set_range(10000000, 50, true);
+        in_memory(bitfield.set_range(10000000, 50, true));
         assert_value_range(&bitfield, 9999990, 9, false);
         assert_value_range(&bitfield, 10000050, 9, false);
-        assert_eq!(bitfield.index_of(true, 32780), Some(10000000));
-        bitfield.set_range(0, 32780, false);
+        assert_eq!(in_memory(bitfield.index_of(true, 32780)), Some(10000000));
+        in_memory(bitfield.set_range(0, 32780, false));
         // Manufacture empty pages to test sorting
-        bitfield.set(900000, true);
-        bitfield.set(900000, false);
-        bitfield.set(300000, true);
-        bitfield.set(300000, false);
-        bitfield.set(200000, true);
-        bitfield.set(200000, false);
-        bitfield.set(500000, true);
-        bitfield.set(500000, false);
-        bitfield.set(100000, true);
-        bitfield.set(100000, false);
-        bitfield.set(700000, true);
-        bitfield.set(700000, false);
-        assert_eq!(bitfield.index_of(true, 0), Some(10000000));
-        assert_eq!(bitfield.last_index_of(true, 9999999), None);
-
-        bitfield.set_range(10000010, 10, false);
+        in_memory(bitfield.set(900000, true));
+        in_memory(bitfield.set(900000, false));
+        in_memory(bitfield.set(300000, true));
+        in_memory(bitfield.set(300000, false));
+        in_memory(bitfield.set(200000, true));
+        in_memory(bitfield.set(200000, false));
+        in_memory(bitfield.set(500000, true));
+        in_memory(bitfield.set(500000, false));
+        in_memory(bitfield.set(100000, true));
+        in_memory(bitfield.set(100000, false));
+        in_memory(bitfield.set(700000, true));
+        in_memory(bitfield.set(700000, false));
+        assert_eq!(in_memory(bitfield.index_of(true, 0)), Some(10000000));
+        assert_eq!(in_memory(bitfield.last_index_of(true, 9999999)), None);
+
+        in_memory(bitfield.set_range(10000010, 10, false));
         assert_value_range(&bitfield, 10000000, 10, true);
         assert_value_range(&bitfield, 10000010, 10, false);
         assert_value_range(&bitfield, 10000020, 30, true);
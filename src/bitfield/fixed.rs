@@ -15,6 +15,8 @@ use std::convert::TryInto;
 #[derive(Debug)]
 pub(crate) struct FixedBitfield {
     pub(crate) dirty: bool,
+    /// When the page was last used, for evicting the least recently used pages
+    pub(crate) last_used: u64,
     bitfield: [u32; FIXED_BITFIELD_LENGTH],
 }
 
@@ -22,6 +24,7 @@ impl FixedBitfield {
     pub(crate) fn new() -> Self {
         Self {
             dirty: false,
+            last_used: 0,
             bitfield: [0; FIXED_BITFIELD_LENGTH],
         }
     }
@@ -42,6 +45,7 @@ impl FixedBitfield {
         }
         Self {
             dirty: false,
+            last_used: 0,
             bitfield,
         }
     }
@@ -133,12 +137,73 @@ impl FixedBitfield {
 
     /// Finds the first index of the value after given position. Returns None if not found.
     pub(crate) fn index_of(&self, value: bool, position: u32) -> Option<u32> {
-        (position..FIXED_BITFIELD_BITS_LENGTH as u32).find(|&i| self.get(i) == value)
+        if position >= FIXED_BITFIELD_BITS_LENGTH as u32 {
+            return None;
+        }
+        let n = FIXED_BITFIELD_BITS_PER_ELEM;
+        let mut i = (position / n) as usize;
+        // Bits before the position are masked out of the first element
+        let mut elem = self.elem_with(i, value) & (u32::MAX << (position & (n - 1)));
+        loop {
+            if elem != 0 {
+                return Some(i as u32 * n + elem.trailing_zeros());
+            }
+            i += 1;
+            if i == FIXED_BITFIELD_LENGTH {
+                return None;
+            }
+            elem = self.elem_with(i, value);
+        }
     }
 
     /// Finds the last index of the value before given position. Returns None if not found.
     pub(crate) fn last_index_of(&self, value: bool, position: u32) -> Option<u32> {
-        (0..position + 1).rev().find(|&i| self.get(i) == value)
+        let n = FIXED_BITFIELD_BITS_PER_ELEM;
+        let position = std::cmp::min(position, FIXED_BITFIELD_BITS_LENGTH as u32 - 1);
+        let mut i = (position / n) as usize;
+        // Bits after the position are masked out of the first element
+        let mut elem = self.elem_with(i, value) & (u32::MAX >> (n - 1 - (position & (n - 1))));
+        loop {
+            if elem != 0 {
+                return Some(i as u32 * n + n - 1 - elem.leading_zeros());
+            }
+            if i == 0 {
+                return None;
+            }
+            i -= 1;
+            elem = self.elem_with(i, value);
+        }
+    }
+
+    /// Counts the set bits from start to end (exclusive)
+    pub(crate) fn count(&self, start: u32, end: u32) -> u32 {
+        if start >= end {
+            return 0;
+        }
+        let n = FIXED_BITFIELD_BITS_PER_ELEM;
+        let first = (start / n) as usize;
+        let last = ((end - 1) / n) as usize;
+        let mut count = 0;
+        for i in first..=last {
+            let mut elem = self.bitfield[i];
+            if i == first {
+                elem &= u32::MAX << (start & (n - 1));
+            }
+            if i == last {
+                elem &= u32::MAX >> (n - 1 - ((end - 1) & (n - 1)));
+            }
+            count += elem.count_ones();
+        }
+        count
+    }
+
+    /// Element at given index, with the bits flipped when looking for unset bits
+    fn elem_with(&self, i: usize, value: bool) -> u32 {
+        if value {
+            self.bitfield[i]
+        } else {
+            !self.bitfield[i]
+        }
     }
 }
 
@@ -191,6 +256,24 @@ mod tests {
         assert_eq!(bitfield.last_index_of(true, 32766), Some(32));
     }
 
+    #[test]
+    fn bitfield_fixed_count() {
+        let mut bitfield = FixedBitfield::new();
+        assert_eq!(bitfield.count(0, 32768), 0);
+        bitfield.set_range(30, 40, true);
+        bitfield.set(32767, true);
+        assert_eq!(bitfield.count(0, 32768), 41);
+        assert_eq!(bitfield.count(31, 69), 38);
+        assert_eq!(bitfield.count(31, 31), 0);
+        assert_eq!(bitfield.count(69, 70), 1);
+        assert_eq!(bitfield.count(70, 32767), 0);
+        assert_eq!(bitfield.index_of(false, 30), Some(70));
+        assert_eq!(bitfield.index_of(true, 70), Some(32767));
+        assert_eq!(bitfield.index_of(false, 32767), None);
+        assert_eq!(bitfield.last_index_of(false, 69), Some(29));
+        assert_eq!(bitfield.last_index_of(true, 32766), Some(69));
+    }
+
     #[test]
     fn bitfield_fixed_set_range() {
         let mut bitfield = FixedBitfield::new();
@@ -1,4 +1,7 @@
 mod dynamic;
 mod fixed;
+mod remote;
+mod rle;
 
 pub(crate) use dynamic::DynamicBitfield as Bitfield;
+pub use remote::RemoteBitfield;
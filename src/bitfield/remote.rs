@@ -0,0 +1,183 @@
+use super::dynamic::DynamicBitfield;
+use crate::{HypercoreError, RleBitfield};
+use futures::future::Either;
+
+/// Blocks of a hypercore that a peer has, built up from the bitfields it sends, see
+/// [`crate::Hypercore::export_bitfield`], and from the ranges it announces. Kept only in
+/// memory.
+#[derive(Debug)]
+pub struct RemoteBitfield {
+    bitfield: DynamicBitfield,
+}
+
+impl Default for RemoteBitfield {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RemoteBitfield {
+    /// Empty bitfield, where the peer has no blocks
+    pub fn new() -> Self {
+        Self {
+            bitfield: DynamicBitfield::new(),
+        }
+    }
+
+    /// Set the blocks of the range of the bitfield to what the peer sent. Bitfields of more
+    /// than 2^24 blocks, or whose range overflows, are rejected.
+    pub fn import(&mut self, bitfield: &RleBitfield) -> Result<(), HypercoreError> {
+        self.bitfield.import(bitfield)
+    }
+
+    /// Set the blocks from `start` for `length` blocks as present or missing
+    pub fn set_range(&mut self, start: u64, length: u64, value: bool) {
+        in_memory(self.bitfield.set_range(start, length, value))
+    }
+
+    #[cfg(feature = "replication")]
+    /// Apply a [`crate::replication::events::Have`] of the peer
+    pub fn apply_have(&mut self, have: &crate::replication::events::Have) {
+        self.set_range(have.start, have.length, !have.drop)
+    }
+
+    /// Check if the peer has the block at the given index
+    pub fn get(&self, index: u64) -> bool {
+        in_memory(self.bitfield.get(index))
+    }
+
+    /// Check if the peer has all blocks from `start` to `end` (exclusive)
+    pub fn has_range(&self, start: u64, end: u64) -> bool {
+        !matches!(self.index_of(false, start), Some(index) if index < end)
+    }
+
+    /// Finds the first index from `position` onwards where the peer has the block, if `value`
+    /// is true, or misses it otherwise.
+    pub fn index_of(&self, value: bool, position: u64) -> Option<u64> {
+        in_memory(self.bitfield.index_of(value, position))
+    }
+
+    /// Finds the last index up to and including `position` where the peer has the block, if
+    /// `value` is true, or misses it otherwise.
+    pub fn last_index_of(&self, value: bool, position: u64) -> Option<u64> {
+        in_memory(self.bitfield.last_index_of(value, position))
+    }
+
+    /// Number of blocks the peer has from `start` to `end` (exclusive)
+    pub fn count(&self, start: u64, end: u64) -> u64 {
+        in_memory(self.bitfield.count(start, end))
+    }
+}
+
+/// Result of an operation on the bitfield, which never needs pages from storage
+fn in_memory<L, T>(result: Either<L, T>) -> T {
+    match result {
+        Either::Right(value) => value,
+        Either::Left(_) => unreachable!("Remote bitfields are not backed by storage"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitfield::dynamic::MAX_IMPORT_LENGTH;
+
+    #[test]
+    fn bitfield_remote_import() -> Result<(), HypercoreError> {
+        let mut local = DynamicBitfield::new();
+        in_memory(local.set_range(3, 5, true));
+        in_memory(local.set_range(100, 40000, true));
+        in_memory(local.set(50000, true));
+
+        let exported = in_memory(local.export(2, 60000)?);
+        assert_eq!(exported.start, 2);
+        assert_eq!(exported.length, 60000);
+        // Long runs compress well
+        assert!(exported.bitfield.len() < 32);
+
+        let mut remote = RemoteBitfield::new();
+        remote.set_range(0, 3, true);
+        remote.import(&exported)?;
+        assert!(remote.get(0));
+        assert!(remote.get(1));
+        assert!(!remote.get(2));
+        assert!(remote.has_range(3, 8));
+        assert!(!remote.has_range(3, 9));
+        assert_eq!(remote.index_of(true, 8), Some(100));
+        assert_eq!(remote.index_of(false, 100), Some(40100));
+        assert_eq!(remote.last_index_of(true, 49999), Some(40099));
+        assert_eq!(remote.index_of(true, 40100), Some(50000));
+        assert_eq!(remote.count(0, 60002), 2 + 5 + 40000 + 1);
+
+        // Imports replace the bits of their range
+        remote.import(&in_memory(DynamicBitfield::new().export(0, 10)?))?;
+        assert_eq!(remote.index_of(true, 0), Some(100));
+        assert!(remote
+            .import(&RleBitfield {
+                start: 0,
+                length: 100,
+                bitfield: vec![0x01],
+            })
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bitfield_remote_import_invalid_range() {
+        let mut remote = RemoteBitfield::new();
+        assert!(remote
+            .import(&RleBitfield {
+                start: 0,
+                length: u64::MAX,
+                bitfield: vec![],
+            })
+            .is_err());
+        assert!(remote
+            .import(&RleBitfield {
+                start: u64::MAX - 7,
+                length: 16,
+                bitfield: vec![0x0b],
+            })
+            .is_err());
+        // A single run header claiming more blocks than the maximum
+        assert!(remote
+            .import(&RleBitfield {
+                start: 0,
+                length: MAX_IMPORT_LENGTH + 8,
+                bitfield: vec![0x87, 0x80, 0x80, 0x04],
+            })
+            .is_err());
+        assert_eq!(remote.count(0, u64::MAX), 0);
+
+        // A run of the maximum length is imported without expanding it
+        remote
+            .import(&RleBitfield {
+                start: 0,
+                length: MAX_IMPORT_LENGTH,
+                bitfield: vec![0x83, 0x80, 0x80, 0x04],
+            })
+            .unwrap();
+        assert_eq!(remote.count(0, u64::MAX), MAX_IMPORT_LENGTH);
+    }
+
+    #[cfg(feature = "replication")]
+    #[test]
+    fn bitfield_remote_apply_have() {
+        use crate::replication::events::Have;
+
+        let mut remote = RemoteBitfield::new();
+        remote.apply_have(&Have {
+            start: 10,
+            length: 10,
+            drop: false,
+        });
+        remote.apply_have(&Have {
+            start: 15,
+            length: 2,
+            drop: true,
+        });
+        assert!(remote.has_range(10, 15));
+        assert_eq!(remote.index_of(true, 15), Some(17));
+        assert_eq!(remote.count(0, 100), 8);
+    }
+}
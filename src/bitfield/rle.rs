@@ -0,0 +1,177 @@
+//! Run-length encoding of bitfields, compatible with Javascript's
+//! [bitfield-rle](https://github.com/mafintosh/bitfield-rle).
+//!
+//! The encoding is a sequence of segments, each starting with a varint header. A header
+//! with the lowest bit set is a run of `header >> 2` bytes, all `0xff` if the second bit is
+//! set and all `0x00` otherwise. Any other header is followed by `header >> 1` literal bytes.
+use crate::HypercoreError;
+
+/// Runs shorter than this are cheaper to keep in a literal segment
+const MIN_RUN_LENGTH: usize = 4;
+
+/// Segment of run-length encoded bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Segment<'a> {
+    /// Run of `length` bytes, all `0xff` if `value` and all `0x00` otherwise
+    Run { value: bool, length: usize },
+    /// Bytes as they are
+    Literal(&'a [u8]),
+}
+
+/// Run-length encode the given bytes
+pub(crate) fn encode(bytes: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        if byte != 0x00 && byte != 0xff {
+            i += 1;
+            continue;
+        }
+        let run_end = bytes[i..]
+            .iter()
+            .position(|b| *b != byte)
+            .map_or(bytes.len(), |position| i + position);
+        if run_end - i >= MIN_RUN_LENGTH {
+            write_literal(&mut out, &bytes[literal_start..i]);
+            let header = ((run_end - i) << 2) | if byte == 0xff { 3 } else { 1 };
+            write_varint(&mut out, header as u64);
+            literal_start = run_end;
+        }
+        i = run_end;
+    }
+    write_literal(&mut out, &bytes[literal_start..]);
+    out
+}
+
+/// Decode run-length encoded bytes, which need to decode to exactly `length` bytes, into
+/// their segments. Runs are not expanded, so the memory used is bounded by `data`.
+pub(crate) fn decode(data: &[u8], length: usize) -> Result<Vec<Segment<'_>>, HypercoreError> {
+    let mut segments: Vec<Segment<'_>> = vec![];
+    let mut decoded_length: usize = 0;
+    let mut data = data;
+    while !data.is_empty() {
+        let (header, rest) = read_varint(data)?;
+        let repeat = header & 1 == 1;
+        let segment_length = if repeat { header >> 2 } else { header >> 1 };
+        let segment_length: usize = match usize::try_from(segment_length) {
+            Ok(segment_length) if segment_length <= length - decoded_length => segment_length,
+            _ => return Err(invalid_rle("Segment exceeds the bitfield length")),
+        };
+        if repeat {
+            segments.push(Segment::Run {
+                value: header & 2 == 2,
+                length: segment_length,
+            });
+            data = rest;
+        } else {
+            if rest.len() < segment_length {
+                return Err(invalid_rle("Literal segment is cut short"));
+            }
+            segments.push(Segment::Literal(&rest[..segment_length]));
+            data = &rest[segment_length..];
+        }
+        decoded_length += segment_length;
+    }
+    if decoded_length != length {
+        return Err(invalid_rle("Bitfield is shorter than its length"));
+    }
+    Ok(segments)
+}
+
+fn write_literal(out: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        write_varint(out, (bytes.len() << 1) as u64);
+        out.extend_from_slice(bytes);
+    }
+}
+
+// NB: bitfield-rle uses protobuf style varints, not compact encoding uints
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8]) -> Result<(u64, &[u8]), HypercoreError> {
+    let mut value: u64 = 0;
+    for (i, byte) in data.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, &data[i + 1..]));
+        }
+    }
+    Err(invalid_rle("Invalid varint"))
+}
+
+fn invalid_rle(context: &str) -> HypercoreError {
+    HypercoreError::InvalidOperation {
+        context: format!("Invalid run-length encoded bitfield: {context}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(data: &[u8], length: usize) -> Result<Vec<u8>, HypercoreError> {
+        let mut bytes: Vec<u8> = vec![];
+        for segment in decode(data, length)? {
+            match segment {
+                Segment::Run { value, length } => {
+                    bytes.resize(bytes.len() + length, if value { 0xff } else { 0x00 })
+                }
+                Segment::Literal(literal) => bytes.extend_from_slice(literal),
+            }
+        }
+        Ok(bytes)
+    }
+
+    #[test]
+    fn bitfield_rle_encode_and_decode() -> Result<(), HypercoreError> {
+        let mut bytes = vec![0xff; 300];
+        bytes.extend([0x00; 2]);
+        bytes.extend([0x01, 0x80, 0xff, 0xff]);
+        bytes.extend([0x00; 1000]);
+        bytes.push(0x0f);
+        let encoded = encode(&bytes);
+        // 300 << 2 | 3 as a varint, then a literal of 6 bytes, a run of zeros and a literal
+        assert_eq!(&encoded[..3], &[0xb3, 0x09, 0x0c]);
+        assert_eq!(encoded.len(), 3 + 6 + 2 + 2);
+        assert_eq!(decode_bytes(&encoded, bytes.len())?, bytes);
+
+        assert!(encode(&[]).is_empty());
+        assert_eq!(decode_bytes(&[], 0)?, Vec::<u8>::new());
+
+        // A run of four 0xff bytes followed by a literal byte
+        assert_eq!(
+            encode(&[0xff, 0xff, 0xff, 0xff, 0x01]),
+            vec![0x13, 0x02, 0x01]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bitfield_rle_decode_invalid() -> Result<(), HypercoreError> {
+        // Run longer than the bitfield
+        assert!(decode(&[0x13], 3).is_err());
+        // Literal cut short
+        assert!(decode(&[0x06, 0x01], 3).is_err());
+        // Too short
+        assert!(decode(&[0x0d], 4).is_err());
+        // Unterminated varint
+        assert!(decode(&[0x80], 4).is_err());
+        // A huge run is not expanded
+        assert_eq!(
+            decode(&[0x83, 0x80, 0x80, 0x80, 0x10], 1 << 30)?,
+            vec![Segment::Run {
+                value: true,
+                length: 1 << 30
+            }]
+        );
+        Ok(())
+    }
+}
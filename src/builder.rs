@@ -5,7 +5,10 @@ use tracing::instrument;
 
 #[cfg(feature = "cache")]
 use crate::common::cache::CacheOptions;
-use crate::{core::HypercoreOptions, Hypercore, HypercoreError, PartialKeypair, Storage};
+use crate::{
+    core::HypercoreOptions, CoreId, Durability, Hypercore, HypercoreError, Manifest,
+    PartialKeypair, Storage,
+};
 
 /// Build CacheOptions.
 #[cfg(feature = "cache")]
@@ -72,12 +75,93 @@ impl HypercoreBuilder {
         self
     }
 
+    /// Set the key of the hypercore to open or create. A new hypercore with only a key is
+    /// read-only, e.g. one replicated from a peer. Building fails if the storage contains
+    /// a different hypercore.
+    pub fn key(mut self, id: CoreId) -> Self {
+        self.options.key = Some(*id.key());
+        self
+    }
+
+    /// Set manifest of a new hypercore, whose key is then the manifest hash instead of the
+    /// public key of the key pair. The key pair must be that of a manifest signer to be
+    /// able to append.
+    pub fn manifest(mut self, manifest: Manifest) -> Self {
+        self.options.manifest = Some(manifest);
+        self
+    }
+
     /// Set open.
     pub fn open(mut self, open: bool) -> Self {
         self.options.open = open;
         self
     }
 
+    /// Freeze the opened hypercore into a static hypercore, whose key is the hash of a
+    /// manifest pinning the current tree, see [`Manifest::new_static`]. Static hypercores
+    /// can not be appended to or truncated. The key pair is kept. Needs [`Self::open`].
+    ///
+    /// Freezing changes the identity of the hypercore: its key and discovery key change,
+    /// so peers and stores that look up the hypercore by the old key, e.g. the storage path
+    /// of a `Corestore`, no longer find it. Hypercores that were ever opened with an
+    /// [`Self::encryption_key`] can not be frozen, as the block encryption is derived from
+    /// the key.
+    pub fn freeze(mut self, freeze: bool) -> Self {
+        self.options.freeze = freeze;
+        self
+    }
+
+    /// Recover a hypercore whose oplog header slots are both invalid. The header is rebuilt
+    /// from the tree, the bitfield and the valid oplog entries, and the oplog is rewritten.
+    /// The manifest and secret key are taken from the damaged headers that can still be
+    /// decoded, and the signature if it is still valid. Otherwise the tree is signed again
+    /// with the secret key, which needs the fork, see [`Self::recovery_fork`]. Fails if the
+    /// tree can not be signed. Needs the key pair, or the key or manifest, of the hypercore. An intact
+    /// hypercore opens as usual.
+    pub fn recover(mut self, recover: bool) -> Self {
+        self.options.recover = recover;
+        self
+    }
+
+    /// Set the fork of the hypercore to [`Self::recover`], for when it can not be read from
+    /// the damaged oplog. It is needed to sign the recovered tree with the secret key, as
+    /// signing with a wrong fork could conflict with a tree already published.
+    pub fn recovery_fork(mut self, fork: u64) -> Self {
+        self.options.recovery_fork = Some(fork);
+        self
+    }
+
+    /// Set encryption key. Blocks are encrypted with a key derived from this and the key
+    /// of the hypercore, compatible with the `encryptionKey` option of Javascript. The
+    /// encryption key is not stored and must be given every time the hypercore is opened.
+    pub fn encryption_key(mut self, encryption_key: [u8; 32]) -> Self {
+        self.options.encryption_key = Some(encryption_key);
+        self
+    }
+
+    /// Set how many operations are stored only to the oplog before the bitfield, tree and
+    /// header are flushed, by default 3. A longer interval gives more throughput, but more
+    /// oplog entries to replay when opening the hypercore. Use [`Hypercore::flush`] to flush
+    /// explicitly.
+    pub fn flush_interval(mut self, operations: u64) -> Self {
+        self.options.flush_interval = operations;
+        self
+    }
+
+    /// Set when writes are synced to storage, by default [`Durability::None`]
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.options.durability = durability;
+        self
+    }
+
+    /// Set how many bytes of bitfield pages are kept in memory, by default 16 MiB. Pages are
+    /// read from storage when needed, and the least recently used ones are evicted, except
+    /// for pages with changes that are not flushed yet.
+    pub fn bitfield_memory_budget(mut self, bytes: usize) -> Self {
+        self.options.bitfield_memory_budget = bytes;
+        self
+    }
+
     /// Set node cache options.
     #[cfg(feature = "cache")]
     pub fn node_cache_options(mut self, builder: CacheOptionsBuilder) -> Self {
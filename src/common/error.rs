@@ -48,6 +48,18 @@ pub enum HypercoreError {
         /// Context for the error
         context: String,
     },
+    /// Value could not be decoded
+    #[error("Could not decode value. {context}")]
+    Decode {
+        /// Context for the error
+        context: String,
+    },
+    /// Snapshot no longer available
+    #[error("Snapshot not available. {context}")]
+    SnapshotNotAvailable {
+        /// Context for the error
+        context: String,
+    },
     /// Unexpected IO error occured
     #[error("Unrecoverable input/output error occured.{}",
           .context.as_ref().map_or_else(String::new, |ctx| format!(" {ctx}.")))]
@@ -11,6 +11,7 @@ pub(crate) use self::node::NodeByteRange;
 pub(crate) use self::peer::ValuelessProof;
 pub use self::peer::{
     DataBlock, DataHash, DataSeek, DataUpgrade, Proof, RequestBlock, RequestSeek, RequestUpgrade,
+    RleBitfield,
 };
 pub use self::store::Store;
 pub(crate) use self::store::{StoreInfo, StoreInfoInstruction, StoreInfoType};
@@ -116,3 +116,17 @@ pub struct DataUpgrade {
     /// TODO: Document
     pub signature: Vec<u8>,
 }
+
+#[derive(Debug, Clone, PartialEq)]
+/// Availability of a range of blocks to peer, with one bit per block run-length encoded.
+/// Created with [`crate::Hypercore::export_bitfield`] and read with
+/// [`crate::RemoteBitfield::import`].
+pub struct RleBitfield {
+    /// Hypercore index of the first block
+    pub start: u64,
+    /// Number of blocks
+    pub length: u64,
+    /// Bits of the blocks, lowest bit of the first byte first, encoded like Javascript's
+    /// [bitfield-rle](https://github.com/mafintosh/bitfield-rle)
+    pub bitfield: Vec<u8>,
+}
@@ -1,27 +1,47 @@
 //! Hypercore's main abstraction. Exposes an append-only, secure log structure.
-use ed25519_dalek::Signature;
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use futures::future::Either;
-use std::convert::TryFrom;
+use futures::stream::Stream;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
+use std::ops::Range;
 use tracing::instrument;
 
 #[cfg(feature = "cache")]
 use crate::common::cache::CacheOptions;
 use crate::{
+    batch::Batch,
     bitfield::Bitfield,
-    common::{BitfieldUpdate, HypercoreError, NodeByteRange, Proof, StoreInfo, ValuelessProof},
-    crypto::{generate_signing_key, PartialKeypair},
+    common::{
+        BitfieldUpdate, HypercoreError, Node, NodeByteRange, Proof, RleBitfield, Store, StoreInfo,
+        StoreInfoInstruction, ValuelessProof,
+    },
+    crypto::{
+        discovery_key, generate_signing_key, BlockEncryption, Hash, Manifest, PartialKeypair,
+        PartialSignature, Verifier, BLOCK_ENCRYPTION_PADDING,
+    },
     data::BlockStore,
-    oplog::{Header, Oplog, MAX_OPLOG_ENTRIES_BYTE_SIZE},
-    storage::Storage,
-    tree::{MerkleTree, MerkleTreeChangeset},
-    RequestBlock, RequestSeek, RequestUpgrade,
+    oplog::{Header, Oplog, ReorgHint, MAX_OPLOG_ENTRIES_BYTE_SIZE},
+    snapshot::Snapshot,
+    storage::{Durability, Storage},
+    tree::{MerkleTree, MerkleTreeChangeset, MerkleTreeHead},
+    CoreId, RequestBlock, RequestSeek, RequestUpgrade,
 };
 
 #[derive(Debug)]
 pub(crate) struct HypercoreOptions {
     pub(crate) key_pair: Option<PartialKeypair>,
+    pub(crate) key: Option<[u8; 32]>,
+    pub(crate) manifest: Option<Manifest>,
     pub(crate) open: bool,
+    pub(crate) encryption_key: Option<[u8; 32]>,
+    pub(crate) freeze: bool,
+    pub(crate) recover: bool,
+    pub(crate) recovery_fork: Option<u64>,
+    pub(crate) flush_interval: u64,
+    pub(crate) durability: Durability,
+    pub(crate) bitfield_memory_budget: usize,
     #[cfg(feature = "cache")]
     pub(crate) node_cache_options: Option<CacheOptions>,
 }
@@ -30,7 +50,16 @@ impl HypercoreOptions {
     pub(crate) fn new() -> Self {
         Self {
             key_pair: None,
+            key: None,
+            manifest: None,
             open: false,
+            encryption_key: None,
+            freeze: false,
+            recover: false,
+            recovery_fork: None,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            durability: Durability::None,
+            bitfield_memory_budget: DEFAULT_BITFIELD_MEMORY_BUDGET,
             #[cfg(feature = "cache")]
             node_cache_options: None,
         }
@@ -46,8 +75,12 @@ pub struct Hypercore {
     pub(crate) tree: MerkleTree,
     pub(crate) block_store: BlockStore,
     pub(crate) bitfield: Bitfield,
-    skip_flush_count: u8, // autoFlush in Javascript
+    pub(crate) skip_flush_count: u64, // autoFlush in Javascript
+    flush_interval: u64,
+    durability: Durability,
     header: Header,
+    verifier: Verifier,
+    encryption: Option<BlockEncryption>,
     #[cfg(feature = "replication")]
     events: crate::replication::events::Events,
 }
@@ -59,6 +92,8 @@ pub struct AppendOutcome {
     pub length: u64,
     /// Byte length of the hypercore after append
     pub byte_length: u64,
+    /// Whether the append was synced to storage, see [`Durability`]
+    pub durable: bool,
 }
 
 /// Info about the hypercore
@@ -77,48 +112,174 @@ pub struct Info {
     pub writeable: bool,
 }
 
+/// Range of blocks that are either all present or all missing locally, see
+/// [`Hypercore::block_ranges`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockRange {
+    /// Index of the first block of the range
+    pub start: u64,
+    /// Index after the last block of the range
+    pub end: u64,
+    /// Whether the blocks of the range are present locally
+    pub present: bool,
+}
+
+/// Corruption of local storage found by [`Hypercore::verify_integrity`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Number of locally present blocks that were checked
+    pub checked_blocks: u64,
+    /// Indices of blocks whose data does not hash to their leaf node
+    pub bad_blocks: Vec<u64>,
+    /// Tree indices of nodes that are missing, that do not hash from their children, or of
+    /// roots that differ from the signed ones
+    pub bad_nodes: Vec<u64>,
+    /// Whether the signature of the tree is valid for its roots
+    pub valid_signature: bool,
+}
+
+impl IntegrityReport {
+    /// Whether no corruption was found
+    pub fn is_ok(&self) -> bool {
+        self.valid_signature && self.bad_blocks.is_empty() && self.bad_nodes.is_empty()
+    }
+}
+
+/// Options for [`Hypercore::read_stream`]
+#[derive(Debug, Clone, Default)]
+pub struct ReadStreamOptions {
+    /// Read blocks from the end of the range towards the start
+    pub reverse: bool,
+    /// Skip blocks not available locally instead of returning an error
+    pub skip_missing: bool,
+}
+
+/// How many operations are stored only to the oplog between flushes by default
+pub(crate) const DEFAULT_FLUSH_INTERVAL: u64 = 3;
+
+/// How many bytes of bitfield pages are kept in memory by default
+pub(crate) const DEFAULT_BITFIELD_MEMORY_BUDGET: usize = 16 * 1024 * 1024;
+
+/// How many blocks a read stream reads from storage at a time
+const READ_STREAM_BATCH_SIZE: u64 = 64;
+
+/// How many blocks are checked at a time when verifying integrity
+const VERIFY_INTEGRITY_BATCH_SIZE: u64 = 64;
+
+/// Number of the latest reorgs to keep hints of, same as in Javascript
+const MAX_REORG_HINTS: usize = 4;
+
+/// User data key marking a hypercore opened with an encryption key, which can not be frozen
+const ENCRYPTED_USER_DATA_KEY: &str = "hypercore/encrypted";
+
 impl Hypercore {
     /// Creates/opens new hypercore using given storage and options
     pub(crate) async fn new(
         mut storage: Storage,
         mut options: HypercoreOptions,
     ) -> Result<Hypercore, HypercoreError> {
-        let key_pair: Option<PartialKeypair> = if options.open {
-            if options.key_pair.is_some() {
+        // Key pair of a hypercore created in an empty storage. An existing hypercore uses the
+        // key pair stored in its header, so failing to build this only matters when creating.
+        let new_key_pair: Option<Result<PartialKeypair, HypercoreError>> = if options.open {
+            if options.key_pair.is_some() || options.manifest.is_some() {
                 return Err(HypercoreError::BadArgument {
-                    context: "Key pair can not be used when building an openable hypercore"
-                        .to_string(),
+                    context:
+                        "Key pair or manifest can not be used when building an openable hypercore"
+                            .to_string(),
                 });
             }
             None
+        } else if options.freeze {
+            return Err(HypercoreError::BadArgument {
+                context: "Only an opened hypercore can be frozen".to_string(),
+            });
         } else {
-            Some(options.key_pair.take().unwrap_or_else(|| {
-                let signing_key = generate_signing_key();
-                PartialKeypair {
-                    public: signing_key.verifying_key(),
-                    secret: Some(signing_key),
-                }
-            }))
+            Some(
+                match (options.key_pair.clone(), &options.manifest, options.key) {
+                    (Some(key_pair), _, _) => Ok(key_pair),
+                    // Without a key pair, the hypercore is read-only
+                    (None, Some(manifest), _) => {
+                        manifest.signer_public_key().map(|public| PartialKeypair {
+                            public,
+                            secret: None,
+                        })
+                    }
+                    (None, None, Some(key)) => VerifyingKey::from_bytes(&key)
+                        .map(|public| PartialKeypair {
+                            public,
+                            secret: None,
+                        })
+                        .map_err(|_| HypercoreError::BadArgument {
+                            context: "Key is not a public key, a manifest is needed".to_string(),
+                        }),
+                    (None, None, None) => {
+                        let signing_key = generate_signing_key();
+                        Ok(PartialKeypair {
+                            public: signing_key.verifying_key(),
+                            secret: Some(signing_key),
+                        })
+                    }
+                },
+            )
+        };
+        let (key_pair, key_pair_error) = match new_key_pair {
+            Some(Ok(key_pair)) => (Some(key_pair), None),
+            Some(Err(err)) => (None, Some(err)),
+            None => (None, None),
+        };
+        let expected_key = match (&options.manifest, options.key) {
+            (Some(manifest), _) => Some(manifest.key()?),
+            (None, key) => key,
         };
 
         // Open/create oplog
-        let mut oplog_open_outcome = match Oplog::open(&key_pair, None)? {
-            Either::Right(value) => value,
-            Either::Left(instruction) => {
-                let info = storage.read_info(instruction).await?;
-                match Oplog::open(&key_pair, Some(info))? {
-                    Either::Right(value) => value,
-                    Either::Left(_) => {
-                        return Err(HypercoreError::InvalidOperation {
-                            context: "Could not open oplog".to_string(),
-                        });
+        let mut oplog_open_outcome =
+            match Oplog::open(&key_pair, &options.manifest, None, options.recover)? {
+                Either::Right(value) => value,
+                Either::Left(instruction) => {
+                    let info = storage.read_info(instruction).await?;
+                    match Oplog::open(&key_pair, &options.manifest, Some(info), options.recover) {
+                        Ok(Either::Right(value)) => value,
+                        Ok(Either::Left(_)) => {
+                            return Err(HypercoreError::InvalidOperation {
+                                context: "Could not open oplog".to_string(),
+                            });
+                        }
+                        // The storage is empty, and the key pair to create a hypercore failed
+                        Err(err @ HypercoreError::EmptyStorage { .. }) => {
+                            return Err(key_pair_error.unwrap_or(err));
+                        }
+                        Err(err) => return Err(err),
                     }
                 }
-            }
-        };
+            };
+        // NB: Only a given key, manifest or key pair identifies the hypercore expected in the
+        // storage, a key pair derived from a key or manifest is only used to create one.
+        let stored_key = oplog_open_outcome.header.key;
+        let stored_public = oplog_open_outcome.header.key_pair.public;
+        if expected_key.is_some_and(|key| key != stored_key)
+            || options
+                .key_pair
+                .take()
+                .is_some_and(|key_pair| key_pair.public != stored_public)
+        {
+            return Err(HypercoreError::BadArgument {
+                context: "Another hypercore is stored in the storage".to_string(),
+            });
+        }
         storage
             .flush_infos(&oplog_open_outcome.infos_to_flush)
             .await?;
+        let recovering = oplog_open_outcome.recovery.is_some();
+        if let Some(damaged_headers) = oplog_open_outcome.recovery.take() {
+            recover_header_tree(
+                &mut storage,
+                &mut oplog_open_outcome.header,
+                damaged_headers,
+                options.recovery_fork,
+            )
+            .await?;
+        }
 
         // Open/create tree
         let mut tree = match MerkleTree::open(
@@ -149,42 +310,54 @@ impl Hypercore {
         // Create block store instance
         let block_store = BlockStore::default();
 
-        // Open bitfield
-        let mut bitfield = match Bitfield::open(None) {
+        // Open bitfield, its pages are read from storage when needed
+        let mut bitfield = match Bitfield::open(None, options.bitfield_memory_budget) {
             Either::Right(value) => value,
             Either::Left(instruction) => {
                 let info = storage.read_info(instruction).await?;
-                match Bitfield::open(Some(info)) {
+                match Bitfield::open(Some(info), options.bitfield_memory_budget) {
                     Either::Right(value) => value,
-                    Either::Left(instruction) => {
-                        let info = storage.read_info(instruction).await?;
-                        match Bitfield::open(Some(info)) {
-                            Either::Right(value) => value,
-                            Either::Left(_) => {
-                                return Err(HypercoreError::InvalidOperation {
-                                    context: "Could not open bitfield".to_string(),
-                                });
-                            }
-                        }
+                    Either::Left(_) => {
+                        return Err(HypercoreError::InvalidOperation {
+                            context: "Could not open bitfield".to_string(),
+                        });
                     }
                 }
             }
         };
 
+        if recovering {
+            let length = oplog_open_outcome.header.tree.length;
+            oplog_open_outcome.header.hints.contiguous_length =
+                bitfield_index_of(&mut storage, &mut bitfield, false, 0)
+                    .await?
+                    .map_or(length, |index| std::cmp::min(index, length));
+        }
+
         // Process entries stored only to the oplog and not yet flushed into bitfield or tree
         if let Some(entries) = oplog_open_outcome.entries {
             for entry in entries.iter() {
+                if let Some(user_data) = &entry.user_data {
+                    oplog_open_outcome
+                        .header
+                        .update_user_data(&user_data.key, &user_data.value);
+                }
                 for node in &entry.tree_nodes {
                     tree.add_node(node.clone());
                 }
 
                 if let Some(bitfield_update) = &entry.bitfield {
-                    bitfield.update(bitfield_update);
+                    with_bitfield(&mut storage, &mut bitfield, |bitfield| {
+                        bitfield.update(bitfield_update)
+                    })
+                    .await?;
                     update_contiguous_length(
+                        &mut storage,
                         &mut oplog_open_outcome.header,
-                        &bitfield,
+                        &mut bitfield,
                         bitfield_update,
-                    );
+                    )
+                    .await?;
                 }
                 if let Some(tree_upgrade) = &entry.tree_upgrade {
                     // TODO: Generalize Either response stack
@@ -212,12 +385,12 @@ impl Hypercore {
                         };
                     changeset.ancestors = tree_upgrade.ancestors;
                     changeset.hash = Some(changeset.hash());
-                    changeset.signature =
-                        Some(Signature::try_from(&*tree_upgrade.signature).map_err(|_| {
-                            HypercoreError::InvalidSignature {
-                                context: "Could not parse changeset signature".to_string(),
-                            }
-                        })?);
+                    // NB: A truncation to zero length is stored without a signature
+                    changeset.signature = if tree_upgrade.signature.is_empty() {
+                        None
+                    } else {
+                        Some(tree_upgrade.signature.clone())
+                    };
 
                     // Update the header with this changeset to make in-memory value match that
                     // of the stored value.
@@ -227,8 +400,11 @@ impl Hypercore {
                         &mut oplog_open_outcome.header,
                     )?;
 
-                    // TODO: Skip reorg hints for now, seems to only have to do with replication
-                    // addReorgHint(header.hints.reorgs, tree, batch)
+                    add_reorg_hint(
+                        &mut oplog_open_outcome.header.hints.reorgs,
+                        &tree,
+                        &changeset,
+                    );
 
                     // Commit changeset to in-memory tree
                     tree.commit(changeset)?;
@@ -239,8 +415,12 @@ impl Hypercore {
         let oplog = oplog_open_outcome.oplog;
         let header = oplog_open_outcome.header;
         let key_pair = header.key_pair.clone();
+        let verifier = Verifier::new(&header.key, &header.manifest)?;
+        let encryption = options.encryption_key.map(|encryption_key| {
+            BlockEncryption::new(&encryption_key, &header.key, verifier.compat())
+        });
 
-        Ok(Hypercore {
+        let mut hypercore = Hypercore {
             key_pair,
             storage,
             oplog,
@@ -248,23 +428,48 @@ impl Hypercore {
             block_store,
             bitfield,
             header,
+            verifier,
+            encryption,
             skip_flush_count: 0,
+            flush_interval: options.flush_interval,
+            durability: options.durability,
             #[cfg(feature = "replication")]
             events: crate::replication::events::Events::new(),
-        })
+        };
+        if recovering {
+            // Rewrite both header slots and drop the replayed entries
+            hypercore.flush_and_sync(true, true).await?;
+        }
+        // Mark the hypercore as encrypted, so that it is not frozen without the encryption key
+        if hypercore.encryption.is_some()
+            && hypercore.get_user_data(ENCRYPTED_USER_DATA_KEY).is_none()
+        {
+            hypercore
+                .set_user_data(ENCRYPTED_USER_DATA_KEY, Some(&[1]))
+                .await?;
+        }
+        if options.freeze {
+            hypercore.freeze().await?;
+        }
+        Ok(hypercore)
     }
 
     /// Gets basic info about the Hypercore
     pub fn info(&self) -> Info {
         Info {
             length: self.tree.length,
-            byte_length: self.tree.byte_length,
+            byte_length: self.byte_length(),
             contiguous_length: self.header.hints.contiguous_length,
             fork: self.tree.fork,
-            writeable: self.key_pair.secret.is_some(),
+            writeable: self.key_pair.secret.is_some() && !self.header.manifest.is_static(),
         }
     }
 
+    /// Create a read-only snapshot pinned to the current length of the hypercore
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.key_pair.public, self.tree.head())
+    }
+
     /// Appends a data slice to the hypercore.
     #[instrument(err, skip_all, fields(data_len = data.len()))]
     pub async fn append(&mut self, data: &[u8]) -> Result<AppendOutcome, HypercoreError> {
@@ -277,11 +482,86 @@ impl Hypercore {
         &mut self,
         batch: B,
     ) -> Result<AppendOutcome, HypercoreError> {
-        let secret_key = match &self.key_pair.secret {
-            Some(key) => key,
-            None => return Err(HypercoreError::NotWritable),
-        };
+        self.append_batch_with_optional_signature(batch, None).await
+    }
 
+    /// Appends a given batch of data slices to the hypercore with a signature of the tree
+    /// after the append, assembled with [`Manifest::assemble_signature`] from the
+    /// [`PartialSignature`]s of a quorum of signers. Does not need a secret key.
+    #[instrument(err, skip_all, fields(batch_len = batch.as_ref().len()))]
+    pub async fn append_batch_with_signature<A: AsRef<[u8]>, B: AsRef<[A]>>(
+        &mut self,
+        batch: B,
+        signature: &[u8],
+    ) -> Result<AppendOutcome, HypercoreError> {
+        self.append_batch_with_optional_signature(batch, Some(signature))
+            .await
+    }
+
+    /// Signs the tree the hypercore would have after appending the given batch, as the
+    /// signer of the manifest with the given signing key.
+    pub fn sign_batch<A: AsRef<[u8]>, B: AsRef<[A]>>(
+        &self,
+        batch: B,
+        signing_key: &SigningKey,
+    ) -> Result<PartialSignature, HypercoreError> {
+        let mut changeset = self.tree.changeset();
+        for block in self.encrypt_batch(self.tree.length, batch.as_ref()) {
+            changeset.append(block.as_ref());
+        }
+        self.verifier.sign_partial(
+            signing_key,
+            &changeset.hash(),
+            changeset.length,
+            changeset.fork,
+        )
+    }
+
+    async fn append_batch_with_optional_signature<A: AsRef<[u8]>, B: AsRef<[A]>>(
+        &mut self,
+        batch: B,
+        signature: Option<&[u8]>,
+    ) -> Result<AppendOutcome, HypercoreError> {
+        if self.encryption.is_some() {
+            let blocks: Vec<Cow<'_, [u8]>> = self.encrypt_batch(self.tree.length, batch.as_ref());
+            let blocks: Vec<Vec<u8>> = blocks.into_iter().map(Cow::into_owned).collect();
+            self.append_blocks(&blocks, signature).await
+        } else {
+            self.append_blocks(batch, signature).await
+        }
+    }
+
+    /// Blocks are encrypted before they are hashed into the tree. The first block of the
+    /// batch has index `start`.
+    pub(crate) fn encrypt_batch<'a, A: AsRef<[u8]>>(
+        &self,
+        start: u64,
+        batch: &'a [A],
+    ) -> Vec<Cow<'a, [u8]>> {
+        batch
+            .iter()
+            .enumerate()
+            .map(|(i, value)| match &self.encryption {
+                Some(encryption) => {
+                    Cow::Owned(encryption.encrypt(start + i as u64, value.as_ref(), self.tree.fork))
+                }
+                None => Cow::Borrowed(value.as_ref()),
+            })
+            .collect()
+    }
+
+    async fn append_blocks<A: AsRef<[u8]>, B: AsRef<[A]>>(
+        &mut self,
+        batch: B,
+        signature: Option<&[u8]>,
+    ) -> Result<AppendOutcome, HypercoreError> {
+        if self.header.manifest.is_static()
+            || (signature.is_none() && self.key_pair.secret.is_none())
+        {
+            return Err(HypercoreError::NotWritable);
+        }
+
+        let mut durable = false;
         if !batch.as_ref().is_empty() {
             // Create a changeset for the tree
             let mut changeset = self.tree.changeset();
@@ -289,57 +569,234 @@ impl Hypercore {
             for data in batch.as_ref().iter() {
                 batch_length += changeset.append(data.as_ref());
             }
-            changeset.hash_and_sign(secret_key);
+            self.sign_changeset(&mut changeset, signature)?;
+            durable = self
+                .write_appended_changeset(batch, batch_length, changeset, false)
+                .await?;
+        }
 
-            // Write the received data to the block store
-            let info =
-                self.block_store
-                    .append_batch(batch.as_ref(), batch_length, self.tree.byte_length);
-            self.storage.flush_info(info).await?;
+        // Return the new value
+        Ok(AppendOutcome {
+            length: self.tree.length,
+            byte_length: self.byte_length(),
+            durable,
+        })
+    }
 
-            // Append the changeset to the Oplog
-            let bitfield_update = BitfieldUpdate {
-                drop: false,
-                start: changeset.ancestors,
-                length: changeset.batch_length,
-            };
-            let outcome = self.oplog.append_changeset(
-                &changeset,
-                Some(bitfield_update.clone()),
-                false,
-                &self.header,
-            )?;
-            self.storage.flush_infos(&outcome.infos_to_flush).await?;
-            self.header = outcome.header;
+    /// Sets the signature of a changeset appending to the tree, either verifying the given
+    /// signature or signing with the secret key.
+    pub(crate) fn sign_changeset(
+        &self,
+        changeset: &mut MerkleTreeChangeset,
+        signature: Option<&[u8]>,
+    ) -> Result<(), HypercoreError> {
+        if self.header.manifest.is_static() {
+            return Err(HypercoreError::NotWritable);
+        }
+        match (signature, &self.key_pair.secret) {
+            (Some(signature), _) => changeset.verify_and_set_signature(signature, &self.verifier),
+            (None, Some(secret_key)) => changeset.hash_and_sign(&self.verifier, secret_key),
+            (None, None) => Err(HypercoreError::NotWritable),
+        }
+    }
 
-            // Write to bitfield
-            self.bitfield.update(&bitfield_update);
+    /// Writes the blocks of a signed changeset appending to the tree, and commits it. With
+    /// `atomic`, the changeset is stored to the oplog as an atomic batch. Returns whether
+    /// the append was synced to storage.
+    pub(crate) async fn write_appended_changeset<A: AsRef<[u8]>, B: AsRef<[A]>>(
+        &mut self,
+        batch: B,
+        batch_length: usize,
+        changeset: MerkleTreeChangeset,
+        atomic: bool,
+    ) -> Result<bool, HypercoreError> {
+        let mut durable = self.durability == Durability::SyncEveryAppend;
+
+        // Write the received data to the block store
+        let info =
+            self.block_store
+                .append_batch(batch.as_ref(), batch_length, self.tree.byte_length);
+        self.storage.flush_info(info).await?;
+        if durable {
+            // Blocks need to be durable before the oplog entry referring to them
+            self.storage.sync(&[Store::Data]).await?;
+        }
 
-            // Contiguous length is known only now
-            update_contiguous_length(&mut self.header, &self.bitfield, &bitfield_update);
+        // Append the changeset to the Oplog
+        let bitfield_update = BitfieldUpdate {
+            drop: false,
+            start: changeset.ancestors,
+            length: changeset.batch_length,
+        };
+        let outcome = self.oplog.append_changeset(
+            &changeset,
+            Some(bitfield_update.clone()),
+            atomic,
+            &self.header,
+        )?;
+        self.storage.flush_infos(&outcome.infos_to_flush).await?;
+        if durable {
+            self.storage.sync(&[Store::Oplog]).await?;
+        }
+        self.header = outcome.header;
 
-            // Commit changeset to in-memory tree
-            self.tree.commit(changeset)?;
+        // Write to bitfield
+        with_bitfield(&mut self.storage, &mut self.bitfield, |bitfield| {
+            bitfield.update(&bitfield_update)
+        })
+        .await?;
 
-            // Now ready to flush
-            if self.should_flush_bitfield_and_tree_and_oplog() {
-                self.flush_bitfield_and_tree_and_oplog(false).await?;
-            }
+        // Contiguous length is known only now
+        update_contiguous_length(
+            &mut self.storage,
+            &mut self.header,
+            &mut self.bitfield,
+            &bitfield_update,
+        )
+        .await?;
 
-            #[cfg(feature = "replication")]
-            {
-                let _ = self.events.send(crate::replication::events::DataUpgrade {});
-                let _ = self
-                    .events
-                    .send(crate::replication::events::Have::from(&bitfield_update));
+        // Commit changeset to in-memory tree
+        self.tree.commit(changeset)?;
+
+        // Now ready to flush
+        if self.should_flush_bitfield_and_tree_and_oplog() {
+            self.flush_bitfield_and_tree_and_oplog(false).await?;
+            durable |= self.durability != Durability::None;
+        }
+
+        #[cfg(feature = "replication")]
+        {
+            let _ = self.events.send(crate::replication::events::DataUpgrade {});
+            let _ = self
+                .events
+                .send(crate::replication::events::Have::from(&bitfield_update));
+        }
+        Ok(durable)
+    }
+
+    /// Writes all unflushed bitfield pages, tree nodes and the header to storage and syncs
+    /// it, so that nothing needs to be replayed from the oplog when the hypercore is opened.
+    #[instrument(err, skip_all)]
+    pub async fn flush(&mut self) -> Result<(), HypercoreError> {
+        self.flush_and_sync(false, true).await?;
+        self.skip_flush_count = self.flush_interval;
+        Ok(())
+    }
+
+    /// Flushes the hypercore, see [`Hypercore::flush`], and releases its storage.
+    #[instrument(err, skip_all)]
+    pub async fn close(mut self) -> Result<(), HypercoreError> {
+        self.flush().await
+    }
+
+    /// Start an atomic batch of appends, see [`Batch`]
+    pub fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    /// Truncates the hypercore to `new_length`, dropping all blocks after it. The fork id
+    /// is set to `fork`, which needs to be greater than the current one, or incremented by
+    /// one if not given.
+    #[instrument(err, skip(self))]
+    pub async fn truncate(
+        &mut self,
+        new_length: u64,
+        fork: Option<u64>,
+    ) -> Result<(), HypercoreError> {
+        if self.key_pair.secret.is_none() || self.header.manifest.is_static() {
+            return Err(HypercoreError::NotWritable);
+        }
+        if new_length > self.tree.length {
+            return Err(HypercoreError::BadArgument {
+                context: format!(
+                    "Can not truncate to length {}, hypercore length is {}",
+                    new_length, self.tree.length
+                ),
+            });
+        }
+        let fork = match fork {
+            // Appending again on the same fork would sign two different trees for it
+            Some(fork) if fork <= self.tree.fork => {
+                return Err(HypercoreError::BadArgument {
+                    context: format!(
+                        "Can not truncate to fork {}, hypercore fork is {}",
+                        fork, self.tree.fork
+                    ),
+                });
             }
+            Some(fork) => fork,
+            None => self.tree.fork + 1,
+        };
+
+        // Create a changeset for the truncated tree
+        let mut changeset = self.truncate_changeset(new_length, fork).await?;
+        if new_length > 0 {
+            let secret_key = self
+                .key_pair
+                .secret
+                .as_ref()
+                .expect("Secret key checked above");
+            changeset.hash_and_sign(&self.verifier, secret_key)?;
+        } else {
+            // NB: This is what javascript does, an empty tree is not signed
+            changeset.hash = Some(changeset.hash());
         }
 
-        // Return the new value
-        Ok(AppendOutcome {
-            length: self.tree.length,
-            byte_length: self.tree.byte_length,
+        // Append the changeset to the Oplog
+        let bitfield_update = BitfieldUpdate {
+            drop: true,
+            start: new_length,
+            length: self.tree.length - new_length,
+        };
+        let outcome = self.oplog.append_changeset(
+            &changeset,
+            Some(bitfield_update.clone()),
+            false,
+            &self.header,
+        )?;
+        self.storage.flush_infos(&outcome.infos_to_flush).await?;
+        self.header = outcome.header;
+
+        // Clear from bitfield
+        with_bitfield(&mut self.storage, &mut self.bitfield, |bitfield| {
+            bitfield.update(&bitfield_update)
         })
+        .await?;
+
+        // Contiguous length is known only now
+        update_contiguous_length(
+            &mut self.storage,
+            &mut self.header,
+            &mut self.bitfield,
+            &bitfield_update,
+        )
+        .await?;
+
+        // Drop data past the new byte length
+        let info = self.block_store.truncate(changeset.byte_length);
+        self.storage.flush_info(info).await?;
+
+        add_reorg_hint(&mut self.header.hints.reorgs, &self.tree, &changeset);
+
+        // Commit changeset to in-memory tree
+        self.tree.commit(changeset)?;
+
+        // Truncation is always flushed immediately
+        self.flush_bitfield_and_tree_and_oplog(false).await?;
+
+        #[cfg(feature = "replication")]
+        {
+            let _ = self.events.send(crate::replication::events::Truncate {
+                ancestors: new_length,
+                fork,
+            });
+            let _ = self.events.send(crate::replication::events::DataUpgrade {});
+            let _ = self
+                .events
+                .send(crate::replication::events::Have::from(&bitfield_update));
+        }
+
+        Ok(())
     }
 
     #[cfg(feature = "replication")]
@@ -348,16 +805,292 @@ impl Hypercore {
         self.events.channel.new_receiver()
     }
 
+    #[cfg(feature = "shared-core")]
+    /// Turn the hypercore into a [`crate::replication::Session`], from which more sessions
+    /// sharing this hypercore can be created.
+    pub fn session(self) -> crate::replication::Session {
+        crate::replication::Session::new(self)
+    }
+
+    #[cfg(feature = "replication")]
+    /// Follow the core from `start` onwards, see [`crate::replication::LiveStream`]
+    pub fn live_stream(&self, start: u64) -> crate::replication::LiveStream {
+        crate::replication::LiveStream::new(start, self.event_subscribe())
+    }
+
     /// Check if core has the block at the given `index` locally
-    #[instrument(ret, skip(self))]
-    pub fn has(&self, index: u64) -> bool {
-        self.bitfield.get(index)
+    #[instrument(err, skip(self))]
+    pub async fn has(&mut self, index: u64) -> Result<bool, HypercoreError> {
+        with_bitfield(&mut self.storage, &mut self.bitfield, |bitfield| {
+            bitfield.get(index)
+        })
+        .await
+    }
+
+    /// Check if core has all blocks from `start` to `end` (exclusive) locally
+    #[instrument(err, skip(self))]
+    pub async fn has_range(&mut self, start: u64, end: u64) -> Result<bool, HypercoreError> {
+        let missing =
+            bitfield_index_of(&mut self.storage, &mut self.bitfield, false, start).await?;
+        Ok(!matches!(missing, Some(index) if index < end))
+    }
+
+    /// Index of the first block from `from` onwards that is missing locally, or None if all
+    /// blocks from `from` up to the length of the hypercore are present.
+    #[instrument(err, skip(self))]
+    pub async fn first_missing(&mut self, from: u64) -> Result<Option<u64>, HypercoreError> {
+        let missing = bitfield_index_of(&mut self.storage, &mut self.bitfield, false, from).await?;
+        Ok(missing.filter(|index| *index < self.tree.length))
+    }
+
+    /// Index of the last block before `before` that is present locally, if any
+    #[instrument(err, skip(self))]
+    pub async fn last_present(&mut self, before: u64) -> Result<Option<u64>, HypercoreError> {
+        if before == 0 {
+            return Ok(None);
+        }
+        bitfield_last_index_of(&mut self.storage, &mut self.bitfield, true, before - 1).await
+    }
+
+    /// Stream the ranges of blocks present and missing locally from `start` to `end`
+    /// (exclusive), in order. Every range is found with one lookup in the bitfield when
+    /// the stream is polled.
+    pub fn block_ranges(
+        &mut self,
+        start: u64,
+        end: u64,
+    ) -> impl Stream<Item = Result<BlockRange, HypercoreError>> + '_ {
+        futures::stream::unfold((self, start), move |(core, position)| async move {
+            if position >= end {
+                return None;
+            }
+            let range = core.block_range_at(position, end).await;
+            // Stop after an error
+            let next = range.as_ref().map_or(end, |range| range.end);
+            Some((range, (core, next)))
+        })
+    }
+
+    /// The range of blocks from `position` on that are all present or all missing
+    /// locally, ending at `end` at the latest.
+    async fn block_range_at(
+        &mut self,
+        position: u64,
+        end: u64,
+    ) -> Result<BlockRange, HypercoreError> {
+        let present = self.has(position).await?;
+        let range_end =
+            bitfield_index_of(&mut self.storage, &mut self.bitfield, !present, position)
+                .await?
+                .map_or(end, |index| std::cmp::min(index, end));
+        Ok(BlockRange {
+            start: position,
+            end: range_end,
+            present,
+        })
+    }
+
+    /// Export which blocks from `start` for `length` blocks are available locally, to send
+    /// to a peer which reads it with [`crate::RemoteBitfield::import`]. At most 2^24 blocks
+    /// are exported at once.
+    #[instrument(err, skip(self))]
+    pub async fn export_bitfield(
+        &mut self,
+        start: u64,
+        length: u64,
+    ) -> Result<RleBitfield, HypercoreError> {
+        loop {
+            match self.bitfield.export(start, length)? {
+                Either::Right(value) => return Ok(value),
+                Either::Left(instructions) => {
+                    let infos = self.storage.read_infos(&instructions).await?;
+                    self.bitfield.load_pages(&infos);
+                }
+            }
+        }
+    }
+
+    /// Number of blocks of the hypercore that are available locally
+    #[instrument(err, skip(self))]
+    pub async fn available_blocks(&mut self) -> Result<u64, HypercoreError> {
+        bitfield_count(&mut self.storage, &mut self.bitfield, 0, self.tree.length).await
+    }
+
+    /// Number of bytes of the blocks of the hypercore that are available locally. This is
+    /// looked up from the tree once per range of present blocks.
+    #[instrument(err, skip(self))]
+    pub async fn available_bytes(&mut self) -> Result<u64, HypercoreError> {
+        let mut bytes = 0;
+        let mut position = 0;
+        while position < self.tree.length {
+            let range = self.block_range_at(position, self.tree.length).await?;
+            position = range.end;
+            if !range.present {
+                continue;
+            }
+            let first = self.byte_range(range.start, None).await?;
+            let last = self.byte_range(range.end - 1, None).await?;
+            bytes +=
+                last.index + last.length - first.index - (range.end - range.start) * self.padding();
+        }
+        Ok(bytes)
+    }
+
+    /// Check the locally present blocks from `range` against the tree. Every block is
+    /// rehashed and compared to its leaf node, every parent node up to the roots is compared
+    /// to the hash of its children, and the signature is verified against the roots. Nodes
+    /// are checked bottom-up, and a node above a bad block or node is not judged, so only
+    /// the lowest corruption on every path is reported.
+    #[instrument(err, skip(self))]
+    pub async fn verify_integrity(
+        &mut self,
+        range: Range<u64>,
+    ) -> Result<IntegrityReport, HypercoreError> {
+        let mut report = IntegrityReport {
+            valid_signature: self.verify_signature(),
+            ..Default::default()
+        };
+        let roots: Vec<u64> = self.tree.roots.iter().map(|root| root.index).collect();
+        let end = std::cmp::min(range.end, self.tree.length);
+        // Parents queued for a check, each one only once
+        let mut queued: HashSet<u64> = HashSet::new();
+        // Bad nodes, and nodes which could not be judged because a child is bad
+        let mut suspect: HashSet<u64> = HashSet::new();
+        let mut position = range.start;
+        while position < end {
+            let present = self.block_range_at(position, end).await?;
+            position = present.end;
+            if !present.present {
+                continue;
+            }
+            let mut start = present.start;
+            while start < present.end {
+                let batch_end = std::cmp::min(start + VERIFY_INTEGRITY_BATCH_SIZE, present.end);
+                let mut parents: Vec<u64> = vec![];
+                for index in start..batch_end {
+                    let mut node = index * 2;
+                    while !roots.contains(&node) {
+                        node = flat_tree::parent(node);
+                        if !queued.insert(node) {
+                            break;
+                        }
+                        parents.push(node);
+                    }
+                }
+                // Lower levels first, so that bad children are known before their parents
+                parents.sort_by_key(|index| (index.trailing_ones(), *index));
+                let mut indices: Vec<u64> = (start..batch_end).map(|index| index * 2).collect();
+                for parent in &parents {
+                    let (left, right) = flat_tree::children(*parent).expect("Parent has children");
+                    indices.extend([*parent, left, right]);
+                }
+                let nodes: HashMap<u64, Node> = self
+                    .tree_nodes(&indices)
+                    .await?
+                    .into_iter()
+                    .flatten()
+                    .map(|node| (node.index, node))
+                    .collect();
+
+                for index in start..batch_end {
+                    report.checked_blocks += 1;
+                    let leaf = match nodes.get(&(index * 2)) {
+                        Some(leaf) => leaf,
+                        None => {
+                            report.bad_nodes.push(index * 2);
+                            suspect.insert(index * 2);
+                            continue;
+                        }
+                    };
+                    let byte_range = match self.byte_range(index, None).await {
+                        Ok(byte_range) => byte_range,
+                        Err(HypercoreError::InvalidOperation { .. }) => {
+                            // A node needed to locate the block is missing, which is
+                            // reported when its parent is checked
+                            suspect.insert(index * 2);
+                            continue;
+                        }
+                        Err(err) => return Err(err),
+                    };
+                    let data = self.read_data(&byte_range).await?;
+                    if Hash::data(&data).as_bytes() != leaf.hash.as_slice() {
+                        report.bad_blocks.push(index);
+                        suspect.insert(index * 2);
+                    }
+                }
+                for parent in parents {
+                    let (left, right) = flat_tree::children(parent).expect("Parent has children");
+                    if !nodes.contains_key(&parent) {
+                        report.bad_nodes.push(parent);
+                        suspect.insert(parent);
+                        continue;
+                    }
+                    if suspect.contains(&left) || suspect.contains(&right) {
+                        suspect.insert(parent);
+                        continue;
+                    }
+                    let missing: Vec<u64> = [left, right]
+                        .into_iter()
+                        .filter(|index| !nodes.contains_key(index))
+                        .collect();
+                    if !missing.is_empty() {
+                        report.bad_nodes.extend(missing);
+                        suspect.insert(parent);
+                        continue;
+                    }
+                    let (node, left, right) = (&nodes[&parent], &nodes[&left], &nodes[&right]);
+                    if Hash::parent(left, right).as_bytes() != node.hash.as_slice()
+                        || node.length != left.length + right.length
+                    {
+                        report.bad_nodes.push(parent);
+                        suspect.insert(parent);
+                    }
+                }
+                // The stored roots need to be the signed ones loaded when the hypercore was
+                // opened, a subtree rewritten consistently only differs from them
+                for root in &self.tree.roots {
+                    if let Some(node) = nodes.get(&root.index) {
+                        if node.hash != root.hash || node.length != root.length {
+                            report.bad_nodes.push(root.index);
+                            suspect.insert(root.index);
+                        }
+                    }
+                }
+                start = batch_end;
+            }
+        }
+        report.bad_nodes.sort_unstable();
+        report.bad_nodes.dedup();
+        Ok(report)
+    }
+
+    /// Verify the integrity of the blocks from `range` like [`Self::verify_integrity`],
+    /// and clear the bad blocks so that they can be fetched from peers again.
+    #[instrument(err, skip(self))]
+    pub async fn repair_integrity(
+        &mut self,
+        range: Range<u64>,
+    ) -> Result<IntegrityReport, HypercoreError> {
+        let report = self.verify_integrity(range).await?;
+        for index in &report.bad_blocks {
+            self.clear(*index, *index + 1).await?;
+        }
+        Ok(report)
     }
 
     /// Read value at given index, if any.
     #[instrument(err, skip(self))]
     pub async fn get(&mut self, index: u64) -> Result<Option<Vec<u8>>, HypercoreError> {
-        if !self.bitfield.get(index) {
+        match self.get_block(index).await? {
+            Some(block) => Ok(Some(self.decrypt_block(index, block)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the block stored at given index, if any. For encrypted hypercores the block
+    /// is encrypted, like it is in proofs.
+    async fn get_block(&mut self, index: u64) -> Result<Option<Vec<u8>>, HypercoreError> {
+        if !self.has(index).await? {
             #[cfg(feature = "replication")]
             // if not in this core, emit Event::Get(index)
             {
@@ -367,24 +1100,156 @@ impl Hypercore {
         }
 
         let byte_range = self.byte_range(index, None).await?;
+        let data = self.read_data(&byte_range).await?;
+        Ok(Some(data.to_vec()))
+    }
 
-        // TODO: Generalize Either response stack
-        let data = match self.block_store.read(&byte_range, None) {
-            Either::Right(value) => value,
-            Either::Left(instruction) => {
-                let info = self.storage.read_info(instruction).await?;
-                match self.block_store.read(&byte_range, Some(info)) {
-                    Either::Right(value) => value,
-                    Either::Left(_) => {
-                        return Err(HypercoreError::InvalidOperation {
-                            context: "Could not read block storage range".to_string(),
-                        });
-                    }
+    /// Read values between start and end (exclusive), None for values not available
+    /// locally. All values are read from storage at once, so holes between available values
+    /// are read as well.
+    #[instrument(err, skip(self))]
+    pub async fn get_range(
+        &mut self,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<Option<Vec<u8>>>, HypercoreError> {
+        if start >= end {
+            return Ok(vec![]);
+        }
+        let mut present: Vec<bool> = Vec::with_capacity((end - start) as usize);
+        for index in start..end {
+            present.push(self.has(index).await?);
+        }
+        let mut blocks: Vec<Option<Vec<u8>>> = vec![None; present.len()];
+
+        #[cfg(feature = "replication")]
+        // if not in this core, emit Event::Get(index) like get does
+        for (i, _) in present.iter().enumerate().filter(|(_, present)| !**present) {
+            self.events.send_on_get(start + i as u64);
+        }
+
+        let (first, last) = match (
+            present.iter().position(|present| *present),
+            present.iter().rposition(|present| *present),
+        ) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(blocks),
+        };
+        let lengths = self
+            .leaf_lengths(start + first as u64, start + last as u64 + 1)
+            .await?;
+
+        // Find the offset of every value, looking them up from the tree again only after
+        // holes with unknown lengths.
+        let mut offsets: Vec<Option<u64>> = Vec::with_capacity(lengths.len());
+        let mut offset: Option<u64> = None;
+        for (i, length) in lengths.iter().enumerate() {
+            if offset.is_none() && present[first + i] {
+                offset = Some(self.block_byte_offset(start + (first + i) as u64).await?);
+            }
+            offsets.push(offset);
+            offset = match (offset, length) {
+                (Some(offset), Some(length)) => Some(offset + length),
+                _ => None,
+            };
+        }
+        let span_start = offsets[0].expect("First value is present");
+        let span_end = offset.ok_or_else(|| HypercoreError::InvalidOperation {
+            context: format!(
+                "Could not read length for index {} from tree",
+                start + last as u64
+            ),
+        })?;
+        let data = self
+            .read_data(&NodeByteRange {
+                index: span_start,
+                length: span_end - span_start,
+            })
+            .await?;
+
+        for (i, (offset, length)) in offsets.iter().zip(lengths.iter()).enumerate() {
+            if let (true, Some(offset), Some(length)) = (present[first + i], offset, length) {
+                let block_start = (offset - span_start) as usize;
+                let block_end = block_start + *length as usize;
+                let index = start + (first + i) as u64;
+                let block = data[block_start..block_end].to_vec();
+                blocks[first + i] = Some(self.decrypt_block(index, block)?);
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Find the index containing the given byte offset, and the offset relative to the
+    /// start of that index. Returns None if the byte offset is beyond the byte length of the
+    /// hypercore, or if it can not be resolved from the locally available merkle tree.
+    #[instrument(err, skip(self))]
+    pub async fn seek(&mut self, byte_offset: u64) -> Result<Option<(u64, u64)>, HypercoreError> {
+        let mut infos: Vec<StoreInfo> = vec![];
+        loop {
+            match self.tree.seek(byte_offset, self.padding(), Some(&infos))? {
+                Either::Right(value) => {
+                    return Ok(value);
+                }
+                Either::Left(instructions) => {
+                    infos.extend(self.storage.read_infos_to_vec(&instructions).await?);
+                }
+            }
+        }
+    }
+
+    /// Get the byte offset where the value at the given index starts. The length of the
+    /// hypercore is accepted as index, and returns the byte length.
+    #[instrument(err, skip(self))]
+    pub async fn byte_offset(&mut self, index: u64) -> Result<u64, HypercoreError> {
+        Ok(self.block_byte_offset(index).await? - index * self.padding())
+    }
+
+    /// Byte offset of the block at the given index in the data storage, which includes the
+    /// padding of encrypted blocks.
+    async fn block_byte_offset(&mut self, index: u64) -> Result<u64, HypercoreError> {
+        if index == self.tree.length {
+            return Ok(self.tree.byte_length);
+        }
+        let mut infos: Vec<StoreInfo> = vec![];
+        loop {
+            match self.tree.byte_offset(index, Some(&infos))? {
+                Either::Right(value) => {
+                    return Ok(value);
+                }
+                Either::Left(instructions) => {
+                    infos.extend(self.storage.read_infos_to_vec(&instructions).await?);
                 }
             }
-        };
+        }
+    }
 
-        Ok(Some(data.to_vec()))
+    /// Stream values in the given index range. The end of the range is capped to the
+    /// length of the hypercore at the time of the call. Values are read in batches with
+    /// [`Hypercore::get_range`].
+    pub fn read_stream(
+        &mut self,
+        range: Range<u64>,
+        options: ReadStreamOptions,
+    ) -> impl Stream<Item = Result<Vec<u8>, HypercoreError>> + '_ {
+        let end = std::cmp::min(range.end, self.tree.length);
+        let state = ReadStreamState {
+            core: self,
+            start: range.start,
+            end,
+            options,
+            buffered: VecDeque::new(),
+        };
+        futures::stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(item) = state.buffered.pop_front() {
+                    return Some((item, state));
+                }
+                if state.start >= state.end {
+                    return None;
+                }
+                state.read_next_batch().await;
+            }
+        })
     }
 
     /// Clear data for entries between start and end (exclusive) indexes.
@@ -399,7 +1264,10 @@ impl Hypercore {
         self.storage.flush_infos(&infos_to_flush).await?;
 
         // Set bitfield
-        self.bitfield.set_range(start, end - start, false);
+        with_bitfield(&mut self.storage, &mut self.bitfield, |bitfield| {
+            bitfield.set_range(start, end - start, false)
+        })
+        .await?;
 
         // Set contiguous length
         if start < self.header.hints.contiguous_length {
@@ -407,12 +1275,16 @@ impl Hypercore {
         }
 
         // Find the biggest hole that can be punched into the data
-        let start = if let Some(index) = self.bitfield.last_index_of(true, start) {
+        let start = if let Some(index) =
+            bitfield_last_index_of(&mut self.storage, &mut self.bitfield, true, start).await?
+        {
             index + 1
         } else {
             0
         };
-        let end = if let Some(index) = self.bitfield.index_of(true, end) {
+        let end = if let Some(index) =
+            bitfield_index_of(&mut self.storage, &mut self.bitfield, true, end).await?
+        {
             index
         } else {
             self.tree.length
@@ -458,6 +1330,22 @@ impl Hypercore {
         &self.key_pair
     }
 
+    /// Identifier of the hypercore, see [`CoreId`]
+    pub fn id(&self) -> CoreId {
+        CoreId::new(self.header.key)
+    }
+
+    /// Discovery key of the hypercore, which can be announced on a public network without
+    /// leaking the key itself
+    pub fn discovery_key(&self) -> [u8; 32] {
+        discovery_key(&self.header.key)
+    }
+
+    /// Manifest of the hypercore, which defines who can sign it
+    pub fn manifest(&self) -> &Manifest {
+        &self.header.manifest
+    }
+
     /// Create a proof for given request
     #[instrument(err, skip_all)]
     pub async fn create_proof(
@@ -466,12 +1354,37 @@ impl Hypercore {
         hash: Option<RequestBlock>,
         seek: Option<RequestSeek>,
         upgrade: Option<RequestUpgrade>,
+    ) -> Result<Option<Proof>, HypercoreError> {
+        self.create_proof_with_head(None, block, hash, seek, upgrade)
+            .await
+    }
+
+    /// Create a proof against the given tree head instead of the current one
+    pub(crate) async fn create_proof_at_head(
+        &mut self,
+        head: &MerkleTreeHead,
+        block: Option<RequestBlock>,
+        hash: Option<RequestBlock>,
+        seek: Option<RequestSeek>,
+        upgrade: Option<RequestUpgrade>,
+    ) -> Result<Option<Proof>, HypercoreError> {
+        self.create_proof_with_head(Some(head), block, hash, seek, upgrade)
+            .await
+    }
+
+    async fn create_proof_with_head(
+        &mut self,
+        head: Option<&MerkleTreeHead>,
+        block: Option<RequestBlock>,
+        hash: Option<RequestBlock>,
+        seek: Option<RequestSeek>,
+        upgrade: Option<RequestUpgrade>,
     ) -> Result<Option<Proof>, HypercoreError> {
         let valueless_proof = self
-            .create_valueless_proof(block, hash, seek, upgrade)
+            .create_valueless_proof(head, block, hash, seek, upgrade)
             .await?;
         let value: Option<Vec<u8>> = if let Some(block) = valueless_proof.block.as_ref() {
-            let value = self.get(block.index).await?;
+            let value = self.get_block(block.index).await?;
             if value.is_none() {
                 // The data value requested in the proof can not be read, we return None here
                 // and let the party requesting figure out what to do.
@@ -488,9 +1401,23 @@ impl Hypercore {
     /// possible to apply.
     #[instrument(skip_all)]
     pub async fn verify_and_apply_proof(&mut self, proof: &Proof) -> Result<bool, HypercoreError> {
-        if proof.fork != self.tree.fork {
-            return Ok(false);
-        }
+        let upgradeless_proof: Proof;
+        let proof = if proof.fork != self.tree.fork {
+            if !self.reorg(proof).await? {
+                return Ok(false);
+            }
+            if proof.block.is_none() && proof.hash.is_none() && proof.seek.is_none() {
+                return Ok(true);
+            }
+            // Now that the forks match, apply the rest of the proof against the new tree
+            upgradeless_proof = Proof {
+                upgrade: None,
+                ..proof.clone()
+            };
+            &upgradeless_proof
+        } else {
+            proof
+        };
         let changeset = self.verify_proof(proof).await?;
         if !self.tree.commitable(&changeset) {
             return Ok(false);
@@ -553,10 +1480,19 @@ impl Hypercore {
 
         if let Some(bitfield_update) = &bitfield_update {
             // Write to bitfield
-            self.bitfield.update(bitfield_update);
+            with_bitfield(&mut self.storage, &mut self.bitfield, |bitfield| {
+                bitfield.update(bitfield_update)
+            })
+            .await?;
 
             // Contiguous length is known only now
-            update_contiguous_length(&mut self.header, &self.bitfield, bitfield_update);
+            update_contiguous_length(
+                &mut self.storage,
+                &mut self.header,
+                &mut self.bitfield,
+                bitfield_update,
+            )
+            .await?;
         }
 
         // Commit changeset to in-memory tree
@@ -584,6 +1520,94 @@ impl Hypercore {
         Ok(true)
     }
 
+    /// Follows a peer that truncated: verifies a proof on a newer fork that upgrades from
+    /// length zero, truncates to the length shared with the new fork and upgrades to the tree
+    /// of the proof. Returns false if the proof is not on a newer fork or not applicable.
+    async fn reorg(&mut self, proof: &Proof) -> Result<bool, HypercoreError> {
+        match proof.upgrade.as_ref() {
+            Some(upgrade) if upgrade.start == 0 && proof.fork > self.tree.fork => {}
+            _ => return Ok(false),
+        }
+        let changeset = self.reorg_changeset(proof).await?;
+        if !self.tree.commitable(&changeset) {
+            return Ok(false);
+        }
+        let ancestors = changeset.ancestors;
+        let byte_offset = self.block_byte_offset(ancestors).await?;
+
+        // Append the changeset to the Oplog
+        let bitfield_update = BitfieldUpdate {
+            drop: true,
+            start: ancestors,
+            length: self.tree.length - ancestors,
+        };
+        let outcome = self.oplog.append_changeset(
+            &changeset,
+            Some(bitfield_update.clone()),
+            false,
+            &self.header,
+        )?;
+        self.storage.flush_infos(&outcome.infos_to_flush).await?;
+        self.header = outcome.header;
+
+        // Clear from bitfield
+        with_bitfield(&mut self.storage, &mut self.bitfield, |bitfield| {
+            bitfield.update(&bitfield_update)
+        })
+        .await?;
+
+        // Contiguous length is known only now
+        update_contiguous_length(
+            &mut self.storage,
+            &mut self.header,
+            &mut self.bitfield,
+            &bitfield_update,
+        )
+        .await?;
+
+        // Drop data not shared with the new fork
+        let info = self.block_store.truncate(byte_offset);
+        self.storage.flush_info(info).await?;
+
+        add_reorg_hint(&mut self.header.hints.reorgs, &self.tree, &changeset);
+
+        // Commit changeset to in-memory tree
+        self.tree.commit(changeset)?;
+
+        // Truncation is always flushed immediately
+        self.flush_bitfield_and_tree_and_oplog(false).await?;
+
+        #[cfg(feature = "replication")]
+        {
+            let _ = self.events.send(crate::replication::events::Truncate {
+                ancestors,
+                fork: self.tree.fork,
+            });
+            let _ = self.events.send(crate::replication::events::DataUpgrade {});
+            let _ = self
+                .events
+                .send(crate::replication::events::Have::from(&bitfield_update));
+        }
+        Ok(true)
+    }
+
+    async fn reorg_changeset(
+        &mut self,
+        proof: &Proof,
+    ) -> Result<MerkleTreeChangeset, HypercoreError> {
+        let mut infos: Vec<StoreInfo> = vec![];
+        loop {
+            match self.tree.reorg(proof, &self.verifier, Some(&infos))? {
+                Either::Right(value) => {
+                    return Ok(value);
+                }
+                Either::Left(instructions) => {
+                    infos.extend(self.storage.read_infos_to_vec(&instructions).await?);
+                }
+            }
+        }
+    }
+
     /// Used to fill the nodes field of a `RequestBlock` during
     /// synchronization.
     #[instrument(err, skip(self))]
@@ -635,6 +1659,82 @@ impl Hypercore {
         }
     }
 
+    /// Makes a read-only hypercore writable again with the secret key of its key pair, e.g.
+    /// when a hypercore first opened by key is opened by name in a `Corestore`.
+    #[cfg(feature = "shared-core")]
+    pub(crate) async fn add_secret_key(
+        &mut self,
+        secret_key: SigningKey,
+    ) -> Result<(), HypercoreError> {
+        if secret_key.verifying_key() != self.key_pair.public {
+            return Err(HypercoreError::BadArgument {
+                context: "Secret key does not match the public key of the hypercore".to_string(),
+            });
+        }
+        self.key_pair.secret = Some(secret_key.clone());
+        self.header.key_pair.secret = Some(secret_key);
+        self.flush_bitfield_and_tree_and_oplog(false).await
+    }
+
+    /// Replaces the manifest with a static manifest pinning the current tree, which changes
+    /// the key of the hypercore to the hash of the static manifest. Encrypted hypercores can
+    /// not be frozen, as their block encryption key is derived from the key.
+    async fn freeze(&mut self) -> Result<(), HypercoreError> {
+        if self.encryption.is_some() || self.get_user_data(ENCRYPTED_USER_DATA_KEY).is_some() {
+            return Err(HypercoreError::BadArgument {
+                context: "An encrypted hypercore can not be frozen".to_string(),
+            });
+        }
+        if self.header.manifest.is_static() {
+            return Ok(());
+        }
+        if self.tree.length == 0 {
+            return Err(HypercoreError::BadArgument {
+                context: "An empty hypercore can not be frozen".to_string(),
+            });
+        }
+        let hash = Hash::tree(&self.tree.roots);
+        let hash: [u8; 32] = hash.as_bytes().try_into().expect("Tree hash is 32 bytes");
+        let manifest = Manifest::new_static(hash, self.tree.length);
+        self.header.key = manifest.key()?;
+        self.header.manifest = manifest;
+        self.verifier = Verifier::new(&self.header.key, &self.header.manifest)?;
+        // Static hypercores have no signers, the key pair is kept but no longer signs
+        self.tree.signature = None;
+        self.header.tree.signature = Box::new([]);
+        // Need to flush clearing traces to make sure both oplog slots are cleared
+        self.flush_bitfield_and_tree_and_oplog(true).await
+    }
+
+    /// Gets the user data value stored for given key. User data is local metadata of the
+    /// hypercore that is not replicated, e.g. the header pointer of hyperbee.
+    pub fn get_user_data(&self, key: &str) -> Option<&[u8]> {
+        self.header.get_user_data(key)
+    }
+
+    /// Sets the user data value of given key, or removes the key if the value is None or
+    /// empty. The change is logged to the oplog and persisted in the oplog header on flush,
+    /// compatible with `userData` of Javascript.
+    #[instrument(err, skip(self, value))]
+    pub async fn set_user_data(
+        &mut self,
+        key: &str,
+        value: Option<&[u8]>,
+    ) -> Result<(), HypercoreError> {
+        let value = value.unwrap_or_default();
+        let existing = self.header.get_user_data(key);
+        if existing == Some(value) || (existing.is_none() && value.is_empty()) {
+            return Ok(());
+        }
+        let infos_to_flush = self.oplog.append_user_data(key, value)?;
+        self.storage.flush_infos(&infos_to_flush).await?;
+        self.header.update_user_data(key, value);
+        if self.should_flush_bitfield_and_tree_and_oplog() {
+            self.flush_bitfield_and_tree_and_oplog(false).await?;
+        }
+        Ok(())
+    }
+
     async fn byte_range(
         &mut self,
         index: u64,
@@ -660,14 +1760,132 @@ impl Hypercore {
         }
     }
 
+    async fn truncate_changeset(
+        &mut self,
+        length: u64,
+        fork: u64,
+    ) -> Result<MerkleTreeChangeset, HypercoreError> {
+        match self.tree.truncate(length, fork, None)? {
+            Either::Right(value) => Ok(value),
+            Either::Left(instructions) => {
+                let mut instructions = instructions;
+                let mut infos: Vec<StoreInfo> = vec![];
+                loop {
+                    infos.extend(self.storage.read_infos_to_vec(&instructions).await?);
+                    match self.tree.truncate(length, fork, Some(&infos))? {
+                        Either::Right(value) => {
+                            return Ok(value);
+                        }
+                        Either::Left(new_instructions) => {
+                            instructions = new_instructions;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Byte length of the values, which excludes the padding of encrypted blocks
+    fn byte_length(&self) -> u64 {
+        self.tree.byte_length - self.tree.length * self.padding()
+    }
+
+    /// Padding of every block
+    pub(crate) fn padding(&self) -> u64 {
+        if self.encryption.is_some() {
+            BLOCK_ENCRYPTION_PADDING as u64
+        } else {
+            0
+        }
+    }
+
+    pub(crate) fn decrypt_block(
+        &self,
+        index: u64,
+        block: Vec<u8>,
+    ) -> Result<Vec<u8>, HypercoreError> {
+        match &self.encryption {
+            Some(encryption) => encryption.decrypt(index, &block),
+            None => Ok(block),
+        }
+    }
+
+    async fn leaf_lengths(
+        &mut self,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<Option<u64>>, HypercoreError> {
+        match self.tree.leaf_lengths(start, end, None)? {
+            Either::Right(value) => Ok(value),
+            Either::Left(instructions) => {
+                let infos = self.storage.read_infos_to_vec(&instructions).await?;
+                match self.tree.leaf_lengths(start, end, Some(&infos))? {
+                    Either::Right(value) => Ok(value),
+                    Either::Left(_) => Err(HypercoreError::InvalidOperation {
+                        context: format!("Could not read leaves {start}..{end} from tree"),
+                    }),
+                }
+            }
+        }
+    }
+
+    async fn tree_nodes(&mut self, indices: &[u64]) -> Result<Vec<Option<Node>>, HypercoreError> {
+        match self.tree.nodes(indices, None)? {
+            Either::Right(value) => Ok(value),
+            Either::Left(instructions) => {
+                let infos = self.storage.read_infos_to_vec(&instructions).await?;
+                match self.tree.nodes(indices, Some(&infos))? {
+                    Either::Right(value) => Ok(value),
+                    Either::Left(_) => Err(HypercoreError::InvalidOperation {
+                        context: "Could not read nodes from tree".to_string(),
+                    }),
+                }
+            }
+        }
+    }
+
+    /// Whether the signature of the tree is valid for its roots
+    fn verify_signature(&self) -> bool {
+        match &self.tree.signature {
+            Some(signature) => self
+                .verifier
+                .verify(
+                    signature,
+                    Hash::tree(&self.tree.roots).as_bytes(),
+                    self.tree.length,
+                    self.tree.fork,
+                )
+                .is_ok(),
+            None => self.tree.length == 0,
+        }
+    }
+
+    async fn read_data(&mut self, byte_range: &NodeByteRange) -> Result<Box<[u8]>, HypercoreError> {
+        // TODO: Generalize Either response stack
+        match self.block_store.read(byte_range, None) {
+            Either::Right(value) => Ok(value),
+            Either::Left(instruction) => {
+                let info = self.storage.read_info(instruction).await?;
+                match self.block_store.read(byte_range, Some(info)) {
+                    Either::Right(value) => Ok(value),
+                    Either::Left(_) => Err(HypercoreError::InvalidOperation {
+                        context: "Could not read block storage range".to_string(),
+                    }),
+                }
+            }
+        }
+    }
+
     async fn create_valueless_proof(
         &mut self,
+        head: Option<&MerkleTreeHead>,
         block: Option<RequestBlock>,
         hash: Option<RequestBlock>,
         seek: Option<RequestSeek>,
         upgrade: Option<RequestUpgrade>,
     ) -> Result<ValuelessProof, HypercoreError> {
         match self.tree.create_valueless_proof(
+            head,
             block.as_ref(),
             hash.as_ref(),
             seek.as_ref(),
@@ -681,6 +1899,7 @@ impl Hypercore {
                 loop {
                     infos.extend(self.storage.read_infos_to_vec(&instructions).await?);
                     match self.tree.create_valueless_proof(
+                        head,
                         block.as_ref(),
                         hash.as_ref(),
                         seek.as_ref(),
@@ -702,13 +1921,13 @@ impl Hypercore {
     /// Verify a proof received from a peer. Returns a changeset that should be
     /// applied.
     async fn verify_proof(&mut self, proof: &Proof) -> Result<MerkleTreeChangeset, HypercoreError> {
-        match self.tree.verify_proof(proof, &self.key_pair.public, None)? {
+        match self.tree.verify_proof(proof, &self.verifier, None)? {
             Either::Right(value) => Ok(value),
             Either::Left(instructions) => {
                 let infos = self.storage.read_infos_to_vec(&instructions).await?;
                 match self
                     .tree
-                    .verify_proof(proof, &self.key_pair.public, Some(&infos))?
+                    .verify_proof(proof, &self.verifier, Some(&infos))?
                 {
                     Either::Right(value) => Ok(value),
                     Either::Left(_) => Err(HypercoreError::InvalidOperation {
@@ -723,7 +1942,7 @@ impl Hypercore {
         if self.skip_flush_count == 0
             || self.oplog.entries_byte_length >= MAX_OPLOG_ENTRIES_BYTE_SIZE
         {
-            self.skip_flush_count = 3;
+            self.skip_flush_count = self.flush_interval;
             true
         } else {
             self.skip_flush_count -= 1;
@@ -731,25 +1950,246 @@ impl Hypercore {
         }
     }
 
-    async fn flush_bitfield_and_tree_and_oplog(
+    pub(crate) async fn flush_bitfield_and_tree_and_oplog(
         &mut self,
         clear_traces: bool,
+    ) -> Result<(), HypercoreError> {
+        let sync = self.durability != Durability::None;
+        self.flush_and_sync(clear_traces, sync).await
+    }
+
+    async fn flush_and_sync(
+        &mut self,
+        clear_traces: bool,
+        sync: bool,
     ) -> Result<(), HypercoreError> {
         let infos = self.bitfield.flush();
         self.storage.flush_infos(&infos).await?;
         let infos = self.tree.flush();
         self.storage.flush_infos(&infos).await?;
+        if sync {
+            // Blocks, bitfield and tree need to be durable before the header slot flip
+            // drops the oplog entries
+            self.storage
+                .sync(&[Store::Data, Store::Bitfield, Store::Tree])
+                .await?;
+        }
         let infos = self.oplog.flush(&self.header, clear_traces)?;
         self.storage.flush_infos(&infos).await?;
+        if sync {
+            self.storage.sync(&[Store::Oplog]).await?;
+        }
         Ok(())
     }
 }
 
-fn update_contiguous_length(
+struct ReadStreamState<'a> {
+    core: &'a mut Hypercore,
+    start: u64,
+    end: u64,
+    options: ReadStreamOptions,
+    buffered: VecDeque<Result<Vec<u8>, HypercoreError>>,
+}
+
+impl ReadStreamState<'_> {
+    async fn read_next_batch(&mut self) {
+        let (start, end) = if self.options.reverse {
+            let start = std::cmp::max(self.start, self.end.saturating_sub(READ_STREAM_BATCH_SIZE));
+            (start, self.end)
+        } else {
+            let end = std::cmp::min(self.end, self.start + READ_STREAM_BATCH_SIZE);
+            (self.start, end)
+        };
+        if self.options.reverse {
+            self.end = start;
+        } else {
+            self.start = end;
+        }
+
+        let blocks = match self.core.get_range(start, end).await {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                self.buffered.push_back(Err(err));
+                self.start = self.end;
+                return;
+            }
+        };
+        let mut blocks: Vec<(u64, Option<Vec<u8>>)> = (start..end).zip(blocks).collect();
+        if self.options.reverse {
+            blocks.reverse();
+        }
+        for (index, block) in blocks {
+            match block {
+                Some(value) => self.buffered.push_back(Ok(value)),
+                None if self.options.skip_missing => {}
+                None => {
+                    // End the stream at the first missing value
+                    self.buffered
+                        .push_back(Err(HypercoreError::InvalidOperation {
+                            context: format!("Block {index} is not available locally"),
+                        }));
+                    self.start = self.end;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Remembers a reorg of the tree to the fork of the changeset, `addReorgHint` in Javascript
+fn add_reorg_hint(reorgs: &mut Vec<ReorgHint>, tree: &MerkleTree, changeset: &MerkleTreeChangeset) {
+    if tree.length == 0 || tree.fork == changeset.fork {
+        return;
+    }
+    // Only keep a few of the latest hints
+    while reorgs.len() >= MAX_REORG_HINTS {
+        reorgs.remove(0);
+    }
+    while matches!(reorgs.last(), Some(hint) if hint.ancestors > changeset.ancestors) {
+        reorgs.pop();
+    }
+    reorgs.push(ReorgHint {
+        from: tree.fork,
+        to: changeset.fork,
+        ancestors: changeset.ancestors,
+    });
+}
+
+/// Rebuild the tree of the header of a hypercore whose oplog is recovered. The manifest,
+/// and the secret key if not given, are taken from the damaged headers. The longest of
+/// the damaged headers whose signature is valid for the roots in the tree store is used as
+/// is. Otherwise the tree is the longest one found in the tree store, signed again with the
+/// secret key. The fork to sign with is the given one or that of the damaged headers:
+/// signing with a guessed fork could sign a different tree than one already published for
+/// the same length and fork. A tree that can not be signed is not recovered, as it would be
+/// stored without a signature.
+async fn recover_header_tree(
+    storage: &mut Storage,
+    header: &mut Header,
+    damaged_headers: Vec<Header>,
+    fork: Option<u64>,
+) -> Result<(), HypercoreError> {
+    let mut damaged_headers: Vec<Header> = damaged_headers
+        .into_iter()
+        .filter(|damaged| damaged.key == header.key)
+        .collect();
+    damaged_headers.sort_by_key(|damaged| std::cmp::Reverse(damaged.tree.length));
+    // A hypercore recovered with its key only stays writable
+    if let Some(damaged) = damaged_headers
+        .iter()
+        .find(|damaged| damaged.key_pair.secret.is_some())
+        .or_else(|| damaged_headers.first())
+    {
+        header.manifest = damaged.manifest.clone();
+        if header.key_pair.secret.is_none() {
+            header.key_pair = damaged.key_pair.clone();
+        }
+    }
+    let verifier = Verifier::new(&header.key, &header.manifest)?;
+    for damaged in &damaged_headers {
+        if let Some(roots) = stored_roots(storage, damaged.tree.length).await? {
+            let hash = Hash::tree(&roots);
+            if verifier
+                .verify(
+                    &damaged.tree.signature,
+                    hash.as_bytes(),
+                    damaged.tree.length,
+                    damaged.tree.fork,
+                )
+                .is_ok()
+            {
+                header.tree = damaged.tree.clone();
+                header.user_data = damaged.user_data.clone();
+                header.hints = damaged.hints.clone();
+                return Ok(());
+            }
+        }
+    }
+
+    let fork = fork.or_else(|| {
+        damaged_headers
+            .iter()
+            .map(|damaged| damaged.tree.fork)
+            .max()
+    });
+    let (length, roots) = longest_stored_tree(storage).await?;
+    let hash = Hash::tree(&roots);
+    header.tree.signature = match (&header.key_pair.secret, fork) {
+        _ if length == 0 => Box::new([]),
+        (Some(secret_key), Some(fork)) => {
+            verifier.sign(secret_key, hash.as_bytes(), length, fork)?
+        }
+        (Some(_), None) => {
+            return Err(HypercoreError::BadArgument {
+                context: "The fork of the hypercore could not be recovered, it is needed to sign \
+                          the recovered tree: give it with `HypercoreBuilder::recovery_fork`"
+                    .to_string(),
+            });
+        }
+        (None, _) => {
+            return Err(HypercoreError::BadArgument {
+                context: format!(
+                    "The recovered tree of length {length} can not be signed without the \
+                     secret key"
+                ),
+            });
+        }
+    };
+    header.tree.root_hash = hash.as_bytes().into();
+    header.tree.length = length;
+    header.tree.fork = fork.unwrap_or(0);
+    Ok(())
+}
+
+/// Roots of the tree of given length, if they are all stored
+async fn stored_roots(
+    storage: &mut Storage,
+    length: u64,
+) -> Result<Option<Vec<Node>>, HypercoreError> {
+    match MerkleTree::stored_roots(length, None)? {
+        Either::Right(value) => Ok(value),
+        Either::Left(instructions) => {
+            let infos = storage.read_infos_to_vec(&instructions).await?;
+            match MerkleTree::stored_roots(length, Some(&infos))? {
+                Either::Right(value) => Ok(value),
+                Either::Left(_) => Err(HypercoreError::InvalidOperation {
+                    context: format!("Could not read roots of tree of length {length}"),
+                }),
+            }
+        }
+    }
+}
+
+/// Length and roots of the tree whose roots are all stored and whose last root is the last
+/// possible node of the tree store. The tree store is searched backwards, one batch of
+/// nodes at a time.
+async fn longest_stored_tree(storage: &mut Storage) -> Result<(u64, Vec<Node>), HypercoreError> {
+    let info = storage
+        .read_info(StoreInfoInstruction::new_size(Store::Tree, 0))
+        .await?;
+    let mut end = MerkleTree::stored_node_count(info.length.unwrap_or(0));
+    let mut checked: HashSet<u64> = HashSet::new();
+    while end > 0 {
+        let (start, instruction) = MerkleTree::stored_trees_batch(end);
+        let info = storage.read_info(instruction).await?;
+        for length in MerkleTree::stored_trees_lengths(&info)? {
+            if checked.insert(length) {
+                if let Some(roots) = stored_roots(storage, length).await? {
+                    return Ok((length, roots));
+                }
+            }
+        }
+        end = start;
+    }
+    Ok((0, vec![]))
+}
+
+async fn update_contiguous_length(
+    storage: &mut Storage,
     header: &mut Header,
-    bitfield: &Bitfield,
+    bitfield: &mut Bitfield,
     bitfield_update: &BitfieldUpdate,
-) {
+) -> Result<(), HypercoreError> {
     let end = bitfield_update.start + bitfield_update.length;
     let mut c = header.hints.contiguous_length;
     if bitfield_update.drop {
@@ -757,20 +2197,102 @@ fn update_contiguous_length(
             c = bitfield_update.start;
         }
     } else if c <= end && c >= bitfield_update.start {
-        c = end;
-        while bitfield.get(c) {
-            c += 1;
+        c = bitfield_index_of(storage, bitfield, false, end)
+            .await?
+            .expect("Bitfield always has a missing value");
+    }
+
+    if c != header.hints.contiguous_length {
+        header.hints.contiguous_length = c;
+    }
+    Ok(())
+}
+
+/// Runs an operation on the bitfield, reading the pages it needs from storage until it
+/// completes.
+async fn with_bitfield<T>(
+    storage: &mut Storage,
+    bitfield: &mut Bitfield,
+    mut operation: impl FnMut(&mut Bitfield) -> Either<Box<[StoreInfoInstruction]>, T>,
+) -> Result<T, HypercoreError> {
+    loop {
+        match operation(bitfield) {
+            Either::Right(value) => return Ok(value),
+            Either::Left(instructions) => {
+                let infos = storage.read_infos(&instructions).await?;
+                bitfield.load_pages(&infos);
+            }
+        }
+    }
+}
+
+/// Finds the first index of the value from `position` onwards, reading pages from storage
+/// as the search goes.
+async fn bitfield_index_of(
+    storage: &mut Storage,
+    bitfield: &mut Bitfield,
+    value: bool,
+    position: u64,
+) -> Result<Option<u64>, HypercoreError> {
+    let mut position = position;
+    with_bitfield(storage, bitfield, |bitfield| {
+        let result = bitfield.index_of(value, position);
+        if let Either::Left(instructions) = &result {
+            // Pages before the first one to read were searched already
+            position = std::cmp::max(position, Bitfield::page_start(&instructions[0]));
+        }
+        result
+    })
+    .await
+}
+
+/// Finds the last index of the value up to and including `position`, reading pages from
+/// storage as the search goes.
+async fn bitfield_last_index_of(
+    storage: &mut Storage,
+    bitfield: &mut Bitfield,
+    value: bool,
+    position: u64,
+) -> Result<Option<u64>, HypercoreError> {
+    let mut position = position;
+    with_bitfield(storage, bitfield, |bitfield| {
+        let result = bitfield.last_index_of(value, position);
+        if let Either::Left(instructions) = &result {
+            // Pages after the first one to read were searched already
+            position = std::cmp::min(position, Bitfield::page_end(&instructions[0]) - 1);
         }
-    }
+        result
+    })
+    .await
+}
 
-    if c != header.hints.contiguous_length {
-        header.hints.contiguous_length = c;
-    }
+/// Counts the set bits from `start` to `end` (exclusive), reading pages from storage as
+/// the count goes.
+async fn bitfield_count(
+    storage: &mut Storage,
+    bitfield: &mut Bitfield,
+    start: u64,
+    end: u64,
+) -> Result<u64, HypercoreError> {
+    let mut start = start;
+    let mut count = 0;
+    with_bitfield(storage, bitfield, |bitfield| {
+        match bitfield.count(start, end) {
+            Either::Left((counted, instructions)) => {
+                count += counted;
+                start = std::cmp::max(start, Bitfield::page_start(&instructions[0]));
+                Either::Left(instructions)
+            }
+            Either::Right(counted) => Either::Right(count + counted),
+        }
+    })
+    .await
 }
 
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use futures::{StreamExt, TryStreamExt};
 
     #[async_std::test]
     async fn core_create_proof_block_only() -> Result<(), HypercoreError> {
@@ -1135,6 +2657,745 @@ pub(crate) mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn core_verify_and_apply_proof_block_in_new_root() -> Result<(), HypercoreError> {
+        let mut main = create_hypercore_with_data(5).await?;
+        let mut clone = create_hypercore_with_data_and_key_pair(
+            0,
+            PartialKeypair {
+                public: main.key_pair.public,
+                secret: None,
+            },
+        )
+        .await?;
+        // Block 4 is the second root of the upgrade, its byte offset is the length of the
+        // first root of the changeset, the empty clone has no roots of its own
+        let nodes = clone.missing_nodes(4).await?;
+        let proof = main
+            .create_proof(
+                Some(RequestBlock { index: 4, nodes }),
+                None,
+                None,
+                Some(RequestUpgrade {
+                    start: 0,
+                    length: 5,
+                }),
+            )
+            .await?
+            .unwrap();
+        assert!(clone.verify_and_apply_proof(&proof).await?);
+        assert_eq!(clone.info().length, 5);
+        assert_eq!(clone.get(4).await?, Some(b"#4".to_vec()));
+        assert!(!clone.has(3).await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_verify_and_apply_proof_reorg() -> Result<(), HypercoreError> {
+        let mut main = create_hypercore_with_data(10).await?;
+        let mut clone = create_hypercore_with_data_and_key_pair(
+            0,
+            PartialKeypair {
+                public: main.key_pair.public,
+                secret: None,
+            },
+        )
+        .await?;
+        for index in 0..10 {
+            let nodes = clone.missing_nodes(index).await?;
+            let upgrade = (index == 0).then_some(RequestUpgrade {
+                start: 0,
+                length: 10,
+            });
+            let proof = main
+                .create_proof(Some(RequestBlock { index, nodes }), None, None, upgrade)
+                .await?
+                .unwrap();
+            assert!(clone.verify_and_apply_proof(&proof).await?);
+        }
+        assert_eq!(clone.info().contiguous_length, 10);
+
+        // Main forks at length 6
+        main.truncate(6, None).await?;
+        main.append_batch(&[b"#6b", b"#7b", b"#8b"]).await?;
+
+        // A proof that does not upgrade from zero can not be applied on the old fork
+        let proof = main
+            .create_proof(Some(RequestBlock { index: 7, nodes: 0 }), None, None, None)
+            .await?
+            .unwrap();
+        assert!(!clone.verify_and_apply_proof(&proof).await?);
+
+        #[cfg(feature = "replication")]
+        let mut rx = clone.event_subscribe();
+        let proof = main
+            .create_proof(
+                Some(RequestBlock { index: 7, nodes: 0 }),
+                None,
+                None,
+                Some(RequestUpgrade {
+                    start: 0,
+                    length: 9,
+                }),
+            )
+            .await?
+            .unwrap();
+        assert!(clone.verify_and_apply_proof(&proof).await?);
+        let info = clone.info();
+        assert_eq!(info.length, 9);
+        assert_eq!(info.fork, 1);
+        assert_eq!(info.byte_length, main.info().byte_length);
+        assert_eq!(info.contiguous_length, 6);
+        assert_eq!(
+            clone.header.hints.reorgs,
+            vec![ReorgHint {
+                from: 0,
+                to: 1,
+                ancestors: 6
+            }]
+        );
+        assert_eq!(clone.get(5).await?, Some(b"#5".to_vec()));
+        assert!(!clone.has(6).await?);
+        assert_eq!(clone.get(7).await?, Some(b"#7b".to_vec()));
+        #[cfg(feature = "replication")]
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(crate::replication::Event::Truncate(
+                crate::replication::events::Truncate {
+                    ancestors: 6,
+                    fork: 1
+                }
+            ))
+        ));
+
+        // The replica follows the new fork
+        let proof = main
+            .create_proof(Some(RequestBlock { index: 6, nodes: 0 }), None, None, None)
+            .await?
+            .unwrap();
+        assert!(clone.verify_and_apply_proof(&proof).await?);
+        assert_eq!(clone.get(6).await?, Some(b"#6b".to_vec()));
+        assert_eq!(clone.info().contiguous_length, 8);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_block_ranges() -> Result<(), HypercoreError> {
+        let mut main = create_hypercore_with_data(10).await?;
+        let mut clone = create_hypercore_with_data_and_key_pair(
+            0,
+            PartialKeypair {
+                public: main.key_pair.public,
+                secret: None,
+            },
+        )
+        .await?;
+        for index in [2, 3, 4, 7] {
+            let nodes = clone.missing_nodes(index).await?;
+            let upgrade = (index == 2).then_some(RequestUpgrade {
+                start: 0,
+                length: 10,
+            });
+            let proof = main
+                .create_proof(Some(RequestBlock { index, nodes }), None, None, upgrade)
+                .await?
+                .unwrap();
+            assert!(clone.verify_and_apply_proof(&proof).await?);
+        }
+
+        assert!(clone.has_range(2, 5).await?);
+        assert!(clone.has_range(5, 5).await?);
+        assert!(!clone.has_range(2, 6).await?);
+        assert_eq!(clone.first_missing(0).await?, Some(0));
+        assert_eq!(clone.first_missing(2).await?, Some(5));
+        assert_eq!(clone.first_missing(8).await?, Some(8));
+        assert_eq!(clone.last_present(10).await?, Some(7));
+        assert_eq!(clone.last_present(7).await?, Some(4));
+        assert_eq!(clone.last_present(2).await?, None);
+        assert_eq!(clone.last_present(0).await?, None);
+        assert_eq!(
+            clone.block_ranges(1, 10).try_collect::<Vec<_>>().await?,
+            vec![
+                BlockRange {
+                    start: 1,
+                    end: 2,
+                    present: false
+                },
+                BlockRange {
+                    start: 2,
+                    end: 5,
+                    present: true
+                },
+                BlockRange {
+                    start: 5,
+                    end: 7,
+                    present: false
+                },
+                BlockRange {
+                    start: 7,
+                    end: 8,
+                    present: true
+                },
+                BlockRange {
+                    start: 8,
+                    end: 10,
+                    present: false
+                },
+            ]
+        );
+        assert_eq!(clone.block_ranges(3, 3).count().await, 0);
+        assert_eq!(clone.available_blocks().await?, 4);
+        assert_eq!(clone.available_bytes().await?, 8);
+
+        // Peers learn the same from an exported bitfield
+        assert!(matches!(
+            clone.export_bitfield(0, u64::MAX).await,
+            Err(HypercoreError::BadArgument { .. })
+        ));
+        assert!(matches!(
+            clone.export_bitfield(u64::MAX, 2).await,
+            Err(HypercoreError::BadArgument { .. })
+        ));
+        let exported = clone.export_bitfield(0, 10).await?;
+        let encoded = compact_encoding::to_encoded_bytes!(&exported);
+        let ((decoded,), rest) = compact_encoding::map_decode!(&encoded, [RleBitfield]);
+        assert!(rest.is_empty());
+        assert_eq!(decoded, exported);
+        let mut remote = crate::RemoteBitfield::new();
+        remote.import(&decoded)?;
+        assert!(remote.has_range(2, 5));
+        assert_eq!(remote.index_of(false, 2), Some(5));
+        assert_eq!(remote.last_index_of(true, 9), Some(7));
+        assert_eq!(remote.count(0, 10), 4);
+
+        assert_eq!(main.first_missing(0).await?, None);
+        assert!(main.has_range(0, 10).await?);
+        assert_eq!(main.available_blocks().await?, 10);
+        assert_eq!(main.available_bytes().await?, main.info().byte_length);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_longest_stored_tree() -> Result<(), HypercoreError> {
+        // More nodes than are read in one batch
+        let mut hypercore = create_hypercore_with_data(600).await?;
+        hypercore.flush_bitfield_and_tree_and_oplog(false).await?;
+        let (length, roots) = longest_stored_tree(&mut hypercore.storage).await?;
+        assert_eq!(length, 600);
+        assert_eq!(roots, hypercore.tree.roots);
+
+        // The last root, over the last eight blocks, is lost
+        hypercore
+            .storage
+            .flush_infos(&[StoreInfo::new_content(Store::Tree, 1191 * 40, &[0; 40])])
+            .await?;
+        let (length, roots) = longest_stored_tree(&mut hypercore.storage).await?;
+        assert_eq!(length, 599);
+        assert_eq!(roots.len(), 6);
+        assert!(stored_roots(&mut hypercore.storage, 600).await?.is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_verify_integrity() -> Result<(), HypercoreError> {
+        let mut main = create_hypercore_with_data(10).await?;
+        main.flush_bitfield_and_tree_and_oplog(false).await?;
+        let report = main.verify_integrity(0..10).await?;
+        assert!(report.is_ok());
+        assert_eq!(report.checked_blocks, 10);
+
+        // Nodes a clone got from proofs are enough to verify its blocks
+        let mut clone = create_hypercore_with_data_and_key_pair(
+            0,
+            PartialKeypair {
+                public: main.key_pair.public,
+                secret: None,
+            },
+        )
+        .await?;
+        for index in [2, 7] {
+            let nodes = clone.missing_nodes(index).await?;
+            let upgrade = (index == 2).then_some(RequestUpgrade {
+                start: 0,
+                length: 10,
+            });
+            let proof = main
+                .create_proof(Some(RequestBlock { index, nodes }), None, None, upgrade)
+                .await?
+                .unwrap();
+            assert!(clone.verify_and_apply_proof(&proof).await?);
+        }
+        let report = clone.verify_integrity(0..100).await?;
+        assert!(report.is_ok());
+        assert_eq!(report.checked_blocks, 2);
+
+        // Corrupt the data of block 3, the hash of the parent of blocks 0 and 1, and zero the
+        // hash of the parent of blocks 4 and 5
+        main.storage
+            .flush_infos(&[
+                StoreInfo::new_content(Store::Data, 6, b"#x"),
+                StoreInfo::new_content(Store::Tree, 40 + 8, &[1; 32]),
+                StoreInfo::new_content(Store::Tree, 9 * 40 + 8, &[0; 32]),
+            ])
+            .await?;
+        let report = main.verify_integrity(0..10).await?;
+        assert_eq!(report.checked_blocks, 10);
+        assert_eq!(report.bad_blocks, vec![3]);
+        assert_eq!(report.bad_nodes, vec![1, 9]);
+        assert!(report.valid_signature);
+        assert!(!report.is_ok());
+        let report = main.verify_integrity(4..6).await?;
+        assert_eq!(report.checked_blocks, 2);
+        assert!(report.bad_blocks.is_empty());
+        assert_eq!(report.bad_nodes, vec![9]);
+
+        // Repairing clears the bad blocks, so they are no longer checked
+        let report = main.repair_integrity(0..10).await?;
+        assert_eq!(report.bad_blocks, vec![3]);
+        assert!(!main.has(3).await?);
+        let report = main.verify_integrity(0..10).await?;
+        assert_eq!(report.checked_blocks, 9);
+        assert!(report.bad_blocks.is_empty());
+        assert_eq!(report.bad_nodes, vec![1, 9]);
+
+        main.tree.signature = Some(vec![0; 64].into_boxed_slice());
+        assert!(!main.verify_integrity(0..0).await?.valid_signature);
+
+        // Blocks 8 and 9 rewritten with their leaves and root, which is consistent with its
+        // children but not the signed root
+        let mut main = create_hypercore_with_data(10).await?;
+        main.flush_bitfield_and_tree_and_oplog(false).await?;
+        let left = Node::new(16, Hash::data(b"#y").as_bytes().to_vec(), 2);
+        let right = Node::new(18, Hash::data(b"#z").as_bytes().to_vec(), 2);
+        let root = Hash::parent(&left, &right);
+        main.storage
+            .flush_infos(&[
+                StoreInfo::new_content(Store::Data, 16, b"#y#z"),
+                StoreInfo::new_content(Store::Tree, 16 * 40 + 8, &left.hash),
+                StoreInfo::new_content(Store::Tree, 17 * 40 + 8, root.as_bytes()),
+                StoreInfo::new_content(Store::Tree, 18 * 40 + 8, &right.hash),
+            ])
+            .await?;
+        let report = main.verify_integrity(0..10).await?;
+        assert!(report.bad_blocks.is_empty());
+        assert_eq!(report.bad_nodes, vec![17]);
+        assert!(report.valid_signature);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_truncate() -> Result<(), HypercoreError> {
+        let mut hypercore = create_hypercore_with_data(10).await?;
+        let byte_length = hypercore.info().byte_length;
+        hypercore.truncate(5, None).await?;
+        let info = hypercore.info();
+        assert_eq!(info.length, 5);
+        assert_eq!(info.byte_length, byte_length - 5 * 2);
+        assert_eq!(info.contiguous_length, 5);
+        assert_eq!(info.fork, 1);
+        assert_eq!(hypercore.get(4).await?, Some(b"#4".to_vec()));
+        assert!(!hypercore.has(5).await?);
+        assert_eq!(hypercore.get(5).await?, None);
+
+        // Appending after truncation continues from the new length
+        let outcome = hypercore.append(b"new").await?;
+        assert_eq!(outcome.length, 6);
+        assert_eq!(hypercore.get(5).await?, Some(b"new".to_vec()));
+
+        // Explicit fork and truncation to zero
+        hypercore.truncate(0, Some(10)).await?;
+        let info = hypercore.info();
+        assert_eq!(info.length, 0);
+        assert_eq!(info.byte_length, 0);
+        assert_eq!(info.fork, 10);
+        assert_eq!(hypercore.append(b"#0").await?.length, 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_truncate_reorg_hints() -> Result<(), HypercoreError> {
+        let mut hypercore = create_hypercore_with_data(10).await?;
+        hypercore.truncate(5, None).await?;
+        assert_eq!(
+            hypercore.header.hints.reorgs,
+            vec![ReorgHint {
+                from: 0,
+                to: 1,
+                ancestors: 5
+            }]
+        );
+        // A hint with more ancestors than the latest reorg is replaced
+        hypercore.append(b"#5").await?;
+        hypercore.truncate(3, None).await?;
+        assert_eq!(
+            hypercore.header.hints.reorgs,
+            vec![ReorgHint {
+                from: 1,
+                to: 2,
+                ancestors: 3
+            }]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_truncate_invalid() -> Result<(), HypercoreError> {
+        let mut hypercore = create_hypercore_with_data(3).await?;
+        assert!(matches!(
+            hypercore.truncate(4, None).await,
+            Err(HypercoreError::BadArgument { .. })
+        ));
+        hypercore.truncate(2, Some(3)).await?;
+        for fork in [0, 3] {
+            assert!(matches!(
+                hypercore.truncate(1, Some(fork)).await,
+                Err(HypercoreError::BadArgument { .. })
+            ));
+        }
+        assert_eq!(hypercore.info().fork, 3);
+        hypercore.make_read_only().await?;
+        assert!(matches!(
+            hypercore.truncate(1, None).await,
+            Err(HypercoreError::NotWritable)
+        ));
+        assert_eq!(hypercore.info().length, 2);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_read_stream() -> Result<(), HypercoreError> {
+        let mut hypercore = create_hypercore_with_data(100).await?;
+        let values: Vec<Vec<u8>> = hypercore
+            .read_stream(0..100, ReadStreamOptions::default())
+            .try_collect()
+            .await?;
+        let expected: Vec<Vec<u8>> = (0..100).map(|i| format!("#{i}").into_bytes()).collect();
+        assert_eq!(values, expected);
+
+        // End is capped to length
+        let values: Vec<Vec<u8>> = hypercore
+            .read_stream(95..200, ReadStreamOptions::default())
+            .try_collect()
+            .await?;
+        assert_eq!(values, expected[95..]);
+
+        let values: Vec<Vec<u8>> = hypercore
+            .read_stream(
+                10..80,
+                ReadStreamOptions {
+                    reverse: true,
+                    ..Default::default()
+                },
+            )
+            .try_collect()
+            .await?;
+        let reversed: Vec<Vec<u8>> = expected[10..80].iter().rev().cloned().collect();
+        assert_eq!(values, reversed);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_read_stream_missing() -> Result<(), HypercoreError> {
+        let mut hypercore = create_hypercore_with_data(10).await?;
+        hypercore.clear(3, 5).await?;
+
+        let values: Vec<Vec<u8>> = hypercore
+            .read_stream(
+                0..10,
+                ReadStreamOptions {
+                    skip_missing: true,
+                    ..Default::default()
+                },
+            )
+            .try_collect()
+            .await?;
+        let expected: Vec<Vec<u8>> = [0, 1, 2, 5, 6, 7, 8, 9]
+            .iter()
+            .map(|i| format!("#{i}").into_bytes())
+            .collect();
+        assert_eq!(values, expected);
+
+        let results: Vec<Result<Vec<u8>, HypercoreError>> = hypercore
+            .read_stream(0..10, ReadStreamOptions::default())
+            .collect()
+            .await;
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[2].as_ref().unwrap(), b"#2");
+        assert!(matches!(
+            results[3],
+            Err(HypercoreError::InvalidOperation { .. })
+        ));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_get_range() -> Result<(), HypercoreError> {
+        let mut hypercore = create_hypercore_with_data(20).await?;
+        let expected: Vec<Option<Vec<u8>>> = (0..20)
+            .map(|i| Some(format!("#{i}").into_bytes()))
+            .collect();
+        assert_eq!(hypercore.get_range(0, 20).await?, expected);
+        assert_eq!(hypercore.get_range(5, 12).await?, expected[5..12]);
+        assert_eq!(
+            hypercore.get_range(19, 22).await?,
+            [expected[19].clone(), None, None]
+        );
+        assert!(hypercore.get_range(5, 5).await?.is_empty());
+
+        hypercore.clear(3, 5).await?;
+        hypercore.clear(18, 20).await?;
+        let values = hypercore.get_range(0, 20).await?;
+        for (i, value) in values.iter().enumerate() {
+            if (3..5).contains(&i) || i >= 18 {
+                assert_eq!(value, &None);
+            } else {
+                assert_eq!(value, &expected[i]);
+            }
+        }
+        assert_eq!(hypercore.get_range(3, 5).await?, [None, None]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_get_range_sparse() -> Result<(), HypercoreError> {
+        let mut main = create_hypercore_with_data(10).await?;
+        let mut clone = create_hypercore_with_data_and_key_pair(
+            0,
+            PartialKeypair {
+                public: main.key_pair.public,
+                secret: None,
+            },
+        )
+        .await?;
+        let proof = main
+            .create_proof(
+                None,
+                None,
+                None,
+                Some(RequestUpgrade {
+                    start: 0,
+                    length: 10,
+                }),
+            )
+            .await?
+            .unwrap();
+        assert!(clone.verify_and_apply_proof(&proof).await?);
+        for index in [2, 3, 7] {
+            let nodes = clone.missing_nodes(index).await?;
+            let proof = main
+                .create_proof(Some(RequestBlock { index, nodes }), None, None, None)
+                .await?
+                .unwrap();
+            assert!(clone.verify_and_apply_proof(&proof).await?);
+        }
+
+        // Leaves of the holes are partly unknown
+        let values = clone.get_range(0, 10).await?;
+        for (i, value) in values.iter().enumerate() {
+            if [2, 3, 7].contains(&i) {
+                assert_eq!(value, &Some(format!("#{i}").into_bytes()));
+            } else {
+                assert_eq!(value, &None);
+            }
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_seek_and_byte_offset() -> Result<(), HypercoreError> {
+        let mut hypercore = create_hypercore_with_data(20).await?;
+        let byte_length = hypercore.info().byte_length;
+        assert_eq!(byte_length, 10 * 2 + 10 * 3);
+        assert_eq!(hypercore.seek(0).await?, Some((0, 0)));
+        assert_eq!(hypercore.seek(1).await?, Some((0, 1)));
+        assert_eq!(hypercore.seek(2).await?, Some((1, 0)));
+        assert_eq!(hypercore.seek(21).await?, Some((10, 1)));
+        assert_eq!(hypercore.seek(byte_length - 1).await?, Some((19, 2)));
+        assert_eq!(hypercore.seek(byte_length).await?, Some((20, 0)));
+        assert_eq!(hypercore.seek(byte_length + 1).await?, None);
+
+        assert_eq!(hypercore.byte_offset(10).await?, 20);
+        assert_eq!(hypercore.byte_offset(20).await?, byte_length);
+        assert!(matches!(
+            hypercore.byte_offset(21).await,
+            Err(HypercoreError::BadArgument { .. })
+        ));
+        for index in 0..20 {
+            let byte_offset = hypercore.byte_offset(index).await?;
+            assert_eq!(hypercore.seek(byte_offset).await?, Some((index, 0)));
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_seek_without_local_tree() -> Result<(), HypercoreError> {
+        let mut main = create_hypercore_with_data(10).await?;
+        let mut clone = create_hypercore_with_data_and_key_pair(
+            0,
+            PartialKeypair {
+                public: main.key_pair.public,
+                secret: None,
+            },
+        )
+        .await?;
+        let proof = main
+            .create_proof(
+                None,
+                None,
+                None,
+                Some(RequestUpgrade {
+                    start: 0,
+                    length: 10,
+                }),
+            )
+            .await?
+            .unwrap();
+        assert!(clone.verify_and_apply_proof(&proof).await?);
+
+        // Only the roots are known, so only their boundaries can be resolved
+        assert_eq!(clone.seek(16).await?, Some((8, 0)));
+        assert_eq!(clone.seek(20).await?, Some((10, 0)));
+        assert_eq!(clone.seek(17).await?, None);
+        assert_eq!(clone.seek(3).await?, None);
+        assert_eq!(main.seek(3).await?, Some((1, 1)));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_user_data() -> Result<(), HypercoreError> {
+        let mut hypercore = create_hypercore_with_data(1).await?;
+        assert_eq!(hypercore.get_user_data("hyperbee"), None);
+        hypercore.set_user_data("hyperbee", Some(b"v1")).await?;
+        hypercore.set_user_data("other", Some(b"x")).await?;
+        hypercore.set_user_data("hyperbee", Some(b"v2")).await?;
+        assert_eq!(hypercore.get_user_data("hyperbee"), Some(&b"v2"[..]));
+        assert_eq!(hypercore.get_user_data("other"), Some(&b"x"[..]));
+
+        hypercore.set_user_data("other", None).await?;
+        assert_eq!(hypercore.get_user_data("other"), None);
+        // Removing a missing key does not log anything
+        let entries_length = hypercore.oplog.entries_length;
+        hypercore.set_user_data("missing", None).await?;
+        hypercore.set_user_data("hyperbee", Some(b"v2")).await?;
+        assert_eq!(hypercore.oplog.entries_length, entries_length);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_encrypted() -> Result<(), HypercoreError> {
+        let key_pair = generate_signing_key();
+        let mut main = create_encrypted_hypercore_with_data(
+            10,
+            PartialKeypair {
+                public: key_pair.verifying_key(),
+                secret: Some(key_pair),
+            },
+            Some([7; 32]),
+        )
+        .await?;
+        let info = main.info();
+        assert_eq!(info.byte_length, 10 * 2);
+        assert_eq!(main.get(3).await?, Some(b"#3".to_vec()));
+        assert_eq!(
+            main.get_range(8, 11).await?,
+            [Some(b"#8".to_vec()), Some(b"#9".to_vec()), None]
+        );
+        assert_eq!(main.byte_offset(5).await?, 10);
+        assert_eq!(main.seek(11).await?, Some((5, 1)));
+        assert_eq!(main.seek(20).await?, Some((10, 0)));
+
+        // Stored blocks are encrypted and padded
+        let stored = main.get_block(3).await?.unwrap();
+        assert_eq!(stored.len(), BLOCK_ENCRYPTION_PADDING + 2);
+        assert_ne!(&stored[BLOCK_ENCRYPTION_PADDING..], b"#3");
+
+        // Replicas with the same encryption key read the values from proofs
+        let public = PartialKeypair {
+            public: main.key_pair.public,
+            secret: None,
+        };
+        let mut clone =
+            create_encrypted_hypercore_with_data(0, public.clone(), Some([7; 32])).await?;
+        let mut other = create_encrypted_hypercore_with_data(0, public, None).await?;
+        let proof = main
+            .create_proof(
+                Some(RequestBlock { index: 3, nodes: 0 }),
+                None,
+                None,
+                Some(RequestUpgrade {
+                    start: 0,
+                    length: 10,
+                }),
+            )
+            .await?
+            .unwrap();
+        assert!(clone.verify_and_apply_proof(&proof).await?);
+        assert!(other.verify_and_apply_proof(&proof).await?);
+        assert_eq!(clone.info().byte_length, 10 * 2);
+        assert_eq!(clone.get(3).await?, Some(b"#3".to_vec()));
+        assert_eq!(other.get(3).await?, Some(stored));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_flush_interval() -> Result<(), HypercoreError> {
+        let mut core = crate::HypercoreBuilder::new(Storage::new_memory().await?)
+            .flush_interval(5)
+            .build()
+            .await?;
+        // The first append flushes, the next five are only in the oplog
+        for i in 0..6 {
+            core.append(format!("#{i}").as_bytes()).await?;
+        }
+        assert_eq!(core.oplog.entries_length, 5);
+        core.append(b"#6").await?;
+        assert_eq!(core.oplog.entries_length, 0);
+        core.append(b"#7").await?;
+        assert_eq!(core.oplog.entries_length, 1);
+        core.flush().await?;
+        assert_eq!(core.oplog.entries_length, 0);
+
+        let mut core = crate::HypercoreBuilder::new(Storage::new_memory().await?)
+            .flush_interval(0)
+            .build()
+            .await?;
+        core.append(b"#0").await?;
+        core.append(b"#1").await?;
+        assert_eq!(core.oplog.entries_length, 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn core_durability() -> Result<(), HypercoreError> {
+        let mut core = crate::HypercoreBuilder::new(Storage::new_memory().await?)
+            .build()
+            .await?;
+        assert!(!core.append(b"#0").await?.durable);
+
+        // Only appends that flush are synced
+        let mut core = crate::HypercoreBuilder::new(Storage::new_memory().await?)
+            .durability(Durability::SyncOnFlush)
+            .flush_interval(1)
+            .build()
+            .await?;
+        assert!(core.append(b"#0").await?.durable);
+        assert!(!core.append(b"#1").await?.durable);
+        assert!(core.append(b"#2").await?.durable);
+
+        let mut core = crate::HypercoreBuilder::new(Storage::new_memory().await?)
+            .durability(Durability::SyncEveryAppend)
+            .flush_interval(1)
+            .build()
+            .await?;
+        assert!(core.append(b"#0").await?.durable);
+        assert!(core.append(b"#1").await?.durable);
+        let mut batch = core.batch();
+        batch.append(b"#2")?;
+        assert!(!batch.append(b"#3")?.durable);
+        assert!(batch.flush().await?.durable);
+        Ok(())
+    }
+
     pub(crate) async fn create_hypercore_with_data(
         length: u64,
     ) -> Result<Hypercore, HypercoreError> {
@@ -1152,13 +3413,30 @@ pub(crate) mod tests {
     pub(crate) async fn create_hypercore_with_data_and_key_pair(
         length: u64,
         key_pair: PartialKeypair,
+    ) -> Result<Hypercore, HypercoreError> {
+        create_encrypted_hypercore_with_data(length, key_pair, None).await
+    }
+
+    async fn create_encrypted_hypercore_with_data(
+        length: u64,
+        key_pair: PartialKeypair,
+        encryption_key: Option<[u8; 32]>,
     ) -> Result<Hypercore, HypercoreError> {
         let storage = Storage::new_memory().await?;
         let mut hypercore = Hypercore::new(
             storage,
             HypercoreOptions {
                 key_pair: Some(key_pair),
+                key: None,
+                manifest: None,
                 open: false,
+                encryption_key,
+                freeze: false,
+                recover: false,
+                recovery_fork: None,
+                flush_interval: DEFAULT_FLUSH_INTERVAL,
+                durability: Durability::None,
+                bitfield_memory_budget: DEFAULT_BITFIELD_MEMORY_BUDGET,
                 #[cfg(feature = "cache")]
                 node_cache_options: None,
             },
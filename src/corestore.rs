@@ -0,0 +1,302 @@
+//! Manage many hypercores under one storage root, compatible with Javascript's
+//! [corestore](https://github.com/holepunchto/corestore).
+//!
+//! Writable hypercores are opened by name, with key pairs derived from the primary key of the
+//! store and a namespace. Any hypercore can be opened by its key. Each hypercore is stored in
+//! its own directory named after its discovery key, or in its own memory instance.
+use async_lock::Mutex;
+use blake2::{
+    digest::{typenum::U32, FixedOutput},
+    Blake2b, Blake2bMac, Digest,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use data_encoding::HEXLOWER;
+use ed25519_dalek::SigningKey;
+use rand::{rngs::OsRng, RngCore};
+#[cfg(not(target_arch = "wasm32"))]
+use random_access_disk::RandomAccessDisk;
+#[cfg(not(target_arch = "wasm32"))]
+use random_access_storage::RandomAccess;
+use std::collections::HashMap;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::storage::map_random_access_err;
+use crate::{
+    replication::SharedCore, CoreId, HypercoreBuilder, HypercoreError, PartialKeypair, Storage,
+};
+
+// This is the output of `crypto.namespace('corestore', 1)[0]` of hypercore-crypto, i.e.
+// blake2b(blake2b("corestore") || [0]), see:
+// https://github.com/holepunchto/corestore/blob/v6.18.4/index.js#L20
+const CORESTORE: [u8; 32] = [
+    0xAC, 0x5C, 0x4C, 0xBF, 0xB1, 0x06, 0x47, 0x76, 0x40, 0x46, 0x37, 0xA2, 0x80, 0xC7, 0x1F, 0xAC,
+    0x82, 0x32, 0x81, 0xD3, 0x51, 0xEB, 0xEC, 0xED, 0x03, 0x15, 0x17, 0x43, 0x27, 0x0D, 0xEF, 0x29,
+];
+
+const DEFAULT_NAMESPACE: [u8; 32] = [0; 32];
+
+#[cfg(not(target_arch = "wasm32"))]
+const PRIMARY_KEY_FILE: &str = "primary-key";
+#[cfg(not(target_arch = "wasm32"))]
+const CORES_DIR: &str = "cores";
+
+/// Store of many hypercores sharing a primary key and a storage root. Clones and namespaces
+/// of a store share the open hypercores.
+///
+/// Opened hypercores are kept open for as long as the store exists: they are not evicted or
+/// closed by the store. Flush them with [`crate::Hypercore::flush`] before dropping the
+/// store, otherwise their unflushed changes are replayed from the oplog on the next open.
+#[derive(Debug, Clone)]
+pub struct Corestore {
+    inner: Arc<Inner>,
+    namespace: [u8; 32],
+}
+
+#[derive(Debug)]
+struct Inner {
+    root: Root,
+    primary_key: [u8; 32],
+    // Open hypercores by discovery key
+    cores: Mutex<HashMap<[u8; 32], SharedCore>>,
+}
+
+#[derive(Debug)]
+enum Root {
+    Memory,
+    #[cfg(not(target_arch = "wasm32"))]
+    Disk(PathBuf),
+}
+
+impl Corestore {
+    /// New store keeping each hypercore in memory, with a random primary key.
+    pub fn new_memory() -> Self {
+        let mut primary_key = [0; 32];
+        OsRng.fill_bytes(&mut primary_key);
+        Self::new_memory_with_primary_key(primary_key)
+    }
+
+    /// New store keeping each hypercore in memory, with the given primary key.
+    pub fn new_memory_with_primary_key(primary_key: [u8; 32]) -> Self {
+        Self::new(Root::Memory, primary_key)
+    }
+
+    /// New store keeping each hypercore in a directory under `dir`. The primary key is read
+    /// from `dir`, or generated and stored there if the store is new. Fails if the stored
+    /// primary key is not 32 bytes, as the key pairs derived from it would be lost.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn new_disk(dir: &Path) -> Result<Self, HypercoreError> {
+        let mut file = RandomAccessDisk::open(dir.join(PRIMARY_KEY_FILE))
+            .await
+            .map_err(map_random_access_err)?;
+        let primary_key: [u8; 32] = match file.len().await.map_err(map_random_access_err)? {
+            32 => file
+                .read(0, 32)
+                .await
+                .map_err(map_random_access_err)?
+                .try_into()
+                .expect("Read 32 bytes"),
+            0 => {
+                let mut primary_key = [0; 32];
+                OsRng.fill_bytes(&mut primary_key);
+                file.write(0, &primary_key)
+                    .await
+                    .map_err(map_random_access_err)?;
+                file.sync_all().await.map_err(map_random_access_err)?;
+                primary_key
+            }
+            length => {
+                return Err(HypercoreError::InvalidOperation {
+                    context: format!(
+                        "Primary key file of the store is {length} bytes instead of 32"
+                    ),
+                })
+            }
+        };
+        Ok(Self::new(Root::Disk(dir.to_path_buf()), primary_key))
+    }
+
+    fn new(root: Root, primary_key: [u8; 32]) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                root,
+                primary_key,
+                cores: Mutex::new(HashMap::new()),
+            }),
+            namespace: DEFAULT_NAMESPACE,
+        }
+    }
+
+    /// Primary key the key pairs of the store are derived from
+    pub fn primary_key(&self) -> &[u8; 32] {
+        &self.inner.primary_key
+    }
+
+    /// Store sharing the open hypercores of this one, whose key pairs are derived in the
+    /// given namespace nested in the namespace of this store.
+    pub fn namespace(&self, name: &str) -> Self {
+        let mut hasher = Blake2b::<U32>::new();
+        hasher.update(self.namespace);
+        hasher.update(name.as_bytes());
+        Self {
+            inner: self.inner.clone(),
+            namespace: hasher.finalize().into(),
+        }
+    }
+
+    /// Key pair of the hypercore with the given name in the namespace of the store. This is
+    /// `createKeyPair` in Javascript.
+    pub fn create_key_pair(&self, name: &str) -> PartialKeypair {
+        let mut hasher =
+            Blake2bMac::<U32>::new_with_salt_and_personal(&self.inner.primary_key, &[], &[])
+                .expect("Primary key is a valid blake2b key");
+        blake2::digest::Update::update(&mut hasher, &CORESTORE);
+        blake2::digest::Update::update(&mut hasher, &self.namespace);
+        blake2::digest::Update::update(&mut hasher, name.as_bytes());
+        let seed: [u8; 32] = hasher.finalize_fixed().into();
+        let signing_key = SigningKey::from_bytes(&seed);
+        PartialKeypair {
+            public: signing_key.verifying_key(),
+            secret: Some(signing_key),
+        }
+    }
+
+    /// Get the writable hypercore with the given name in the namespace of the store,
+    /// creating it if it does not exist. A hypercore that was opened read-only by its key
+    /// is made writable.
+    pub async fn get_by_name(&self, name: &str) -> Result<SharedCore, HypercoreError> {
+        let key_pair = self.create_key_pair(name);
+        self.get(CoreId::from(key_pair.public), Some(key_pair))
+            .await
+    }
+
+    /// Get the hypercore with the given key. A hypercore not in the store is created
+    /// read-only, e.g. to replicate it from a peer.
+    pub async fn get_by_key(&self, id: CoreId) -> Result<SharedCore, HypercoreError> {
+        self.get(id, None).await
+    }
+
+    async fn get(
+        &self,
+        id: CoreId,
+        key_pair: Option<PartialKeypair>,
+    ) -> Result<SharedCore, HypercoreError> {
+        let discovery_key = id.discovery_key();
+        // NB: Hold the lock while opening, so that a hypercore is opened only once
+        let mut cores = self.inner.cores.lock().await;
+        let core = match cores.get(&discovery_key) {
+            Some(core) => core.clone(),
+            None => {
+                let storage = self.inner.root.storage(&discovery_key).await?;
+                let builder = HypercoreBuilder::new(storage).key(id);
+                let builder = match &key_pair {
+                    Some(key_pair) => builder.key_pair(key_pair.clone()),
+                    None => builder,
+                };
+                let core = SharedCore::from(builder.build().await?);
+                cores.insert(discovery_key, core.clone());
+                core
+            }
+        };
+        // The hypercore may have been created read-only by its key before
+        if let Some(secret_key) = key_pair.and_then(|key_pair| key_pair.secret) {
+            let mut hypercore = core.0.lock().await;
+            if hypercore.key_pair().secret.is_none() {
+                hypercore.add_secret_key(secret_key).await?;
+            }
+        }
+        Ok(core)
+    }
+}
+
+impl Root {
+    async fn storage(&self, discovery_key: &[u8; 32]) -> Result<Storage, HypercoreError> {
+        match self {
+            Root::Memory => Storage::new_memory().await,
+            #[cfg(not(target_arch = "wasm32"))]
+            Root::Disk(dir) => {
+                // Same layout as in Javascript: cores/ab/cd/abcd...
+                let id = HEXLOWER.encode(discovery_key);
+                let dir = dir
+                    .join(CORES_DIR)
+                    .join(&id[0..2])
+                    .join(&id[2..4])
+                    .join(&id);
+                Storage::new_disk(&dir, false).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replication::{CoreInfo, CoreMethods, CoreMethodsError};
+
+    #[async_std::test]
+    async fn corestore_key_pairs_and_namespaces() -> Result<(), CoreMethodsError> {
+        let store = Corestore::new_memory_with_primary_key([1; 32]);
+        let key_pair = store.create_key_pair("a");
+        assert_eq!(
+            key_pair.public,
+            Corestore::new_memory_with_primary_key([1; 32])
+                .create_key_pair("a")
+                .public
+        );
+        assert_ne!(key_pair.public, store.create_key_pair("b").public);
+        assert_ne!(
+            key_pair.public,
+            Corestore::new_memory_with_primary_key([2; 32])
+                .create_key_pair("a")
+                .public
+        );
+
+        let namespace = store.namespace("ns");
+        assert_ne!(key_pair.public, namespace.create_key_pair("a").public);
+        assert_eq!(
+            namespace.create_key_pair("a").public,
+            store.namespace("ns").create_key_pair("a").public
+        );
+        assert_ne!(
+            namespace.namespace("ns").create_key_pair("a").public,
+            namespace.create_key_pair("a").public
+        );
+
+        // Namespaces share the open hypercores
+        let core = store.get_by_name("a").await?;
+        core.append(b"hello").await?;
+        let same = store.get_by_key(CoreId::from(key_pair.public)).await?;
+        assert_eq!(same.info().await.length, 1);
+        let other = namespace.get_by_name("a").await?;
+        assert_eq!(other.info().await.length, 0);
+        assert!(other.key_pair().await.secret.is_some());
+
+        let read_only = store
+            .get_by_key(CoreId::from(crate::generate_signing_key().verifying_key()))
+            .await?;
+        assert!(read_only.key_pair().await.secret.is_none());
+
+        // A hypercore opened by key first is writable when opened by name
+        let id = CoreId::from(store.create_key_pair("c").public);
+        assert!(store
+            .get_by_key(id)
+            .await?
+            .key_pair()
+            .await
+            .secret
+            .is_none());
+        let writable = store.get_by_name("c").await?;
+        assert!(writable.key_pair().await.secret.is_some());
+        writable.append(b"hello").await?;
+        assert!(store
+            .get_by_key(id)
+            .await?
+            .key_pair()
+            .await
+            .secret
+            .is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+//! Block encryption compatible with the `encryptionKey` option of Javascript.
+//! See https://github.com/holepunchto/hypercore/blob/v10.38.2/lib/block-encryption.js
+use blake2::{
+    digest::{typenum::U32, FixedOutput},
+    Blake2b, Blake2bMac, Digest,
+};
+use salsa20::{
+    cipher::{KeyIvInit, StreamCipher},
+    XSalsa20,
+};
+use std::fmt;
+
+use crate::HypercoreError;
+
+// This is `BLOCK_ENCRYPTION` of caps.js in Javascript, the sixth (index 5) hash of
+// `crypto.namespace('hypercore', 6)`, see the `hash_namespace` test of hash.rs
+pub(crate) const BLOCK_ENCRYPTION: [u8; 32] = [
+    0x9A, 0xED, 0xB6, 0xF1, 0xDE, 0xA8, 0x48, 0x7C, 0xC1, 0xE1, 0x69, 0x8F, 0x97, 0xEB, 0x2F, 0x06,
+    0x3F, 0x6D, 0xB4, 0xC6, 0x49, 0x69, 0x56, 0x22, 0xE7, 0xB6, 0x75, 0xE2, 0xCE, 0xAF, 0xA6, 0xE9,
+];
+
+/// Bytes prepended to every block, which hold the blinded fork id used in the nonce
+pub(crate) const BLOCK_ENCRYPTION_PADDING: usize = 8;
+
+/// Encrypts and decrypts blocks with keys derived from the encryption key and the key of
+/// the hypercore.
+#[derive(Clone)]
+pub(crate) struct BlockEncryption {
+    block_key: [u8; 32],
+    blinding_key: [u8; 32],
+}
+
+impl fmt::Debug for BlockEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // NB: Never print the keys
+        f.debug_struct("BlockEncryption").finish_non_exhaustive()
+    }
+}
+
+impl BlockEncryption {
+    /// Derive keys for the hypercore with given key. Compat hypercores, whose key is their
+    /// public key, use the older derivation without a namespace.
+    pub(crate) fn new(encryption_key: &[u8; 32], hypercore_key: &[u8; 32], compat: bool) -> Self {
+        let block_key: [u8; 32] = if compat {
+            let mut hasher = Blake2bMac::<U32>::new_with_salt_and_personal(hypercore_key, &[], &[])
+                .expect("Key length is valid");
+            blake2::digest::Update::update(&mut hasher, encryption_key);
+            hasher.finalize_fixed().into()
+        } else {
+            let mut hasher = Blake2b::<U32>::new();
+            Digest::update(&mut hasher, BLOCK_ENCRYPTION);
+            Digest::update(&mut hasher, hypercore_key);
+            Digest::update(&mut hasher, encryption_key);
+            hasher.finalize().into()
+        };
+        let blinding_key: [u8; 32] = Blake2b::<U32>::digest(block_key).into();
+        Self {
+            block_key,
+            blinding_key,
+        }
+    }
+
+    /// Encrypt a value at the given index and fork into a block, which is prefixed with
+    /// padding.
+    pub(crate) fn encrypt(&self, index: u64, value: &[u8], fork: u64) -> Vec<u8> {
+        let mut block = vec![0; BLOCK_ENCRYPTION_PADDING + value.len()];
+        let (padding, data) = block.split_at_mut(BLOCK_ENCRYPTION_PADDING);
+        data.copy_from_slice(value);
+
+        // Blind the fork id, possibly reusing the nonce on a reorg. This is fine as the
+        // blinding is best-effort and the fork id is shared on replication anyway.
+        padding.copy_from_slice(&fork.to_le_bytes());
+        let mut nonce = [0; 24];
+        nonce[..8].copy_from_slice(&index.to_le_bytes());
+        XSalsa20::new(&self.blinding_key.into(), &nonce.into()).apply_keystream(padding);
+
+        // The blinded fork id together with the index is unique for the hypercore, so
+        // they form a valid nonce for the block.
+        nonce[8..16].copy_from_slice(padding);
+        XSalsa20::new(&self.block_key.into(), &nonce.into()).apply_keystream(data);
+        block
+    }
+
+    /// Decrypt the block at the given index into its value.
+    pub(crate) fn decrypt(&self, index: u64, block: &[u8]) -> Result<Vec<u8>, HypercoreError> {
+        if block.len() < BLOCK_ENCRYPTION_PADDING {
+            return Err(HypercoreError::InvalidOperation {
+                context: format!("Encrypted block {index} is shorter than its padding"),
+            });
+        }
+        let (padding, data) = block.split_at(BLOCK_ENCRYPTION_PADDING);
+        let mut nonce = [0; 24];
+        nonce[..8].copy_from_slice(&index.to_le_bytes());
+        nonce[8..16].copy_from_slice(padding);
+        let mut value = data.to_vec();
+        XSalsa20::new(&self.block_key.into(), &nonce.into()).apply_keystream(&mut value);
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_encryption_round_trip() -> Result<(), HypercoreError> {
+        let encryption = BlockEncryption::new(&[1; 32], &[2; 32], true);
+        let block = encryption.encrypt(3, b"hello world", 0);
+        assert_eq!(block.len(), BLOCK_ENCRYPTION_PADDING + 11);
+        assert_ne!(&block[BLOCK_ENCRYPTION_PADDING..], b"hello world");
+        assert_eq!(encryption.decrypt(3, &block)?, b"hello world");
+
+        // Index and fork both affect the ciphertext
+        assert_ne!(encryption.encrypt(4, b"hello world", 0), block);
+        assert_ne!(encryption.encrypt(3, b"hello world", 1), block);
+        assert_ne!(encryption.decrypt(4, &block)?, b"hello world");
+
+        // Keys are bound to the hypercore
+        let other = BlockEncryption::new(&[1; 32], &[3; 32], true);
+        assert_ne!(other.decrypt(3, &block)?, b"hello world");
+        let other = BlockEncryption::new(&[1; 32], &[2; 32], false);
+        assert_ne!(other.decrypt(3, &block)?, b"hello world");
+        assert!(encryption.decrypt(3, &block[..4]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+//! Identifiers of hypercores, compatible with `hypercore-id-encoding` of Javascript.
+use data_encoding::{Encoding, Specification, HEXLOWER_PERMISSIVE};
+use ed25519_dalek::VerifyingKey;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use super::Hash;
+use crate::HypercoreError;
+
+const URL_SCHEME: &str = "hypercore://";
+const Z32_SYMBOLS: &str = "ybndrfg8ejkmcpqxot1uwisza345h769";
+
+fn z32() -> &'static Encoding {
+    static Z32: OnceLock<Encoding> = OnceLock::new();
+    Z32.get_or_init(|| {
+        let mut spec = Specification::new();
+        spec.symbols.push_str(Z32_SYMBOLS);
+        spec.encoding().expect("z-base32 specification is valid")
+    })
+}
+
+/// Get the discovery key of a hypercore with the given key. The discovery key can be
+/// announced on a public network without leaking the key itself.
+pub fn discovery_key(key: &[u8; 32]) -> [u8; 32] {
+    Hash::for_discovery_key(key)
+        .as_bytes()
+        .try_into()
+        .expect("Discovery key is 32 bytes")
+}
+
+/// Identifier of a hypercore, i.e. its key. The key is the public key of the hypercore, or
+/// the hash of its [`crate::Manifest`]. Parses from and formats to hex, z-base32 and
+/// `hypercore://` URLs. Formats as z-base32 with [`fmt::Display`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreId([u8; 32]);
+
+impl CoreId {
+    /// Create an identifier from the key of a hypercore
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// Key of the hypercore
+    pub fn key(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Discovery key of the hypercore, see [`discovery_key`]
+    pub fn discovery_key(&self) -> [u8; 32] {
+        discovery_key(&self.0)
+    }
+
+    /// Lowercase hex representation
+    pub fn to_hex(&self) -> String {
+        HEXLOWER_PERMISSIVE.encode(&self.0)
+    }
+
+    /// z-base32 representation
+    pub fn to_z32(&self) -> String {
+        z32().encode(&self.0)
+    }
+
+    /// `hypercore://` URL with the z-base32 representation as host
+    pub fn to_url(&self) -> String {
+        format!("{URL_SCHEME}{}", self.to_z32())
+    }
+}
+
+impl From<VerifyingKey> for CoreId {
+    fn from(key: VerifyingKey) -> Self {
+        Self(key.to_bytes())
+    }
+}
+
+impl From<[u8; 32]> for CoreId {
+    fn from(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+impl fmt::Display for CoreId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_z32())
+    }
+}
+
+impl FromStr for CoreId {
+    type Err = HypercoreError;
+
+    /// Parse a 64 character hex string, a 52 character z-base32 string, or either of them
+    /// as the host of a `hypercore://` URL.
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        let encoded = match id.strip_prefix(URL_SCHEME) {
+            Some(rest) => rest.trim_end_matches('/'),
+            None => id,
+        };
+        let bytes = match encoded.len() {
+            64 => HEXLOWER_PERMISSIVE.decode(encoded.as_bytes()),
+            52 => z32().decode(encoded.to_ascii_lowercase().as_bytes()),
+            length => {
+                return Err(HypercoreError::BadArgument {
+                    context: format!("Invalid hypercore id length {length}: {id}"),
+                })
+            }
+        }
+        .map_err(|err| HypercoreError::BadArgument {
+            context: format!("Invalid hypercore id {id}: {err}"),
+        })?;
+        Ok(Self(
+            bytes.try_into().expect("Decoded hypercore id is 32 bytes"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn core_id_formats() -> Result<(), HypercoreError> {
+        let key = VerifyingKey::from_bytes(&[
+            119, 143, 141, 149, 81, 117, 201, 46, 76, 237, 94, 79, 85, 99, 246, 155, 254, 192, 200,
+            108, 198, 246, 112, 53, 44, 69, 121, 67, 102, 111, 230, 57,
+        ])
+        .unwrap();
+        let id = CoreId::from(key);
+        let hex = id.to_hex();
+        assert_eq!(
+            hex,
+            "778f8d955175c92e4ced5e4f5563f69bfec0c86cc6f670352c457943666fe639"
+        );
+        let z32 = id.to_z32();
+        assert_eq!(z32, "q68a5fktqzr1hu8pm38ika9sux9cb1dca558ypjceihwg3uxhaho");
+        assert_eq!(id.to_string(), z32);
+        assert_eq!(id.to_url(), format!("hypercore://{z32}"));
+
+        assert_eq!(hex.parse::<CoreId>()?, id);
+        assert_eq!(hex.to_uppercase().parse::<CoreId>()?, id);
+        assert_eq!(z32.parse::<CoreId>()?, id);
+        assert_eq!(id.to_url().parse::<CoreId>()?, id);
+        assert_eq!(format!("hypercore://{hex}/").parse::<CoreId>()?, id);
+
+        assert_eq!(
+            id.discovery_key(),
+            [
+                37, 167, 138, 168, 22, 21, 132, 126, 186, 0, 153, 93, 242, 157, 212, 29, 126, 227,
+                15, 59, 1, 248, 146, 32, 159, 121, 183, 90, 87, 217, 137, 225,
+            ]
+        );
+
+        assert!("abc".parse::<CoreId>().is_err());
+        assert!(hex.replace('7', "z").parse::<CoreId>().is_err());
+        Ok(())
+    }
+}
@@ -4,7 +4,6 @@ use blake2::{
 };
 use byteorder::{BigEndian, WriteBytesExt};
 use compact_encoding::{as_array, to_encoded_bytes, EncodingError, FixedWidthEncoding};
-use ed25519_dalek::VerifyingKey;
 use merkle_tree_stream::Node as NodeTrait;
 use std::convert::AsRef;
 use std::mem;
@@ -30,11 +29,6 @@ const TREE: [u8; 32] = [
 //     0x4F, 0x35, 0x53, 0x43, 0xFF, 0x6F, 0xCB, 0x0F, 0x00, 0x52, 0x00, 0xE1, 0x2C, 0xD7, 0x47, 0xCB,
 // ];
 
-// const MANIFEST: [u8; 32] = [
-//     0xE6, 0x4B, 0x71, 0x08, 0xEA, 0xCC, 0xE4, 0x7C, 0xFC, 0x61, 0xAC, 0x85, 0x05, 0x68, 0xF5, 0x5F,
-//     0x8B, 0x15, 0xB8, 0x2E, 0xC5, 0xED, 0x78, 0xC4, 0xEC, 0x59, 0x7B, 0x03, 0x6E, 0x2A, 0x14, 0x98,
-// ];
-
 pub(crate) type Blake2bResult = GenericArray<u8, U32>;
 type Blake2b256 = Blake2b<U32>;
 
@@ -84,10 +78,8 @@ impl Hash {
 
     /// Hash a public key. Useful to find the key you're looking for on a public
     /// network without leaking the key itself.
-    #[allow(dead_code)]
-    pub(crate) fn for_discovery_key(public_key: VerifyingKey) -> Self {
-        let mut hasher =
-            Blake2bMac::<U32>::new_with_salt_and_personal(public_key.as_bytes(), &[], &[]).unwrap();
+    pub(crate) fn for_discovery_key(key: &[u8; 32]) -> Self {
+        let mut hasher = Blake2bMac::<U32>::new_with_salt_and_personal(key, &[], &[]).unwrap();
         blake2::digest::Update::update(&mut hasher, &HYPERCORE);
         Self {
             hash: hasher.finalize_fixed(),
@@ -222,12 +214,34 @@ pub(crate) fn signable_tree(hash: &[u8], length: u64, fork: u64) -> Box<[u8]> {
     .expect("Encoding should not fail")
 }
 
+/// Create a signable buffer for tree of a hypercore that is not compat, i.e. whose key is
+/// its manifest hash. This is treeSignable in Javascript, with the manifest hash or signer
+/// namespace as context.
+pub(crate) fn signable_tree_with_context(
+    context: &[u8; 32],
+    hash: &[u8],
+    length: u64,
+    fork: u64,
+) -> Box<[u8]> {
+    (|| {
+        Ok::<_, EncodingError>(to_encoded_bytes!(
+            &TREE,
+            context,
+            as_array::<32>(hash)?,
+            length.as_fixed_width(),
+            fork.as_fixed_width()
+        ))
+    })()
+    .expect("Encoding should not fail")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use self::data_encoding::HEXLOWER;
     use data_encoding;
+    use ed25519_dalek::VerifyingKey;
 
     fn hash_with_extra_byte(data: &[u8], byte: u8) -> Box<[u8]> {
         let mut hasher = Blake2b256::new();
@@ -301,7 +315,10 @@ mod tests {
             59, 1, 248, 146, 32, 159, 121, 183, 90, 87, 217, 137, 225,
         ];
 
-        assert_eq!(Hash::for_discovery_key(public_key).as_bytes(), expected);
+        assert_eq!(
+            Hash::for_discovery_key(public_key.as_bytes()).as_bytes(),
+            expected
+        );
 
         Ok(())
     }
@@ -352,5 +369,10 @@ mod tests {
         let ns = hash.as_slice();
         let tree: Box<[u8]> = { hash_with_extra_byte(ns, 0) };
         assert_eq!(tree, TREE.into());
+        let block_encryption: Box<[u8]> = hash_with_extra_byte(ns, 5);
+        assert_eq!(
+            block_encryption,
+            crate::crypto::block_encryption::BLOCK_ENCRYPTION.into()
+        );
     }
 }
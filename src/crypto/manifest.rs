@@ -1,3 +1,10 @@
+use blake2::{digest::typenum::U32, Blake2b, Digest};
+use compact_encoding::CompactEncoding;
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+
+use super::{sign, signable_tree, signable_tree_with_context, verify};
+use crate::{HypercoreError, Node};
+
 // These the output of the following link:
 // https://github.com/holepunchto/hypercore/blob/cf08b72f14ed7d9ef6d497ebb3071ee0ae20967e/lib/caps.js#L16
 
@@ -6,38 +13,540 @@ const DEFAULT_NAMESPACE: [u8; 32] = [
     0x4F, 0x35, 0x53, 0x43, 0xFF, 0x6F, 0xCB, 0x0F, 0x00, 0x52, 0x00, 0xE1, 0x2C, 0xD7, 0x47, 0xCB,
 ];
 
-// TODO: Eventually this would be used in manifestHash
+// Used in manifestHash
 // https://github.com/holepunchto/hypercore/blob/cf08b72f14ed7d9ef6d497ebb3071ee0ae20967e/lib/manifest.js#L211
-//
-// const MANIFEST: [u8; 32] = [
-//     0xE6, 0x4B, 0x71, 0x08, 0xEA, 0xCC, 0xE4, 0x7C, 0xFC, 0x61, 0xAC, 0x85, 0x05, 0x68, 0xF5, 0x5F,
-//     0x8B, 0x15, 0xB8, 0x2E, 0xC5, 0xED, 0x78, 0xC4, 0xEC, 0x59, 0x7B, 0x03, 0x6E, 0x2A, 0x14, 0x98,
-// ];
+const MANIFEST: [u8; 32] = [
+    0xE6, 0x4B, 0x71, 0x08, 0xEA, 0xCC, 0xE4, 0x7C, 0xFC, 0x61, 0xAC, 0x85, 0x05, 0x68, 0xF5, 0x5F,
+    0x8B, 0x15, 0xB8, 0x2E, 0xC5, 0xED, 0x78, 0xC4, 0xEC, 0x59, 0x7B, 0x03, 0x6E, 0x2A, 0x14, 0x98,
+];
 
-#[derive(Debug, Clone)]
-pub(crate) struct Manifest {
+/// Manifest of a hypercore, which defines who can sign its tree. Hypercores created from a
+/// manifest with [`crate::HypercoreBuilder::manifest`] have the manifest hash as their key,
+/// like hypercores of Javascript created with `compat: false`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub(crate) version: u64,
     pub(crate) hash: String,
-    // TODO: In v11 can be static
-    // pub(crate) static_core: Option<bool>,
-    pub(crate) signer: ManifestSigner,
-    // TODO: In v11 can have multiple signers
-    // pub(crate) multiple_signers: Option<bool>,
+    pub(crate) allow_patch: bool,
+    pub(crate) quorum: u64,
+    pub(crate) signers: Vec<ManifestSigner>,
+    pub(crate) prologue: Option<ManifestPrologue>,
 }
 
-#[derive(Debug, Clone)]
+/// Signer of a manifest
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct ManifestSigner {
     pub(crate) signature: String,
     pub(crate) namespace: [u8; 32],
     pub(crate) public_key: [u8; 32],
 }
 
-pub(crate) fn default_signer_manifest(public_key: [u8; 32]) -> Manifest {
-    Manifest {
-        hash: "blake2b".to_string(),
-        signer: ManifestSigner {
+/// Signature of a tree by one of the signers of a [`Manifest`]. Hypercores with a quorum of
+/// several signers are appended to with a signature assembled from partial signatures with
+/// [`Manifest::assemble_signature`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialSignature {
+    /// Index of the signer in the manifest
+    pub signer: u64,
+    /// Signature of the tree by the signer
+    pub signature: Signature,
+}
+
+/// Signature of a signer in a multisig
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MultisigInput {
+    pub(crate) signer: u64,
+    pub(crate) signature: [u8; 64],
+    pub(crate) patch: u64,
+}
+
+/// Signatures of a quorum of signers, stored as the tree signature of hypercores created
+/// from a manifest. Version 0 manifests use the flags based encoding of [`MultiSignatureV0`].
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MultiSignature {
+    pub(crate) proofs: Vec<MultisigInput>,
+    pub(crate) patch: Vec<Node>,
+}
+
+/// Multisig of version 0 manifests
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MultiSignatureV0(pub(crate) MultiSignature);
+
+/// Tree hash and length a hypercore started from
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ManifestPrologue {
+    pub(crate) hash: [u8; 32],
+    pub(crate) length: u64,
+}
+
+impl Manifest {
+    /// Version 1 manifest with the given public key as its only signer
+    pub fn new_single_signer(public_key: &VerifyingKey) -> Self {
+        Self {
+            version: 1,
+            hash: "blake2b".to_string(),
+            allow_patch: false,
+            quorum: 1,
+            signers: vec![ManifestSigner::new(public_key.to_bytes())],
+            prologue: None,
+        }
+    }
+
+    /// Version 1 manifest with the given public keys as signers, of which `quorum` need to
+    /// sign the tree.
+    pub fn new_multi_signer(
+        quorum: u64,
+        public_keys: &[VerifyingKey],
+    ) -> Result<Self, HypercoreError> {
+        if quorum == 0 || quorum > public_keys.len() as u64 {
+            return Err(HypercoreError::BadArgument {
+                context: format!(
+                    "Quorum {quorum} is invalid for {} signers",
+                    public_keys.len()
+                ),
+            });
+        }
+        Ok(Self {
+            version: 1,
+            hash: "blake2b".to_string(),
+            allow_patch: false,
+            quorum,
+            signers: public_keys
+                .iter()
+                .map(|public_key| ManifestSigner::new(public_key.to_bytes()))
+                .collect(),
+            prologue: None,
+        })
+    }
+
+    /// Static manifest without signers, whose hypercores consist of the tree with the given
+    /// hash and length and can not be appended to.
+    pub fn new_static(tree_hash: [u8; 32], length: u64) -> Self {
+        Self {
+            version: 1,
+            hash: "blake2b".to_string(),
+            allow_patch: false,
+            quorum: 0,
+            signers: vec![],
+            prologue: Some(ManifestPrologue {
+                hash: tree_hash,
+                length,
+            }),
+        }
+    }
+
+    /// Whether the manifest is static, i.e. has a prologue and no signers
+    pub fn is_static(&self) -> bool {
+        self.signers.is_empty() && self.prologue.is_some()
+    }
+
+    /// Number of signers that need to sign the tree
+    pub fn quorum(&self) -> u64 {
+        self.quorum
+    }
+
+    /// Combine partial signatures into the signature of a hypercore created from this
+    /// manifest. This is `assemble` in Javascript.
+    pub fn assemble_signature(
+        &self,
+        partials: &[PartialSignature],
+    ) -> Result<Vec<u8>, HypercoreError> {
+        let multisig = MultiSignature {
+            proofs: partials
+                .iter()
+                .map(|partial| MultisigInput {
+                    signer: partial.signer,
+                    signature: partial.signature.to_bytes(),
+                    patch: 0,
+                })
+                .collect(),
+            patch: vec![],
+        };
+        let encoded = if self.version == 0 {
+            MultiSignatureV0(multisig).to_encoded_bytes()?
+        } else {
+            multisig.to_encoded_bytes()?
+        };
+        Ok(encoded.into_vec())
+    }
+
+    fn decode_signature(&self, signature: &[u8]) -> Result<MultiSignature, HypercoreError> {
+        let decoded = if self.version == 0 {
+            MultiSignatureV0::decode(signature).map(|(multisig, _)| multisig.0)
+        } else {
+            MultiSignature::decode(signature).map(|(multisig, _)| multisig)
+        };
+        decoded.map_err(|err| HypercoreError::InvalidSignature {
+            context: format!("Could not parse multisig: {err}"),
+        })
+    }
+
+    /// Hash of the manifest, which is the key of hypercores created from it. This is
+    /// `manifestHash` in Javascript.
+    pub fn key(&self) -> Result<[u8; 32], HypercoreError> {
+        let encoded = self.to_encoded_bytes()?;
+        let mut hasher = Blake2b::<U32>::new();
+        hasher.update(MANIFEST);
+        hasher.update(&encoded);
+        Ok(hasher.finalize().into())
+    }
+
+    /// Public key of the first signer, which is the one signing with a key pair. Static
+    /// manifests have no signers, their hypercores get a public key derived from the
+    /// manifest hash that is not used to sign or verify anything.
+    pub(crate) fn signer_public_key(&self) -> Result<VerifyingKey, HypercoreError> {
+        if self.is_static() {
+            return Ok(SigningKey::from_bytes(&self.key()?).verifying_key());
+        }
+        let signer = self
+            .signers
+            .first()
+            .ok_or_else(|| HypercoreError::BadArgument {
+                context: "Manifest has no signers".to_string(),
+            })?;
+        VerifyingKey::from_bytes(&signer.public_key).map_err(|_| HypercoreError::BadArgument {
+            context: "Manifest signer has an invalid public key".to_string(),
+        })
+    }
+
+    /// Whether a hypercore with given key uses the signatures of older versions, i.e. its
+    /// key is the public key of the only signer.
+    pub(crate) fn is_compat(&self, key: &[u8; 32]) -> bool {
+        self.signers.len() == 1 && &self.signers[0].public_key == key
+    }
+}
+
+impl ManifestSigner {
+    fn new(public_key: [u8; 32]) -> Self {
+        Self {
             signature: "ed25519".to_string(),
             namespace: DEFAULT_NAMESPACE,
             public_key,
-        },
+        }
+    }
+}
+
+pub(crate) fn default_signer_manifest(public_key: [u8; 32]) -> Manifest {
+    Manifest {
+        version: 0,
+        hash: "blake2b".to_string(),
+        allow_patch: false,
+        quorum: 1,
+        signers: vec![ManifestSigner::new(public_key)],
+        prologue: None,
+    }
+}
+
+/// Signs and verifies trees of a hypercore according to its manifest. This is `Verifier`
+/// in Javascript.
+#[derive(Debug, Clone)]
+pub(crate) struct Verifier {
+    manifest: Manifest,
+    compat: bool,
+    manifest_hash: [u8; 32],
+}
+
+impl Verifier {
+    pub(crate) fn new(key: &[u8; 32], manifest: &Manifest) -> Result<Self, HypercoreError> {
+        Ok(Self {
+            manifest: manifest.clone(),
+            compat: manifest.is_compat(key),
+            manifest_hash: manifest.key()?,
+        })
+    }
+
+    /// Whether the hypercore uses the signatures of older versions
+    pub(crate) fn compat(&self) -> bool {
+        self.compat
+    }
+
+    /// Sign tree with given hash, length and fork. Hypercores created from a manifest get
+    /// a multisig of the single signature, which requires a quorum of one.
+    pub(crate) fn sign(
+        &self,
+        signing_key: &SigningKey,
+        hash: &[u8],
+        length: u64,
+        fork: u64,
+    ) -> Result<Box<[u8]>, HypercoreError> {
+        if self.manifest.quorum > 1 {
+            return Err(HypercoreError::InvalidOperation {
+                context: format!(
+                    "Manifest requires a quorum of {} signatures",
+                    self.manifest.quorum
+                ),
+            });
+        }
+        let partial = self.sign_partial(signing_key, hash, length, fork)?;
+        if self.compat {
+            Ok(partial.signature.to_bytes().into())
+        } else {
+            Ok(self
+                .manifest
+                .assemble_signature(&[partial])?
+                .into_boxed_slice())
+        }
+    }
+
+    /// Sign tree with given hash, length and fork as one of the signers of the manifest.
+    pub(crate) fn sign_partial(
+        &self,
+        signing_key: &SigningKey,
+        hash: &[u8],
+        length: u64,
+        fork: u64,
+    ) -> Result<PartialSignature, HypercoreError> {
+        let public_key = signing_key.verifying_key().to_bytes();
+        let signer = self
+            .manifest
+            .signers
+            .iter()
+            .position(|signer| signer.public_key == public_key)
+            .ok_or_else(|| HypercoreError::BadArgument {
+                context: "Key pair is not a signer of the manifest".to_string(),
+            })?;
+        Ok(PartialSignature {
+            signer: signer as u64,
+            signature: sign(
+                signing_key,
+                &self.signable(&self.manifest.signers[signer], hash, length, fork),
+            ),
+        })
+    }
+
+    /// Verify the signature of the tree with given hash, length and fork. Except for compat
+    /// hypercores, the signature is a multisig, of which the first `quorum` signatures
+    /// need to be by distinct signers. Trees up to the length of the prologue are instead
+    /// verified against the prologue hash.
+    pub(crate) fn verify(
+        &self,
+        signature: &[u8],
+        hash: &[u8],
+        length: u64,
+        fork: u64,
+    ) -> Result<(), HypercoreError> {
+        if let Some(prologue) = &self.manifest.prologue {
+            if length <= prologue.length {
+                if length == prologue.length && hash == prologue.hash {
+                    return Ok(());
+                }
+                return Err(HypercoreError::InvalidSignature {
+                    context: format!("Tree of length {length} does not match the prologue"),
+                });
+            }
+        }
+        if self.compat {
+            let signature =
+                Signature::try_from(signature).map_err(|_| HypercoreError::InvalidSignature {
+                    context: "Could not parse signature".to_string(),
+                })?;
+            return self.verify_signer(&self.manifest.signers[0], &signature, hash, length, fork);
+        }
+
+        let multisig = self.manifest.decode_signature(signature)?;
+        let quorum = self.manifest.quorum;
+        if quorum == 0 || (multisig.proofs.len() as u64) < quorum {
+            return Err(HypercoreError::InvalidSignature {
+                context: format!(
+                    "Multisig has {} signatures, quorum is {quorum}",
+                    multisig.proofs.len()
+                ),
+            });
+        }
+        let mut tried = vec![false; self.manifest.signers.len()];
+        for proof in multisig.proofs.iter().take(quorum as usize) {
+            if proof.patch > 0 && self.manifest.allow_patch {
+                return Err(HypercoreError::InvalidOperation {
+                    context: "Patched multisig signatures are not supported".to_string(),
+                });
+            }
+            let index = usize::try_from(proof.signer)
+                .ok()
+                .filter(|index| *index < tried.len() && !tried[*index])
+                .ok_or_else(|| HypercoreError::InvalidSignature {
+                    context: format!("Invalid or duplicate signer {} in multisig", proof.signer),
+                })?;
+            tried[index] = true;
+            self.verify_signer(
+                &self.manifest.signers[index],
+                &Signature::from_bytes(&proof.signature),
+                hash,
+                length,
+                fork,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn verify_signer(
+        &self,
+        signer: &ManifestSigner,
+        signature: &Signature,
+        hash: &[u8],
+        length: u64,
+        fork: u64,
+    ) -> Result<(), HypercoreError> {
+        let public_key = VerifyingKey::from_bytes(&signer.public_key).map_err(|_| {
+            HypercoreError::InvalidSignature {
+                context: "Could not parse public key of manifest signer".to_string(),
+            }
+        })?;
+        verify(
+            &public_key,
+            &self.signable(signer, hash, length, fork),
+            Some(signature),
+        )
+    }
+
+    fn signable(&self, signer: &ManifestSigner, hash: &[u8], length: u64, fork: u64) -> Box<[u8]> {
+        if self.compat {
+            signable_tree(hash, length, fork)
+        } else if self.manifest.version == 0 {
+            signable_tree_with_context(&signer.namespace, hash, length, fork)
+        } else {
+            signable_tree_with_context(&self.manifest_hash, hash, length, fork)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_signing_key;
+
+    #[test]
+    fn manifest_encoding_and_hash() -> Result<(), HypercoreError> {
+        let public_key = [7; 32];
+        let manifest = Manifest {
+            version: 1,
+            hash: "blake2b".to_string(),
+            allow_patch: false,
+            quorum: 1,
+            signers: vec![ManifestSigner::new(public_key)],
+            prologue: None,
+        };
+        let encoded = manifest.to_encoded_bytes()?;
+        // version, flags, hash, quorum, signers length, signature, namespace, public key
+        assert_eq!(&encoded[..6], &[1, 0, 0, 1, 1, 0]);
+        assert_eq!(&encoded[6..38], &DEFAULT_NAMESPACE);
+        assert_eq!(&encoded[38..], &public_key);
+        assert_eq!(Manifest::decode(&encoded)?.0, manifest);
+
+        let with_prologue = Manifest {
+            prologue: Some(ManifestPrologue {
+                hash: [3; 32],
+                length: 300,
+            }),
+            ..manifest.clone()
+        };
+        let encoded = with_prologue.to_encoded_bytes()?;
+        assert_eq!(encoded[1], 2);
+        assert_eq!(Manifest::decode(&encoded)?.0, with_prologue);
+        assert_ne!(with_prologue.key()?, manifest.key()?);
+
+        let compat = default_signer_manifest(public_key);
+        let encoded = compat.to_encoded_bytes()?;
+        assert_eq!(&encoded[..4], &[0, 0, 1, 0]);
+        assert_eq!(Manifest::decode(&encoded)?.0, compat);
+        assert!(compat.is_compat(&public_key));
+        assert!(!manifest.is_compat(&manifest.key()?));
+        Ok(())
+    }
+
+    #[test]
+    fn verifier_sign_and_verify() -> Result<(), HypercoreError> {
+        let signing_key = generate_signing_key();
+        let public_key = signing_key.verifying_key();
+        let manifest = Manifest::new_single_signer(&public_key);
+        let hash = [1; 32];
+
+        let verifier = Verifier::new(&manifest.key()?, &manifest)?;
+        assert!(!verifier.compat());
+        let signature = verifier.sign(&signing_key, &hash, 10, 0)?;
+        verifier.verify(&signature, &hash, 10, 0)?;
+        assert!(verifier.verify(&signature, &hash, 11, 0).is_err());
+
+        // Compat signatures differ from manifest signatures
+        let compat = Verifier::new(&public_key.to_bytes(), &manifest)?;
+        assert!(compat.compat());
+        assert!(compat.verify(&signature, &hash, 10, 0).is_err());
+        let signature = compat.sign(&signing_key, &hash, 10, 0)?;
+        compat.verify(&signature, &hash, 10, 0)?;
+
+        assert!(verifier
+            .sign(&generate_signing_key(), &hash, 10, 0)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn verifier_multisig_quorum() -> Result<(), HypercoreError> {
+        let signing_keys: Vec<SigningKey> = (0..3).map(|_| generate_signing_key()).collect();
+        let public_keys: Vec<VerifyingKey> =
+            signing_keys.iter().map(|key| key.verifying_key()).collect();
+        let manifest = Manifest::new_multi_signer(2, &public_keys)?;
+        let verifier = Verifier::new(&manifest.key()?, &manifest)?;
+        let hash = [1; 32];
+
+        let partials: Vec<PartialSignature> = signing_keys
+            .iter()
+            .map(|key| verifier.sign_partial(key, &hash, 10, 0))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(partials[2].signer, 2);
+        assert!(verifier.sign(&signing_keys[0], &hash, 10, 0).is_err());
+
+        // signers length, signer, signature, patch, ..., patch nodes length
+        let signature = manifest.assemble_signature(&partials[1..])?;
+        assert_eq!(signature.len(), 1 + 2 * (1 + 64 + 1) + 1);
+        assert_eq!(&signature[..2], &[2, 1]);
+        assert_eq!(&signature[2..66], &partials[1].signature.to_bytes());
+        verifier.verify(&signature, &hash, 10, 0)?;
+        assert!(verifier.verify(&signature, &hash, 11, 0).is_err());
+
+        // Below quorum, duplicate signers and wrong signer indices are rejected
+        let below_quorum = manifest.assemble_signature(&partials[..1])?;
+        assert!(verifier.verify(&below_quorum, &hash, 10, 0).is_err());
+        let duplicate = manifest.assemble_signature(&[partials[0].clone(), partials[0].clone()])?;
+        assert!(verifier.verify(&duplicate, &hash, 10, 0).is_err());
+        let wrong_signer = PartialSignature {
+            signer: 1,
+            ..partials[0].clone()
+        };
+        let wrong = manifest.assemble_signature(&[partials[2].clone(), wrong_signer])?;
+        assert!(verifier.verify(&wrong, &hash, 10, 0).is_err());
+
+        // Version 0 multisigs have flags instead of patch lengths
+        let v0 = Manifest {
+            version: 0,
+            ..manifest.clone()
+        };
+        let signature = v0.assemble_signature(&partials[..2])?;
+        assert_eq!(&signature[..3], &[2, 0, 0]);
+        assert_eq!(
+            v0.decode_signature(&signature)?,
+            manifest.decode_signature(&manifest.assemble_signature(&partials[..2])?)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verifier_static_prologue() -> Result<(), HypercoreError> {
+        let hash = [1; 32];
+        let manifest = Manifest::new_static(hash, 10);
+        assert!(manifest.is_static());
+        let encoded = manifest.to_encoded_bytes()?;
+        // version, flags, hash, quorum, signers length, prologue
+        assert_eq!(&encoded[..5], &[1, 2, 0, 0, 0]);
+        assert_eq!(Manifest::decode(&encoded)?.0, manifest);
+
+        let verifier = Verifier::new(&manifest.key()?, &manifest)?;
+        verifier.verify(&[], &hash, 10, 0)?;
+        assert!(verifier.verify(&[], &[2; 32], 10, 0).is_err());
+        assert!(verifier.verify(&[], &hash, 9, 0).is_err());
+        assert!(verifier.verify(&[], &hash, 11, 0).is_err());
+        assert!(verifier
+            .sign(&generate_signing_key(), &hash, 11, 0)
+            .is_err());
+        Ok(())
     }
 }
@@ -1,9 +1,17 @@
 //! Cryptographic functions.
 
+mod block_encryption;
+mod core_id;
 mod hash;
 mod key_pair;
 mod manifest;
 
-pub(crate) use hash::{signable_tree, Hash};
+pub(crate) use block_encryption::{BlockEncryption, BLOCK_ENCRYPTION_PADDING};
+pub use core_id::{discovery_key, CoreId};
+pub(crate) use hash::{signable_tree, signable_tree_with_context, Hash};
 pub use key_pair::{generate as generate_signing_key, sign, verify, PartialKeypair};
-pub(crate) use manifest::{default_signer_manifest, Manifest, ManifestSigner};
+pub(crate) use manifest::{
+    default_signer_manifest, ManifestPrologue, ManifestSigner, MultiSignature, MultiSignatureV0,
+    MultisigInput, Verifier,
+};
+pub use manifest::{Manifest, PartialSignature};
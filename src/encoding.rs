@@ -1,7 +1,10 @@
 //! Hypercore-specific compact encodings
 use crate::{
-    crypto::{Manifest, ManifestSigner},
+    crypto::{
+        Manifest, ManifestPrologue, ManifestSigner, MultiSignature, MultiSignatureV0, MultisigInput,
+    },
     DataBlock, DataHash, DataSeek, DataUpgrade, Node, RequestBlock, RequestSeek, RequestUpgrade,
+    RleBitfield,
 };
 use compact_encoding::{
     as_array, encode_bytes_fixed, encoded_size_usize, map_decode, map_encode, sum_encoded_size,
@@ -198,6 +201,31 @@ impl CompactEncoding for DataUpgrade {
     }
 }
 
+impl CompactEncoding for RleBitfield {
+    fn encoded_size(&self) -> Result<usize, EncodingError> {
+        Ok(sum_encoded_size!(self.start, self.length, self.bitfield))
+    }
+
+    fn encode<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a mut [u8], EncodingError> {
+        Ok(map_encode!(buffer, self.start, self.length, self.bitfield))
+    }
+
+    fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), EncodingError>
+    where
+        Self: Sized,
+    {
+        let ((start, length, bitfield), rest) = map_decode!(buffer, [u64, u64, Vec<u8>]);
+        Ok((
+            RleBitfield {
+                start,
+                length,
+                bitfield,
+            },
+            rest,
+        ))
+    }
+}
+
 impl CompactEncoding for ManifestSigner {
     fn encoded_size(&self) -> Result<usize, EncodingError> {
         Ok(
@@ -245,53 +273,326 @@ impl CompactEncoding for ManifestSigner {
     }
 }
 
-impl CompactEncoding for Manifest {
+impl VecEncodable for ManifestSigner {
+    fn vec_encoded_size(vec: &[Self]) -> Result<usize, EncodingError>
+    where
+        Self: Sized,
+    {
+        let mut out = encoded_size_usize(vec.len());
+        for x in vec {
+            out += x.encoded_size()?;
+        }
+        Ok(out)
+    }
+}
+
+impl CompactEncoding for ManifestPrologue {
     fn encoded_size(&self) -> Result<usize, EncodingError> {
-        Ok(1 // Version
-        + 1 // hash in one byte
-        + 1 // type in one byte
-        + self.signer.encoded_size()?)
+        Ok(32 + self.length.encoded_size()?)
     }
 
     fn encode<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a mut [u8], EncodingError> {
-        let rest = write_slice(&[0], buffer)?;
-        let rest = if &self.hash == "blake2b" {
-            write_slice(&[0], rest)?
+        let rest = encode_bytes_fixed(&self.hash, buffer)?;
+        self.length.encode(rest)
+    }
+
+    fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), EncodingError>
+    where
+        Self: Sized,
+    {
+        let (hash, rest) = take_array::<32>(buffer)?;
+        let (length, rest) = u64::decode(rest)?;
+        Ok((ManifestPrologue { hash, length }, rest))
+    }
+}
+
+impl CompactEncoding for MultisigInput {
+    fn encoded_size(&self) -> Result<usize, EncodingError> {
+        Ok(sum_encoded_size!(self.signer, self.patch) + 64)
+    }
+
+    fn encode<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a mut [u8], EncodingError> {
+        let rest = self.signer.encode(buffer)?;
+        let rest = encode_bytes_fixed(&self.signature, rest)?;
+        self.patch.encode(rest)
+    }
+
+    fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), EncodingError>
+    where
+        Self: Sized,
+    {
+        let (signer, rest) = u64::decode(buffer)?;
+        let (signature, rest) = take_array::<64>(rest)?;
+        let (patch, rest) = u64::decode(rest)?;
+        Ok((
+            MultisigInput {
+                signer,
+                signature,
+                patch,
+            },
+            rest,
+        ))
+    }
+}
+
+impl VecEncodable for MultisigInput {
+    fn vec_encoded_size(vec: &[Self]) -> Result<usize, EncodingError>
+    where
+        Self: Sized,
+    {
+        let mut out = encoded_size_usize(vec.len());
+        for x in vec {
+            out += x.encoded_size()?;
+        }
+        Ok(out)
+    }
+}
+
+impl CompactEncoding for MultiSignature {
+    fn encoded_size(&self) -> Result<usize, EncodingError> {
+        Ok(sum_encoded_size!(self.proofs, self.patch))
+    }
+
+    fn encode<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a mut [u8], EncodingError> {
+        Ok(map_encode!(buffer, self.proofs, self.patch))
+    }
+
+    fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), EncodingError>
+    where
+        Self: Sized,
+    {
+        let ((proofs, patch), rest) = map_decode!(buffer, [Vec<MultisigInput>, Vec<Node>]);
+        Ok((MultiSignature { proofs, patch }, rest))
+    }
+}
+
+// Version 0 inputs have flags instead of a patch length, where flag 1 means the input is
+// followed by a patch. Patches are not supported.
+impl CompactEncoding for MultiSignatureV0 {
+    fn encoded_size(&self) -> Result<usize, EncodingError> {
+        let mut out = encoded_size_usize(self.0.proofs.len());
+        for proof in &self.0.proofs {
+            out += 1 + proof.signer.encoded_size()? + 64;
+        }
+        Ok(out + self.0.patch.encoded_size()?)
+    }
+
+    fn encode<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a mut [u8], EncodingError> {
+        let mut rest = self.0.proofs.len().encode(buffer)?;
+        for proof in &self.0.proofs {
+            if proof.patch > 0 {
+                return Err(EncodingError::new(
+                    EncodingErrorKind::InvalidData,
+                    "Patched multisig inputs are not supported",
+                ));
+            }
+            rest = write_slice(&[0], rest)?;
+            rest = proof.signer.encode(rest)?;
+            rest = encode_bytes_fixed(&proof.signature, rest)?;
+        }
+        self.0.patch.encode(rest)
+    }
+
+    fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), EncodingError>
+    where
+        Self: Sized,
+    {
+        let (len, mut rest) = usize::decode(buffer)?;
+        let mut proofs = Vec::with_capacity(len.min(rest.len()));
+        for _ in 0..len {
+            let ([flags], inner) = take_array::<1>(rest)?;
+            if flags & 1 != 0 {
+                return Err(EncodingError::new(
+                    EncodingErrorKind::InvalidData,
+                    "Patched multisig inputs are not supported",
+                ));
+            }
+            let (signer, inner) = u64::decode(inner)?;
+            let (signature, inner) = take_array::<64>(inner)?;
+            proofs.push(MultisigInput {
+                signer,
+                signature,
+                patch: 0,
+            });
+            rest = inner;
+        }
+        let (patch, rest) = Vec::<Node>::decode(rest)?;
+        Ok((MultiSignatureV0(MultiSignature { proofs, patch }), rest))
+    }
+}
+
+fn encode_manifest_hash<'a>(
+    hash: &str,
+    buffer: &'a mut [u8],
+) -> Result<&'a mut [u8], EncodingError> {
+    if hash == "blake2b" {
+        write_slice(&[0], buffer)
+    } else {
+        Err(EncodingError::new(
+            EncodingErrorKind::InvalidData,
+            &format!("Unknown hash: {hash}"),
+        ))
+    }
+}
+
+fn decode_manifest_hash(buffer: &[u8]) -> Result<(String, &[u8]), EncodingError> {
+    let ([hash_id], rest) = take_array::<1>(buffer)?;
+    if hash_id != 0 {
+        return Err(EncodingError::new(
+            EncodingErrorKind::InvalidData,
+            &format!("Unknown hash id: {hash_id}"),
+        ));
+    }
+    Ok(("blake2b".to_string(), rest))
+}
+
+/// Version 0 manifests have a type instead of flags, which is 0 for a prologue only, 1 for
+/// a single signer and 2 for multiple signers.
+fn manifest_v0_type(manifest: &Manifest) -> u64 {
+    if manifest.prologue.is_some() && manifest.signers.is_empty() {
+        0
+    } else if manifest.quorum == 1 && manifest.signers.len() == 1 && !manifest.allow_patch {
+        1
+    } else {
+        2
+    }
+}
+
+impl CompactEncoding for Manifest {
+    fn encoded_size(&self) -> Result<usize, EncodingError> {
+        let mut out = self.version.encoded_size()? + 1; // hash in one byte
+        if self.version == 0 {
+            out += 1; // type in one byte
+            match manifest_v0_type(self) {
+                0 => out += 32,
+                1 => out += self.signers[0].encoded_size()?,
+                _ => out += 1 + sum_encoded_size!(self.quorum, self.signers),
+            }
         } else {
-            return Err(EncodingError::new(
-                EncodingErrorKind::InvalidData,
-                &format!("Unknown hash: {}", &self.hash),
-            ));
-        };
-        let rest = write_slice(&[1], rest)?;
-        self.signer.encode(rest)
+            out += 1 // flags in one byte
+                + sum_encoded_size!(self.quorum, self.signers);
+            if let Some(prologue) = &self.prologue {
+                out += prologue.encoded_size()?;
+            }
+        }
+        Ok(out)
+    }
+
+    fn encode<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a mut [u8], EncodingError> {
+        let rest = self.version.encode(buffer)?;
+        let allow_patch: u8 = if self.allow_patch { 1 } else { 0 };
+        if self.version == 0 {
+            let rest = encode_manifest_hash(&self.hash, rest)?;
+            let manifest_type = manifest_v0_type(self);
+            let rest = write_slice(&[manifest_type as u8], rest)?;
+            return match manifest_type {
+                0 => encode_bytes_fixed(
+                    &self.prologue.as_ref().expect("Prologue checked above").hash,
+                    rest,
+                ),
+                1 => self.signers[0].encode(rest),
+                _ => {
+                    let rest = write_slice(&[allow_patch], rest)?;
+                    Ok(map_encode!(rest, self.quorum, self.signers))
+                }
+            };
+        }
+        let prologue: u8 = if self.prologue.is_some() { 2 } else { 0 };
+        let rest = write_slice(&[allow_patch | prologue], rest)?;
+        let rest = encode_manifest_hash(&self.hash, rest)?;
+        let rest = map_encode!(rest, self.quorum, self.signers);
+        match &self.prologue {
+            Some(prologue) => prologue.encode(rest),
+            None => Ok(rest),
+        }
     }
 
     fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), EncodingError>
     where
         Self: Sized,
     {
-        let ([version], rest) = take_array::<1>(buffer)?;
-        if version != 0 {
-            panic!("Unknown manifest version {}", version);
+        let (version, rest) = u64::decode(buffer)?;
+        if version == 0 {
+            let (hash, rest) = decode_manifest_hash(rest)?;
+            let ([manifest_type], rest) = take_array::<1>(rest)?;
+            return match manifest_type {
+                0 => {
+                    let (prologue_hash, rest) = take_array::<32>(rest)?;
+                    Ok((
+                        Manifest {
+                            version,
+                            hash,
+                            allow_patch: false,
+                            quorum: 0,
+                            signers: vec![],
+                            prologue: Some(ManifestPrologue {
+                                hash: prologue_hash,
+                                length: 0,
+                            }),
+                        },
+                        rest,
+                    ))
+                }
+                1 => {
+                    let (signer, rest) = ManifestSigner::decode(rest)?;
+                    Ok((
+                        Manifest {
+                            version,
+                            hash,
+                            allow_patch: false,
+                            quorum: 1,
+                            signers: vec![signer],
+                            prologue: None,
+                        },
+                        rest,
+                    ))
+                }
+                2 => {
+                    let ([flags], rest) = take_array::<1>(rest)?;
+                    let ((quorum, signers), rest) = map_decode!(rest, [u64, Vec<ManifestSigner>]);
+                    Ok((
+                        Manifest {
+                            version,
+                            hash,
+                            allow_patch: flags & 1 != 0,
+                            quorum,
+                            signers,
+                            prologue: None,
+                        },
+                        rest,
+                    ))
+                }
+                _ => Err(EncodingError::new(
+                    EncodingErrorKind::InvalidData,
+                    &format!("Unknown manifest type: {manifest_type}"),
+                )),
+            };
         }
-        let ([hash_id], rest) = take_array::<1>(rest)?;
-        let hash: String = if hash_id != 0 {
+        if version != 1 {
             return Err(EncodingError::new(
                 EncodingErrorKind::InvalidData,
-                &format!("Unknown hash id: {hash_id}"),
+                &format!("Unknown manifest version: {version}"),
             ));
+        }
+        let ([flags], rest) = take_array::<1>(rest)?;
+        let (hash, rest) = decode_manifest_hash(rest)?;
+        let ((quorum, signers), rest) = map_decode!(rest, [u64, Vec<ManifestSigner>]);
+        let (prologue, rest) = if flags & 2 != 0 {
+            let (prologue, rest) = ManifestPrologue::decode(rest)?;
+            (Some(prologue), rest)
         } else {
-            "blake2b".to_string()
+            (None, rest)
         };
-        let ([manifest_type], rest) = take_array::<1>(rest)?;
-        if manifest_type != 1 {
-            return Err(EncodingError::new(
-                EncodingErrorKind::InvalidData,
-                &format!("Unknown manifest type: {manifest_type}"),
-            ));
-        }
-        let (signer, rest) = ManifestSigner::decode(rest)?;
-        Ok((Manifest { hash, signer }, rest))
+        Ok((
+            Manifest {
+                version,
+                hash,
+                allow_patch: flags & 1 != 0,
+                quorum,
+                signers,
+                prologue,
+            },
+            rest,
+        ))
     }
 }
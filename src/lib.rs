@@ -35,6 +35,10 @@
 //!
 //! Use a moka cache for merkle tree nodes to speed-up reading.
 //!
+//! ### `json`
+//!
+//! Enable the `Json` value encoding in [`value_encoding`], using serde_json.
+//!
 //! ## Example
 //! ```rust
 //! # #[cfg(feature = "tokio")]
@@ -72,11 +76,15 @@
 //! [HypercoreBuilder]: crate::builder::HypercoreBuilder
 //! [examples]: https://github.com/datrs/hypercore/tree/master/examples
 
+#[cfg(feature = "shared-core")]
+pub mod corestore;
 pub mod encoding;
 pub mod prelude;
 #[cfg(feature = "replication")]
 pub mod replication;
+pub mod value_encoding;
 
+mod batch;
 mod bitfield;
 mod builder;
 mod common;
@@ -84,19 +92,31 @@ mod core;
 mod crypto;
 mod data;
 mod oplog;
+mod snapshot;
 mod storage;
 mod tree;
 
+pub use crate::batch::Batch;
+pub use crate::bitfield::RemoteBitfield;
 #[cfg(feature = "cache")]
 pub use crate::builder::CacheOptionsBuilder;
 pub use crate::builder::HypercoreBuilder;
 pub use crate::common::{
     DataBlock, DataHash, DataSeek, DataUpgrade, HypercoreError, Node, Proof, RequestBlock,
-    RequestSeek, RequestUpgrade, Store,
+    RequestSeek, RequestUpgrade, RleBitfield, Store,
+};
+pub use crate::core::{
+    AppendOutcome, BlockRange, Hypercore, Info, IntegrityReport, ReadStreamOptions,
+};
+#[cfg(feature = "shared-core")]
+pub use crate::corestore::Corestore;
+pub use crate::crypto::{
+    discovery_key, generate_signing_key, sign, verify, CoreId, Manifest, PartialKeypair,
+    PartialSignature,
 };
-pub use crate::core::{AppendOutcome, Hypercore, Info};
-pub use crate::crypto::{generate_signing_key, sign, verify, PartialKeypair};
-pub use crate::storage::{Storage, StorageTraits};
+pub use crate::snapshot::Snapshot;
+pub use crate::storage::{Durability, Storage, StorageTraits};
+pub use crate::value_encoding::{TypedHypercore, ValueEncoding};
 pub use ed25519_dalek::{
     SecretKey, Signature, SigningKey, VerifyingKey, KEYPAIR_LENGTH, PUBLIC_KEY_LENGTH,
     SECRET_KEY_LENGTH,
@@ -3,6 +3,7 @@ use compact_encoding::{
     CompactEncoding, EncodingError,
 };
 
+use super::KeyValue;
 use crate::{common::BitfieldUpdate, Node};
 
 /// Entry tree upgrade
@@ -83,8 +84,8 @@ impl CompactEncoding for BitfieldUpdate {
 /// Oplog Entry
 #[derive(Debug)]
 pub(crate) struct Entry {
-    // TODO: This is a keyValueArray in JS
-    pub(crate) user_data: Vec<String>,
+    /// Single user data change, an empty value removes the key
+    pub(crate) user_data: Option<KeyValue>,
     pub(crate) tree_nodes: Vec<Node>,
     pub(crate) tree_upgrade: Option<EntryTreeUpgrade>,
     pub(crate) bitfield: Option<BitfieldUpdate>,
@@ -93,8 +94,8 @@ pub(crate) struct Entry {
 impl CompactEncoding for Entry {
     fn encoded_size(&self) -> Result<usize, EncodingError> {
         let mut out = 1; // flags
-        if !self.user_data.is_empty() {
-            out += self.user_data.encoded_size()?;
+        if let Some(user_data) = &self.user_data {
+            out += user_data.encoded_size()?;
         }
         if !self.tree_nodes.is_empty() {
             out += self.tree_nodes.encoded_size()?;
@@ -111,9 +112,9 @@ impl CompactEncoding for Entry {
     fn encode<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a mut [u8], EncodingError> {
         let (flag_buf, mut rest) = take_array_mut::<1>(buffer)?;
         let mut flags = 0u8;
-        if !self.user_data.is_empty() {
+        if let Some(user_data) = &self.user_data {
             flags |= 1;
-            rest = self.user_data.encode(rest)?;
+            rest = user_data.encode(rest)?;
         }
         if !self.tree_nodes.is_empty() {
             flags |= 2;
@@ -137,7 +138,8 @@ impl CompactEncoding for Entry {
     {
         let ([flags], rest) = take_array::<1>(buffer)?;
         let (user_data, rest) = if flags & 1 != 0 {
-            <Vec<String>>::decode(rest)?
+            let (x, rest) = KeyValue::decode(rest)?;
+            (Some(x), rest)
         } else {
             (Default::default(), rest)
         };
@@ -148,14 +150,14 @@ impl CompactEncoding for Entry {
             (Default::default(), rest)
         };
 
-        let (tree_upgrade, rest) = if flags & 2 != 0 {
+        let (tree_upgrade, rest) = if flags & 4 != 0 {
             let (x, rest) = EntryTreeUpgrade::decode(rest)?;
             (Some(x), rest)
         } else {
             (Default::default(), rest)
         };
 
-        let (bitfield, rest) = if flags & 2 != 0 {
+        let (bitfield, rest) = if flags & 8 != 0 {
             let (x, rest) = BitfieldUpdate::decode(rest)?;
             (Some(x), rest)
         } else {
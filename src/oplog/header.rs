@@ -1,36 +1,130 @@
 use compact_encoding::{
-    decode_usize, map_decode, take_array, write_array, CompactEncoding, EncodingError,
+    decode_usize, encoded_size_usize, map_decode, take_array, write_array, CompactEncoding,
+    EncodingError, VecEncodable,
 };
 use compact_encoding::{map_encode, sum_encoded_size};
 use ed25519_dalek::{SigningKey, PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
 
 use crate::crypto::default_signer_manifest;
 use crate::crypto::Manifest;
+use crate::HypercoreError;
 use crate::PartialKeypair;
 use crate::VerifyingKey;
 
+/// User data entry, `keyValue` in Javascript. An empty value is encoded the same as a
+/// null value in Javascript.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct KeyValue {
+    pub(crate) key: String,
+    pub(crate) value: Vec<u8>,
+}
+
+impl CompactEncoding for KeyValue {
+    fn encoded_size(&self) -> Result<usize, EncodingError> {
+        Ok(sum_encoded_size!(self.key, self.value))
+    }
+
+    fn encode<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a mut [u8], EncodingError> {
+        Ok(map_encode!(buffer, self.key, self.value))
+    }
+
+    fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), EncodingError>
+    where
+        Self: Sized,
+    {
+        let ((key, value), rest) = map_decode!(buffer, [String, Vec<u8>]);
+        Ok((KeyValue { key, value }, rest))
+    }
+}
+
+impl VecEncodable for KeyValue {
+    fn vec_encoded_size(vec: &[Self]) -> Result<usize, EncodingError>
+    where
+        Self: Sized,
+    {
+        let mut out = encoded_size_usize(vec.len());
+        for x in vec {
+            out += x.encoded_size()?;
+        }
+        Ok(out)
+    }
+}
+
+/// Hint of a reorg to a newer fork, `reorgHint` in Javascript
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct ReorgHint {
+    pub(crate) from: u64,
+    pub(crate) to: u64,
+    pub(crate) ancestors: u64,
+}
+
+impl CompactEncoding for ReorgHint {
+    fn encoded_size(&self) -> Result<usize, EncodingError> {
+        Ok(sum_encoded_size!(self.from, self.to, self.ancestors))
+    }
+
+    fn encode<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a mut [u8], EncodingError> {
+        Ok(map_encode!(buffer, self.from, self.to, self.ancestors))
+    }
+
+    fn decode(buffer: &[u8]) -> Result<(Self, &[u8]), EncodingError>
+    where
+        Self: Sized,
+    {
+        let ((from, to, ancestors), rest) = map_decode!(buffer, [u64, u64, u64]);
+        Ok((
+            ReorgHint {
+                from,
+                to,
+                ancestors,
+            },
+            rest,
+        ))
+    }
+}
+
+impl VecEncodable for ReorgHint {
+    fn vec_encoded_size(vec: &[Self]) -> Result<usize, EncodingError>
+    where
+        Self: Sized,
+    {
+        let mut out = encoded_size_usize(vec.len());
+        for x in vec {
+            out += x.encoded_size()?;
+        }
+        Ok(out)
+    }
+}
+
 /// Oplog header.
 #[derive(Debug, Clone)]
 pub(crate) struct Header {
     // TODO: v11 has external
     // pub(crate) external: Option<bool>,
-    // NB: This is the manifest hash in v11, right now
-    // just the public key,
+    // NB: This is the public key for compat hypercores, and the manifest hash otherwise
     pub(crate) key: [u8; 32],
     pub(crate) manifest: Manifest,
     pub(crate) key_pair: PartialKeypair,
-    // TODO: This is a keyValueArray in JS
-    pub(crate) user_data: Vec<String>,
+    pub(crate) user_data: Vec<KeyValue>,
     pub(crate) tree: HeaderTree,
     pub(crate) hints: HeaderHints,
 }
 
 impl Header {
-    /// Creates a new Header from given key pair
-    pub(crate) fn new(key_pair: PartialKeypair) -> Self {
-        let key = key_pair.public.to_bytes();
-        let manifest = default_signer_manifest(key);
-        Self {
+    /// Creates a new Header from given key pair. With a manifest the key is the manifest
+    /// hash, otherwise the key is the public key of the key pair.
+    pub(crate) fn new(
+        key_pair: PartialKeypair,
+        manifest: Option<Manifest>,
+    ) -> Result<Self, HypercoreError> {
+        let (key, manifest) = match manifest {
+            Some(manifest) => (manifest.key()?, manifest),
+            None => {
+                let key = key_pair.public.to_bytes();
+                (key, default_signer_manifest(key))
+            }
+        };
+        Ok(Self {
             key,
             manifest,
             key_pair,
@@ -40,7 +134,7 @@ impl Header {
                 reorgs: vec![],
                 contiguous_length: 0,
             },
-        }
+        })
         // Javascript side, initial header
         // header = {
         //    external: null,
@@ -60,6 +154,34 @@ impl Header {
         //    }
         //  }
     }
+
+    /// Gets the user data value stored for given key
+    pub(crate) fn get_user_data(&self, key: &str) -> Option<&[u8]> {
+        self.user_data
+            .iter()
+            .find(|user_data| user_data.key == key)
+            .map(|user_data| &user_data.value[..])
+    }
+
+    /// Sets the user data value of given key, an empty value removes the key. Matches
+    /// `updateUserData` of Javascript.
+    pub(crate) fn update_user_data(&mut self, key: &str, value: &[u8]) {
+        let existing = self
+            .user_data
+            .iter()
+            .position(|user_data| user_data.key == key);
+        match (existing, value.is_empty()) {
+            (Some(i), true) => {
+                self.user_data.remove(i);
+            }
+            (Some(i), false) => self.user_data[i].value = value.to_vec(),
+            (None, true) => {}
+            (None, false) => self.user_data.push(KeyValue {
+                key: key.to_string(),
+                value: value.to_vec(),
+            }),
+        }
+    }
 }
 
 /// Oplog header tree
@@ -190,7 +312,7 @@ impl CompactEncoding for PartialKeypair {
 /// Oplog header hints
 #[derive(Debug, Clone)]
 pub(crate) struct HeaderHints {
-    pub(crate) reorgs: Vec<String>,
+    pub(crate) reorgs: Vec<ReorgHint>,
     pub(crate) contiguous_length: u64,
 }
 
@@ -207,7 +329,7 @@ impl CompactEncoding for HeaderHints {
     where
         Self: Sized,
     {
-        let ((reorgs, contiguous_length), rest) = map_decode!(buffer, [Vec<String>, u64]);
+        let ((reorgs, contiguous_length), rest) = map_decode!(buffer, [Vec<ReorgHint>, u64]);
         Ok((
             Self {
                 reorgs,
@@ -252,7 +374,7 @@ impl CompactEncoding for Header {
         let (key, rest) = take_array::<32>(rest)?;
         let ((manifest, key_pair, user_data, tree, hints), rest) = map_decode!(
             rest, [
-                Manifest, PartialKeypair, Vec<String>, HeaderTree, HeaderHints
+                Manifest, PartialKeypair, Vec<KeyValue>, HeaderTree, HeaderHints
             ]
         );
         Ok((
@@ -334,7 +456,7 @@ mod tests {
             public: signing_key.verifying_key(),
             secret: Some(signing_key),
         };
-        let header = Header::new(signing_key);
+        let header = Header::new(signing_key, None).unwrap();
         let encoded = to_encoded_bytes!(&header);
         let ((dec_header,), rest) = map_decode!(&encoded, [Header]);
         assert!(rest.is_empty());
@@ -342,15 +464,65 @@ mod tests {
         assert_eq!(header.tree.fork, dec_header.tree.fork);
         assert_eq!(header.tree.length, dec_header.tree.length);
         assert_eq!(header.tree.length, dec_header.tree.length);
-        assert_eq!(header.manifest.hash, dec_header.manifest.hash);
-        assert_eq!(
-            header.manifest.signer.public_key,
-            dec_header.manifest.signer.public_key
-        );
-        assert_eq!(
-            header.manifest.signer.signature,
-            dec_header.manifest.signer.signature
-        );
+        assert_eq!(header.manifest, dec_header.manifest);
+        Ok(())
+    }
+
+    #[test]
+    fn encode_header_user_data() -> Result<(), EncodingError> {
+        let signing_key = generate_signing_key();
+        let mut header = Header::new(
+            PartialKeypair {
+                public: signing_key.verifying_key(),
+                secret: None,
+            },
+            None,
+        )
+        .unwrap();
+        header.update_user_data("a", b"1");
+        header.update_user_data("b", b"2");
+        header.update_user_data("a", b"3");
+        header.update_user_data("b", b"");
+        header.update_user_data("c", b"");
+        assert_eq!(header.get_user_data("a"), Some(&b"3"[..]));
+        assert_eq!(header.get_user_data("b"), None);
+
+        let encoded = to_encoded_bytes!(&header);
+        let ((dec_header,), rest) = map_decode!(&encoded, [Header]);
+        assert!(rest.is_empty());
+        assert_eq!(dec_header.user_data, header.user_data);
+
+        // Javascript keyValue: string key followed by buffer value
+        let encoded = to_encoded_bytes!(&header.user_data[0]);
+        assert_eq!(&encoded[..], &[1, b'a', 1, b'3']);
+        Ok(())
+    }
+
+    #[test]
+    fn encode_header_reorg_hints() -> Result<(), EncodingError> {
+        let signing_key = generate_signing_key();
+        let mut header = Header::new(
+            PartialKeypair {
+                public: signing_key.verifying_key(),
+                secret: None,
+            },
+            None,
+        )
+        .unwrap();
+        header.hints.reorgs.push(ReorgHint {
+            from: 0,
+            to: 1,
+            ancestors: 300,
+        });
+
+        let encoded = to_encoded_bytes!(&header);
+        let ((dec_header,), rest) = map_decode!(&encoded, [Header]);
+        assert!(rest.is_empty());
+        assert_eq!(dec_header.hints.reorgs, header.hints.reorgs);
+
+        // Javascript reorgHint: from, to and ancestors as uints
+        let encoded = to_encoded_bytes!(&header.hints);
+        assert_eq!(&encoded[..], &[1, 0, 1, 0xfd, 0x2c, 0x01, 0]);
         Ok(())
     }
 }
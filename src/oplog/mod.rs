@@ -7,13 +7,13 @@ use std::convert::{TryFrom, TryInto};
 
 use crate::common::{BitfieldUpdate, Store, StoreInfo, StoreInfoInstruction};
 use crate::tree::MerkleTreeChangeset;
-use crate::{HypercoreError, Node, PartialKeypair};
+use crate::{HypercoreError, Manifest, Node, PartialKeypair};
 
 pub(crate) mod entry;
 mod header;
 
 pub(crate) use entry::{Entry, EntryTreeUpgrade};
-pub(crate) use header::{Header, HeaderTree};
+pub(crate) use header::{Header, HeaderTree, KeyValue, ReorgHint};
 
 pub(crate) const MAX_OPLOG_ENTRIES_BYTE_SIZE: u64 = 65536;
 const HEADER_SIZE: usize = 4096;
@@ -51,6 +51,10 @@ pub(crate) struct OplogOpenOutcome {
     pub(crate) header: Header,
     pub(crate) infos_to_flush: Box<[StoreInfo]>,
     pub(crate) entries: Option<Box<[Entry]>>,
+    /// Set when both header slots were invalid and the oplog is being recovered: headers
+    /// decoded from the damaged slots without checking their checksums. The header of the
+    /// outcome is then a fresh one, whose tree needs to be rebuilt.
+    pub(crate) recovery: Option<Vec<Header>>,
 }
 
 impl OplogOpenOutcome {
@@ -60,6 +64,7 @@ impl OplogOpenOutcome {
             header,
             infos_to_flush,
             entries: None,
+            recovery: None,
         }
     }
     pub(crate) fn from_create_header_outcome(
@@ -71,6 +76,7 @@ impl OplogOpenOutcome {
             header: create_header_outcome.header,
             infos_to_flush: create_header_outcome.infos_to_flush,
             entries: None,
+            recovery: None,
         }
     }
 }
@@ -94,10 +100,15 @@ struct ValidateLeaderOutcome<'a> {
 const INITIAL_HEADER_BITS: [bool; 2] = [true, false];
 
 impl Oplog {
-    /// Opens an existing Oplog from existing byte buffer or creates a new one.
+    /// Opens an existing Oplog from existing byte buffer or creates a new one. A new one
+    /// uses the given manifest, or the default signer manifest of the key pair if not given.
+    /// With `recover`, an existing oplog whose header slots are both invalid is opened with
+    /// a fresh header and its valid entries, see [`OplogOpenOutcome::recovery`].
     pub(crate) fn open(
         key_pair: &Option<PartialKeypair>,
+        manifest: &Option<Manifest>,
         info: Option<StoreInfo>,
+        recover: bool,
     ) -> Result<Either<StoreInfoInstruction, OplogOpenOutcome>, HypercoreError> {
         match info {
             None => Ok(Either::Left(StoreInfoInstruction::new_all_content(
@@ -109,14 +120,14 @@ impl Oplog {
                 let h1_outcome = if let Some(h1) =
                     existing.get(OplogSlot::FirstHeader as usize..OplogSlot::SecondHeader as usize)
                 {
-                    Self::validate_leader(h1)?
+                    Self::validate_header_leader(h1, recover)?
                 } else {
                     None
                 };
                 let h2_outcome = if let Some(h2) =
                     existing.get(OplogSlot::SecondHeader as usize..OplogSlot::Entries as usize)
                 {
-                    Self::validate_leader(h2)?
+                    Self::validate_header_leader(h2, recover)?
                 } else {
                     None
                 };
@@ -154,9 +165,17 @@ impl Oplog {
                         entries_byte_length: 0,
                     };
                     OplogOpenOutcome::new(oplog, Header::decode(h2_outcome.state)?.0, Box::new([]))
+                } else if recover && !existing.is_empty() {
+                    let key_pair = key_pair
+                        .clone()
+                        .ok_or_else(|| HypercoreError::BadArgument {
+                            context: "A key pair, key or manifest is needed to recover the oplog"
+                                .to_string(),
+                        })?;
+                    Self::recovered(key_pair, manifest.clone(), &existing)?
                 } else if let Some(key_pair) = key_pair {
                     // There is nothing in the oplog, start from fresh given key pair.
-                    Self::fresh(key_pair.clone())?
+                    Self::fresh(key_pair.clone(), manifest.clone())?
                 } else {
                     // The storage is empty and no key pair given, erroring
                     return Err(HypercoreError::EmptyStorage {
@@ -170,7 +189,14 @@ impl Oplog {
                         get_slices_checked(&existing, OplogSlot::Entries as usize)?.1;
                     let mut entries: Vec<Entry> = Vec::new();
                     let mut partials: Vec<bool> = Vec::new();
-                    while let Some(entry_outcome) = Self::validate_leader(entries_buff)? {
+                    loop {
+                        let entry_outcome = match Self::validate_leader(entries_buff) {
+                            Ok(Some(entry_outcome)) => entry_outcome,
+                            Ok(None) => break,
+                            // When recovering, keep the entries before the first invalid one
+                            Err(_) if outcome.recovery.is_some() => break,
+                            Err(err) => return Err(err),
+                        };
                         let res = Entry::decode(entry_outcome.state)?;
                         entries.push(res.0);
                         entries_buff = res.1;
@@ -217,16 +243,15 @@ impl Oplog {
                 .hash
                 .as_ref()
                 .expect("Upgraded changeset must have a hash before appended");
-            let signature = changeset
-                .signature
-                .expect("Upgraded changeset must be signed before appended");
-            let signature: Box<[u8]> = signature.to_bytes().into();
+            // NB: A changeset truncated to zero length is not signed, like in Javascript
+            let signature: Box<[u8]> = changeset.signature.clone().unwrap_or_default();
             header.tree.root_hash = hash.clone();
             header.tree.signature = signature.clone();
             header.tree.length = changeset.length;
+            header.tree.fork = changeset.fork;
 
             Entry {
-                user_data: vec![],
+                user_data: None,
                 tree_nodes,
                 tree_upgrade: Some(EntryTreeUpgrade {
                     fork: changeset.fork,
@@ -238,7 +263,7 @@ impl Oplog {
             }
         } else {
             Entry {
-                user_data: vec![],
+                user_data: None,
                 tree_nodes,
                 tree_upgrade: None,
                 bitfield: bitfield_update,
@@ -254,7 +279,7 @@ impl Oplog {
         end: u64,
     ) -> Result<Box<[StoreInfo]>, HypercoreError> {
         let entry: Entry = Entry {
-            user_data: vec![],
+            user_data: None,
             tree_nodes: vec![],
             tree_upgrade: None,
             bitfield: Some(BitfieldUpdate {
@@ -266,6 +291,25 @@ impl Oplog {
         self.append_entries(&[entry], false)
     }
 
+    /// Appends a user data change, returns infos to write to storage. An empty value
+    /// removes the key.
+    pub(crate) fn append_user_data(
+        &mut self,
+        key: &str,
+        value: &[u8],
+    ) -> Result<Box<[StoreInfo]>, HypercoreError> {
+        let entry: Entry = Entry {
+            user_data: Some(KeyValue {
+                key: key.to_string(),
+                value: value.to_vec(),
+            }),
+            tree_nodes: vec![],
+            tree_upgrade: None,
+            bitfield: None,
+        };
+        self.append_entries(&[entry], false)
+    }
+
     /// Flushes pending changes, returns infos to write to storage.
     pub(crate) fn flush(
         &mut self,
@@ -321,10 +365,13 @@ impl Oplog {
         Ok(vec![StoreInfo::new_content(Store::Oplog, index, &buffer)].into_boxed_slice())
     }
 
-    fn fresh(key_pair: PartialKeypair) -> Result<OplogOpenOutcome, HypercoreError> {
+    fn fresh(
+        key_pair: PartialKeypair,
+        manifest: Option<Manifest>,
+    ) -> Result<OplogOpenOutcome, HypercoreError> {
         let entries_length: u64 = 0;
         let entries_byte_length: u64 = 0;
-        let header = Header::new(key_pair);
+        let header = Header::new(key_pair, manifest)?;
         let (header_bits, infos_to_flush) =
             Self::insert_header(&header, entries_byte_length, INITIAL_HEADER_BITS, false)?;
         let oplog = Oplog {
@@ -341,6 +388,32 @@ impl Oplog {
         ))
     }
 
+    /// Oplog with a fresh header, to be rebuilt from the tree and bitfield, and the headers
+    /// that can still be decoded from the damaged header slots of `existing`
+    fn recovered(
+        key_pair: PartialKeypair,
+        manifest: Option<Manifest>,
+        existing: &[u8],
+    ) -> Result<OplogOpenOutcome, HypercoreError> {
+        let damaged_headers: Vec<Header> = [
+            existing.get(OplogSlot::FirstHeader as usize..OplogSlot::SecondHeader as usize),
+            existing.get(OplogSlot::SecondHeader as usize..OplogSlot::Entries as usize),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(decode_damaged_header)
+        .collect();
+        let oplog = Oplog {
+            header_bits: INITIAL_HEADER_BITS,
+            entries_length: 0,
+            entries_byte_length: 0,
+        };
+        let mut outcome =
+            OplogOpenOutcome::new(oplog, Header::new(key_pair, manifest)?, Box::new([]));
+        outcome.recovery = Some(damaged_headers);
+        Ok(outcome)
+    }
+
     fn insert_header(
         header: &Header,
         entries_byte_length: u64,
@@ -422,6 +495,18 @@ impl Oplog {
         }))
     }
 
+    /// Validates the leader of a header slot. When recovering, a slot with an invalid
+    /// checksum is treated as empty.
+    fn validate_header_leader(
+        buffer: &[u8],
+        recover: bool,
+    ) -> Result<Option<ValidateLeaderOutcome<'_>>, HypercoreError> {
+        match Self::validate_leader(buffer) {
+            Err(HypercoreError::InvalidChecksum { .. }) if recover => Ok(None),
+            result => result,
+        }
+    }
+
     /// Gets the current header bit
     fn get_current_header_bit(&self) -> bool {
         self.header_bits[0] != self.header_bits[1]
@@ -446,6 +531,12 @@ impl Oplog {
     }
 }
 
+/// Decode the header of a slot without checking its checksum, if it still decodes
+fn decode_damaged_header(buffer: &[u8]) -> Option<Header> {
+    let data = buffer.get(LEADER_SIZE..)?;
+    Header::decode(data).ok().map(|(header, _)| header)
+}
+
 /// Create a header. 30 bits are the length plus two bits for "partial" and "header" info
 fn build_len_and_info_header(data_length: usize, header_bit: bool, partial_bit: bool) -> u32 {
     let data_length: u32 = data_length
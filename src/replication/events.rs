@@ -18,6 +18,16 @@ pub struct Get {
 #[derive(Debug, Clone)]
 pub struct DataUpgrade {}
 
+/// Emitted when the hypercore is truncated, either locally or by following a peer to a
+/// newer fork
+#[derive(Debug, Clone)]
+pub struct Truncate {
+    /// Length of the hypercore kept from before the truncation
+    pub ancestors: u64,
+    /// Fork id after the truncation
+    pub fork: u64,
+}
+
 /// Emitted when core gets new blocks
 #[derive(Debug, Clone)]
 pub struct Have {
@@ -54,6 +64,8 @@ pub enum Event {
     DataUpgrade(DataUpgrade),
     /// Emmitted when core gets new blocks
     Have(Have),
+    /// Emmitted when core is truncated
+    Truncate(Truncate),
 }
 
 /// Derive From<msg> for Enum where enum variant and msg have the same name
@@ -70,6 +82,7 @@ macro_rules! impl_from_for_enum_variant {
 impl_from_for_enum_variant!(Event, Get);
 impl_from_for_enum_variant!(Event, DataUpgrade);
 impl_from_for_enum_variant!(Event, Have);
+impl_from_for_enum_variant!(Event, Truncate);
 
 #[derive(Debug)]
 pub(crate) struct Events {
@@ -0,0 +1,142 @@
+//! Live stream that follows a hypercore as it grows
+use async_broadcast::{Receiver, RecvError};
+
+use super::Event;
+use crate::{Hypercore, HypercoreError};
+
+/// Cursor that follows a [`Hypercore`] as it grows, created with
+/// [`crate::Hypercore::live_stream`]. Core events are only used to wake up, the core
+/// itself is always checked for the next value. Because of this no values are lost when
+/// the event queue overflows.
+#[derive(Debug)]
+pub struct LiveStream {
+    index: u64,
+    requested: bool,
+    events: Receiver<Event>,
+}
+
+impl LiveStream {
+    pub(crate) fn new(start: u64, events: Receiver<Event>) -> Self {
+        Self {
+            index: start,
+            requested: false,
+            events,
+        }
+    }
+
+    /// Index of the next value this stream yields
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Read the next value from the core and advance, or return None if it is not
+    /// available yet. A value that is within the length of the core but missing locally
+    /// is requested from replicators once with a [`super::events::Get`] event.
+    pub async fn try_next(
+        &mut self,
+        core: &mut Hypercore,
+    ) -> Result<Option<Vec<u8>>, HypercoreError> {
+        if !core.has(self.index).await? {
+            if !self.requested && self.index < core.info().length {
+                self.requested = true;
+                core.get(self.index).await?;
+            }
+            return Ok(None);
+        }
+        let value = core.get(self.index).await?;
+        self.index += 1;
+        self.requested = false;
+        Ok(value)
+    }
+
+    /// Wait until the core has changed since this was last called. Returns false if the
+    /// core has been dropped and no more values can arrive.
+    pub async fn changed(&mut self) -> bool {
+        match self.events.recv().await {
+            // Dropped events are fine, we only need to know that something happened
+            Ok(_) | Err(RecvError::Overflowed(_)) => true,
+            Err(RecvError::Closed) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::tests::{create_hypercore_with_data, create_hypercore_with_data_and_key_pair};
+    use crate::{PartialKeypair, RequestBlock, RequestUpgrade};
+
+    #[async_std::test]
+    async fn live_stream_follows_appends() -> Result<(), HypercoreError> {
+        let mut core = create_hypercore_with_data(2).await?;
+        let mut stream = core.live_stream(1);
+        assert_eq!(stream.try_next(&mut core).await?, Some(b"#1".to_vec()));
+        assert_eq!(stream.try_next(&mut core).await?, None);
+
+        // Overflow the event queue, no values must be lost
+        for i in 2..100 {
+            core.append(format!("#{i}").as_bytes()).await?;
+        }
+        assert!(stream.changed().await);
+        for i in 2..100 {
+            assert_eq!(
+                stream.try_next(&mut core).await?,
+                Some(format!("#{i}").into_bytes())
+            );
+        }
+        assert_eq!(stream.try_next(&mut core).await?, None);
+        assert_eq!(stream.index(), 100);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn live_stream_follows_proofs() -> Result<(), HypercoreError> {
+        let mut main = create_hypercore_with_data(10).await?;
+        let mut clone = create_hypercore_with_data_and_key_pair(
+            0,
+            PartialKeypair {
+                public: main.key_pair.public,
+                secret: None,
+            },
+        )
+        .await?;
+        let mut stream = clone.live_stream(0);
+        assert_eq!(stream.try_next(&mut clone).await?, None);
+
+        // Upgrade without data, the first value gets requested
+        let proof = main
+            .create_proof(
+                None,
+                Some(RequestBlock { index: 0, nodes: 0 }),
+                None,
+                Some(RequestUpgrade {
+                    start: 0,
+                    length: 10,
+                }),
+            )
+            .await?
+            .unwrap();
+        assert!(clone.verify_and_apply_proof(&proof).await?);
+        assert!(stream.changed().await);
+        assert_eq!(stream.try_next(&mut clone).await?, None);
+        let mut requested = false;
+        while let Ok(event) = stream.events.try_recv() {
+            if let Event::Get(get) = event {
+                assert_eq!(get.index, 0);
+                requested = true;
+            }
+        }
+        assert!(requested);
+
+        // Receiving the data wakes up the stream
+        let nodes = clone.missing_nodes(0).await?;
+        let proof = main
+            .create_proof(Some(RequestBlock { index: 0, nodes }), None, None, None)
+            .await?
+            .unwrap();
+        assert!(clone.verify_and_apply_proof(&proof).await?);
+        assert!(stream.changed().await);
+        assert_eq!(stream.try_next(&mut clone).await?, Some(b"#0".to_vec()));
+        Ok(())
+    }
+}
@@ -1,8 +1,13 @@
 //! External interface for replication
 pub mod events;
+pub mod live_stream;
+#[cfg(feature = "shared-core")]
+pub mod session;
 #[cfg(feature = "shared-core")]
 pub mod shared_core;
 
+#[cfg(feature = "shared-core")]
+pub use session::Session;
 #[cfg(feature = "shared-core")]
 pub use shared_core::SharedCore;
 
@@ -12,6 +17,7 @@ use crate::{
 };
 
 pub use events::Event;
+pub use live_stream::LiveStream;
 
 use async_broadcast::Receiver;
 use std::future::Future;
@@ -71,7 +77,7 @@ pub enum CoreMethodsError {
 /// so they can use all Hypercore-like things such as `SharedCore`.
 pub trait CoreMethods: CoreInfo {
     /// Check if the core has the block at the given index locally
-    fn has(&self, index: u64) -> impl Future<Output = bool> + Send;
+    fn has(&self, index: u64) -> impl Future<Output = Result<bool, CoreMethodsError>> + Send;
 
     /// get a block
     fn get(
@@ -0,0 +1,307 @@
+//! Sessions that share one underlying [`Hypercore`]. Each session has its own event
+//! subscription, optional snapshot and close lifecycle, and the underlying hypercore is
+//! closed when the last session closes.
+use crate::{
+    AppendOutcome, Hypercore, HypercoreError, Info, PartialKeypair, Proof, RequestBlock,
+    RequestSeek, RequestUpgrade, Snapshot,
+};
+use async_broadcast::Receiver;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use super::{
+    CoreInfo, CoreMethods, CoreMethodsError, Event, ReplicationMethods, ReplicationMethodsError,
+    SharedCore,
+};
+
+/// Session over a [`Hypercore`], created with [`Hypercore::session`] or
+/// [`Session::session`].
+///
+/// Dropping a session without [`Session::close`] does not flush the underlying hypercore,
+/// even if it was the last open session. The changes that were not flushed are replayed
+/// from the oplog when the hypercore is opened again.
+#[derive(Debug)]
+pub struct Session {
+    core: SharedCore,
+    open_sessions: Arc<AtomicUsize>,
+    events: Receiver<Event>,
+    snapshot: Option<Snapshot>,
+    closed: bool,
+}
+
+impl Session {
+    pub(crate) fn new(core: Hypercore) -> Self {
+        let events = core.event_subscribe();
+        Self {
+            core: SharedCore::from(core),
+            open_sessions: Arc::new(AtomicUsize::new(1)),
+            events,
+            snapshot: None,
+            closed: false,
+        }
+    }
+
+    /// Create a new session over the same hypercore, with its own event subscription.
+    pub async fn session(&self) -> Session {
+        let events = {
+            // Sessions are counted with the hypercore locked, see `close`
+            let core = self.core.0.lock().await;
+            self.open_sessions.fetch_add(1, Ordering::SeqCst);
+            core.event_subscribe()
+        };
+        Self {
+            core: self.core.clone(),
+            open_sessions: self.open_sessions.clone(),
+            events,
+            snapshot: None,
+            closed: false,
+        }
+    }
+
+    /// Create a new read-only session over the same hypercore, pinned to its current
+    /// length. See [`Snapshot`].
+    pub async fn snapshot(&self) -> Session {
+        let (events, snapshot) = {
+            let core = self.core.0.lock().await;
+            self.open_sessions.fetch_add(1, Ordering::SeqCst);
+            (core.event_subscribe(), core.snapshot())
+        };
+        Self {
+            core: self.core.clone(),
+            open_sessions: self.open_sessions.clone(),
+            events,
+            snapshot: Some(snapshot),
+            closed: false,
+        }
+    }
+
+    /// Snapshot this session is pinned to, if any.
+    pub fn pinned_snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    /// Events of the underlying hypercore, received since this session was created.
+    pub fn events(&mut self) -> &mut Receiver<Event> {
+        &mut self.events
+    }
+
+    /// Number of sessions open on the underlying hypercore.
+    pub fn open_sessions(&self) -> usize {
+        self.open_sessions.load(Ordering::SeqCst)
+    }
+
+    /// Close the session. If this is the last open session, the underlying hypercore is
+    /// flushed, see [`Hypercore::flush`].
+    pub async fn close(mut self) -> Result<(), HypercoreError> {
+        self.closed = true;
+        // The hypercore is locked before counting, so that no session is created meanwhile
+        let mut core = self.core.0.lock().await;
+        if self.open_sessions.fetch_sub(1, Ordering::SeqCst) == 1 {
+            core.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if !self.closed {
+            self.open_sessions.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl CoreInfo for Session {
+    /// Info of the snapshot for snapshot sessions, see [`Snapshot::info`]. A snapshot that
+    /// is no longer available keeps its pinned length, with a contiguous length of zero.
+    async fn info(&self) -> Info {
+        let core = self.core.0.lock().await;
+        match &self.snapshot {
+            Some(snapshot) => snapshot.pinned_info(&core),
+            None => core.info(),
+        }
+    }
+
+    async fn key_pair(&self) -> PartialKeypair {
+        let core = self.core.0.lock().await;
+        core.key_pair().clone()
+    }
+}
+
+impl ReplicationMethods for Session {
+    async fn verify_and_apply_proof(&self, proof: &Proof) -> Result<bool, ReplicationMethodsError> {
+        let mut core = self.core.0.lock().await;
+        Ok(core.verify_and_apply_proof(proof).await?)
+    }
+
+    async fn missing_nodes(&self, index: u64) -> Result<u64, ReplicationMethodsError> {
+        let mut core = self.core.0.lock().await;
+        Ok(core.missing_nodes(index).await?)
+    }
+
+    async fn create_proof(
+        &self,
+        block: Option<RequestBlock>,
+        hash: Option<RequestBlock>,
+        seek: Option<RequestSeek>,
+        upgrade: Option<RequestUpgrade>,
+    ) -> Result<Option<Proof>, ReplicationMethodsError> {
+        let mut core = self.core.0.lock().await;
+        Ok(match &self.snapshot {
+            Some(snapshot) => {
+                snapshot
+                    .create_proof(&mut core, block, hash, seek, upgrade)
+                    .await?
+            }
+            None => core.create_proof(block, hash, seek, upgrade).await?,
+        })
+    }
+
+    async fn event_subscribe(&self) -> Receiver<Event> {
+        self.core.0.lock().await.event_subscribe()
+    }
+}
+
+impl CoreMethods for Session {
+    async fn has(&self, index: u64) -> Result<bool, CoreMethodsError> {
+        let mut core = self.core.0.lock().await;
+        match &self.snapshot {
+            Some(snapshot) => match snapshot.info(&core) {
+                Ok(info) => Ok(index < info.length && core.has(index).await?),
+                Err(_) => Ok(false),
+            },
+            None => Ok(core.has(index).await?),
+        }
+    }
+
+    async fn get(&self, index: u64) -> Result<Option<Vec<u8>>, CoreMethodsError> {
+        let mut core = self.core.0.lock().await;
+        Ok(match &self.snapshot {
+            Some(snapshot) => snapshot.get(&mut core, index).await?,
+            None => core.get(index).await?,
+        })
+    }
+
+    async fn append(&self, data: &[u8]) -> Result<AppendOutcome, CoreMethodsError> {
+        if self.snapshot.is_some() {
+            return Err(HypercoreError::NotWritable.into());
+        }
+        let mut core = self.core.0.lock().await;
+        Ok(core.append(data).await?)
+    }
+
+    async fn append_batch<A: AsRef<[u8]>, B: AsRef<[A]> + Send>(
+        &self,
+        batch: B,
+    ) -> Result<AppendOutcome, CoreMethodsError> {
+        if self.snapshot.is_some() {
+            return Err(HypercoreError::NotWritable.into());
+        }
+        let mut core = self.core.0.lock().await;
+        Ok(core.append_batch(batch).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::tests::create_hypercore_with_data;
+
+    #[async_std::test]
+    async fn session_shares_core() -> Result<(), CoreMethodsError> {
+        let first = create_hypercore_with_data(2).await?.session();
+        let mut second = first.session().await;
+        assert_eq!(first.open_sessions(), 2);
+
+        first.append(b"#2").await?;
+        assert_eq!(second.info().await.length, 3);
+        assert_eq!(second.get(2).await?, Some(b"#2".to_vec()));
+
+        // Both sessions received the events of the append, independently
+        assert!(matches!(
+            second.events().try_recv(),
+            Ok(Event::DataUpgrade(_))
+        ));
+        assert!(matches!(second.events().try_recv(), Ok(Event::Have(_))));
+        assert!(second.events().try_recv().is_err());
+        let mut first = first;
+        assert!(matches!(
+            first.events().try_recv(),
+            Ok(Event::DataUpgrade(_))
+        ));
+
+        first.close().await?;
+        assert_eq!(second.open_sessions(), 1);
+        second.append(b"#3").await?;
+        assert_eq!(second.get(3).await?, Some(b"#3".to_vec()));
+        second.close().await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn session_snapshot() -> Result<(), CoreMethodsError> {
+        let session = create_hypercore_with_data(2).await?.session();
+        let snapshot = session.snapshot().await;
+        session.append(b"#2").await?;
+
+        assert_eq!(snapshot.info().await.length, 2);
+        assert!(snapshot.has(1).await?);
+        assert!(!snapshot.has(2).await?);
+        assert_eq!(snapshot.get(2).await?, None);
+        assert!(matches!(
+            snapshot.append(b"#3").await,
+            Err(CoreMethodsError::HypercoreError(
+                HypercoreError::NotWritable
+            ))
+        ));
+        assert_eq!(session.get(2).await?, Some(b"#2".to_vec()));
+
+        // Dropping a session counts as closing it
+        drop(snapshot);
+        assert_eq!(session.open_sessions(), 1);
+        session.close().await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn session_close_flushes() -> Result<(), CoreMethodsError> {
+        let session = create_hypercore_with_data(0).await?.session();
+        let core = session.core.clone();
+        session.append(b"#0").await?;
+        session.append(b"#1").await?;
+        assert_ne!(
+            core.0.lock().await.skip_flush_count,
+            crate::core::DEFAULT_FLUSH_INTERVAL
+        );
+        session.close().await?;
+        assert_eq!(
+            core.0.lock().await.skip_flush_count,
+            crate::core::DEFAULT_FLUSH_INTERVAL
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn session_snapshot_unavailable() -> Result<(), CoreMethodsError> {
+        let session = create_hypercore_with_data(3).await?.session();
+        let snapshot = session.snapshot().await;
+        session.core.0.lock().await.truncate(1, None).await?;
+
+        // The snapshot keeps its pinned info instead of reporting the truncated hypercore
+        let info = snapshot.info().await;
+        assert_eq!(info.length, 3);
+        assert_eq!(info.fork, 0);
+        assert_eq!(info.contiguous_length, 0);
+        assert!(!snapshot.has(0).await?);
+        assert!(matches!(
+            snapshot.get(0).await,
+            Err(CoreMethodsError::HypercoreError(
+                HypercoreError::SnapshotNotAvailable { .. }
+            ))
+        ));
+        assert_eq!(session.info().await.length, 1);
+        Ok(())
+    }
+}
@@ -1,15 +1,21 @@
 //! Implementation of a Hypercore that can have multiple owners. Along with implementations of all
 //! the hypercore traits.
 use crate::{
-    AppendOutcome, Hypercore, Info, PartialKeypair, Proof, RequestBlock, RequestSeek,
-    RequestUpgrade,
+    AppendOutcome, Hypercore, HypercoreError, Info, PartialKeypair, Proof, RequestBlock,
+    RequestSeek, RequestUpgrade,
 };
-use async_broadcast::Receiver;
+use async_broadcast::{Receiver, RecvError};
 use async_lock::Mutex;
+use futures::{
+    future::{self, Either},
+    pin_mut,
+    stream::Stream,
+};
 use std::{future::Future, sync::Arc};
 
 use super::{
-    CoreInfo, CoreMethods, CoreMethodsError, Event, ReplicationMethods, ReplicationMethodsError,
+    CoreInfo, CoreMethods, CoreMethodsError, Event, LiveStream, ReplicationMethods,
+    ReplicationMethodsError,
 };
 
 /// Hypercore that can have multiple owners
@@ -26,6 +32,102 @@ impl SharedCore {
     pub fn from_hypercore(core: Hypercore) -> Self {
         SharedCore(Arc::new(Mutex::new(core)))
     }
+
+    /// Stream values from `start` onwards, waiting for new values appended locally or
+    /// received from peers. The lock is released while waiting. The stream ends after
+    /// the first error.
+    pub fn live_stream(
+        &self,
+        start: u64,
+    ) -> impl Stream<Item = Result<Vec<u8>, HypercoreError>> + Send + 'static {
+        let state: (SharedCore, Option<LiveStream>, bool) = (self.clone(), None, false);
+        futures::stream::unfold(state, move |(core, mut live_stream, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                let next = {
+                    let mut hypercore = core.0.lock().await;
+                    // NB: Subscribe while holding the lock, so that no change after
+                    // the check below goes unnoticed.
+                    let live_stream =
+                        live_stream.get_or_insert_with(|| hypercore.live_stream(start));
+                    live_stream.try_next(&mut hypercore).await
+                };
+                match next {
+                    Ok(Some(value)) => return Some((Ok(value), (core, live_stream, false))),
+                    Ok(None) => {
+                        let live_stream = live_stream.as_mut().expect("Created above");
+                        if !live_stream.changed().await {
+                            return None;
+                        }
+                    }
+                    Err(err) => return Some((Err(err), (core, live_stream, true))),
+                }
+            }
+        })
+    }
+}
+
+impl SharedCore {
+    /// Wait until the hypercore has at least `min_length` blocks, through local appends or
+    /// upgrades received from peers, and return its length. The lock is released while
+    /// waiting, and dropping the future cancels the wait.
+    pub async fn wait_for_length(&self, min_length: u64) -> u64 {
+        let mut events: Option<Receiver<Event>> = None;
+        loop {
+            let length = {
+                let core = self.0.lock().await;
+                // NB: Subscribe while holding the lock, so that no change after the check
+                // below goes unnoticed.
+                events.get_or_insert_with(|| core.event_subscribe());
+                core.info().length
+            };
+            if length >= min_length {
+                return length;
+            }
+            let events = events.as_mut().expect("Subscribed above");
+            match events.recv().await {
+                // Dropped events are fine, the length is checked from the core again
+                Ok(_) | Err(RecvError::Overflowed(_)) => {}
+                Err(RecvError::Closed) => return length,
+            }
+        }
+    }
+
+    /// Same as [`SharedCore::wait_for_length`], but gives up and returns None when
+    /// `timeout` completes first, e.g. a sleep of the async runtime in use.
+    pub async fn wait_for_length_with_timeout<F: Future<Output = ()>>(
+        &self,
+        min_length: u64,
+        timeout: F,
+    ) -> Option<u64> {
+        with_timeout(self.wait_for_length(min_length), timeout).await
+    }
+
+    /// Wait until the hypercore grows past its current length, and return the new length.
+    /// The lock is released while waiting, and dropping the future cancels the wait.
+    pub async fn update(&self) -> u64 {
+        let length = self.0.lock().await.info().length;
+        self.wait_for_length(length + 1).await
+    }
+
+    /// Same as [`SharedCore::update`], but gives up and returns None when `timeout`
+    /// completes first, e.g. a sleep of the async runtime in use.
+    pub async fn update_with_timeout<F: Future<Output = ()>>(&self, timeout: F) -> Option<u64> {
+        with_timeout(self.update(), timeout).await
+    }
+}
+
+async fn with_timeout<F: Future<Output = u64>, T: Future<Output = ()>>(
+    wait: F,
+    timeout: T,
+) -> Option<u64> {
+    pin_mut!(wait, timeout);
+    match future::select(wait, timeout).await {
+        Either::Left((length, _)) => Some(length),
+        Either::Right(_) => None,
+    }
 }
 
 impl CoreInfo for SharedCore {
@@ -84,10 +186,10 @@ impl ReplicationMethods for SharedCore {
 }
 
 impl CoreMethods for SharedCore {
-    fn has(&self, index: u64) -> impl Future<Output = bool> + Send {
+    fn has(&self, index: u64) -> impl Future<Output = Result<bool, CoreMethodsError>> + Send {
         async move {
-            let core = self.0.lock().await;
-            core.has(index)
+            let mut core = self.0.lock().await;
+            Ok(core.has(index).await?)
         }
     }
     fn get(
@@ -125,6 +227,7 @@ impl CoreMethods for SharedCore {
 mod tests {
 
     use super::*;
+    use std::time::Duration;
 
     use crate::core::tests::{create_hypercore_with_data, create_hypercore_with_data_and_key_pair};
     #[async_std::test]
@@ -149,31 +252,107 @@ mod tests {
         let _kp = core.key_pair().await;
 
         // check CoreMethods
-        assert_eq!(core.has(0).await, false);
+        assert_eq!(core.has(0).await?, false);
         assert_eq!(core.get(0).await?, None);
         let res = core.append(b"foo").await?;
         assert_eq!(
             res,
             AppendOutcome {
                 length: 1,
-                byte_length: 3
+                byte_length: 3,
+                durable: false,
             }
         );
-        assert_eq!(core.has(0).await, true);
+        assert_eq!(core.has(0).await?, true);
         assert_eq!(core.get(0).await?, Some(b"foo".into()));
         let res = core.append_batch([b"hello", b"world"]).await?;
         assert_eq!(
             res,
             AppendOutcome {
                 length: 3,
-                byte_length: 13
+                byte_length: 13,
+                durable: false,
             }
         );
-        assert_eq!(core.has(2).await, true);
+        assert_eq!(core.has(2).await?, true);
         assert_eq!(core.get(2).await?, Some(b"world".into()));
         Ok(())
     }
 
+    #[async_std::test]
+    async fn shared_core_live_stream() -> Result<(), CoreMethodsError> {
+        use futures::StreamExt;
+
+        let core = SharedCore::from(create_hypercore_with_data(1).await?);
+        let stream = core.live_stream(0);
+        let reader = async_std::task::spawn(async move {
+            stream
+                .take(100)
+                .map(|value| value.unwrap())
+                .collect::<Vec<Vec<u8>>>()
+                .await
+        });
+        for i in 1..100 {
+            core.append(format!("#{i}").as_bytes()).await?;
+        }
+        let values = reader.await;
+        let expected: Vec<Vec<u8>> = (0..100).map(|i| format!("#{i}").into_bytes()).collect();
+        assert_eq!(values, expected);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn shared_core_wait_for_length() -> Result<(), ReplicationMethodsError> {
+        let main = SharedCore::from(create_hypercore_with_data(0).await?);
+        let clone = SharedCore::from(
+            create_hypercore_with_data_and_key_pair(
+                0,
+                PartialKeypair {
+                    public: main.key_pair().await.public,
+                    secret: None,
+                },
+            )
+            .await?,
+        );
+        assert_eq!(main.wait_for_length(0).await, 0);
+        let timed_out = main
+            .wait_for_length_with_timeout(1, async_std::task::sleep(Duration::from_millis(10)))
+            .await;
+        assert_eq!(timed_out, None);
+
+        // Local appends
+        let waiter = main.clone();
+        let handle = async_std::task::spawn(async move { waiter.wait_for_length(3).await });
+        // NB: Joined futures are polled in order, so the update starts waiting before the
+        // append
+        let (length, outcome) = futures::join!(main.update(), main.append(b"#0"));
+        assert_eq!(length, 1);
+        assert_eq!(outcome?.length, 1);
+        main.append_batch([b"#1", b"#2", b"#3"]).await?;
+        assert_eq!(handle.await, 4);
+
+        // Upgrades from a peer
+        let proof = main
+            .create_proof(
+                None,
+                Some(RequestBlock { index: 0, nodes: 0 }),
+                None,
+                Some(RequestUpgrade {
+                    start: 0,
+                    length: 4,
+                }),
+            )
+            .await?
+            .unwrap();
+        let (length, applied) = futures::join!(
+            clone.update_with_timeout(async_std::task::sleep(Duration::from_secs(10))),
+            clone.verify_and_apply_proof(&proof)
+        );
+        assert!(applied?);
+        assert_eq!(length, Some(4));
+        Ok(())
+    }
+
     #[async_std::test]
     async fn shared_core_replication_methods() -> Result<(), ReplicationMethodsError> {
         let main = create_hypercore_with_data(10).await?;
@@ -0,0 +1,201 @@
+//! Read-only snapshots of a hypercore
+use ed25519_dalek::VerifyingKey;
+
+use crate::{
+    tree::MerkleTreeHead, Hypercore, HypercoreError, Info, Proof, RequestBlock, RequestSeek,
+    RequestUpgrade,
+};
+
+/// Read-only view of a [`Hypercore`] pinned to the length it had when the snapshot was
+/// created with [`Hypercore::snapshot`]. Later appends are not visible through the
+/// snapshot. The snapshot becomes unavailable if the hypercore is truncated or forked.
+///
+/// Values are read through the hypercore given to each method, which must be the one the
+/// snapshot was created from.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    key: VerifyingKey,
+    head: MerkleTreeHead,
+}
+
+impl Snapshot {
+    pub(crate) fn new(key: VerifyingKey, head: MerkleTreeHead) -> Self {
+        Self { key, head }
+    }
+
+    /// Gets basic info about the hypercore at the time of the snapshot
+    pub fn info(&self, core: &Hypercore) -> Result<Info, HypercoreError> {
+        self.validate(core)?;
+        let info = core.info();
+        Ok(Info {
+            length: self.head.length,
+            byte_length: self.head.byte_length - self.head.length * core.padding(),
+            contiguous_length: std::cmp::min(info.contiguous_length, self.head.length),
+            fork: self.head.fork,
+            writeable: false,
+        })
+    }
+
+    /// Info pinned at the time of the snapshot, also when the snapshot is no longer
+    /// available. No values of an unavailable snapshot can be read, so its contiguous
+    /// length is zero.
+    #[cfg(feature = "shared-core")]
+    pub(crate) fn pinned_info(&self, core: &Hypercore) -> Info {
+        self.info(core).unwrap_or_else(|_| Info {
+            length: self.head.length,
+            byte_length: self.head.byte_length - self.head.length * core.padding(),
+            contiguous_length: 0,
+            fork: self.head.fork,
+            writeable: false,
+        })
+    }
+
+    /// Read value at given index, if any. Indices past the length of the snapshot have no
+    /// value.
+    pub async fn get(
+        &self,
+        core: &mut Hypercore,
+        index: u64,
+    ) -> Result<Option<Vec<u8>>, HypercoreError> {
+        self.validate(core)?;
+        if index >= self.head.length {
+            return Ok(None);
+        }
+        core.get(index).await
+    }
+
+    /// Create a proof for given request against the snapshot, i.e. upgrades are capped to
+    /// the length of the snapshot.
+    pub async fn create_proof(
+        &self,
+        core: &mut Hypercore,
+        block: Option<RequestBlock>,
+        hash: Option<RequestBlock>,
+        seek: Option<RequestSeek>,
+        upgrade: Option<RequestUpgrade>,
+    ) -> Result<Option<Proof>, HypercoreError> {
+        self.validate(core)?;
+        core.create_proof_at_head(&self.head, block, hash, seek, upgrade)
+            .await
+    }
+
+    fn validate(&self, core: &Hypercore) -> Result<(), HypercoreError> {
+        if core.key_pair.public != self.key {
+            return Err(HypercoreError::BadArgument {
+                context: "Snapshot was created from a different hypercore".to_string(),
+            });
+        }
+        let info = core.info();
+        if info.fork != self.head.fork {
+            return Err(HypercoreError::SnapshotNotAvailable {
+                context: format!("Hypercore forked from {} to {}", self.head.fork, info.fork),
+            });
+        }
+        if info.length < self.head.length {
+            return Err(HypercoreError::SnapshotNotAvailable {
+                context: format!(
+                    "Hypercore truncated from {} to {}",
+                    self.head.length, info.length
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::tests::{create_hypercore_with_data, create_hypercore_with_data_and_key_pair};
+    use crate::PartialKeypair;
+
+    #[async_std::test]
+    async fn snapshot_pinned_to_length() -> Result<(), HypercoreError> {
+        let mut core = create_hypercore_with_data(10).await?;
+        let snapshot = core.snapshot();
+        core.append(b"#10").await?;
+        assert_eq!(core.info().length, 11);
+
+        let info = snapshot.info(&core)?;
+        assert_eq!(info.length, 10);
+        assert_eq!(info.byte_length, 20);
+        assert_eq!(info.contiguous_length, 10);
+        assert!(!info.writeable);
+        assert_eq!(snapshot.get(&mut core, 9).await?, Some(b"#9".to_vec()));
+        assert_eq!(snapshot.get(&mut core, 10).await?, None);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn snapshot_create_proof() -> Result<(), HypercoreError> {
+        let mut core = create_hypercore_with_data(10).await?;
+        let snapshot = core.snapshot();
+        core.append_batch(&[b"#10", b"#11"]).await?;
+
+        let mut clone = create_hypercore_with_data_and_key_pair(
+            0,
+            PartialKeypair {
+                public: core.key_pair.public,
+                secret: None,
+            },
+        )
+        .await?;
+        let proof = snapshot
+            .create_proof(
+                &mut core,
+                None,
+                Some(RequestBlock { index: 6, nodes: 0 }),
+                None,
+                Some(RequestUpgrade {
+                    start: 0,
+                    length: 10,
+                }),
+            )
+            .await?
+            .unwrap();
+        assert!(clone.verify_and_apply_proof(&proof).await?);
+        assert_eq!(clone.info().length, 10);
+
+        // Upgrading past the snapshot is not possible
+        assert!(snapshot
+            .create_proof(
+                &mut core,
+                None,
+                None,
+                None,
+                Some(RequestUpgrade {
+                    start: 0,
+                    length: 12,
+                }),
+            )
+            .await
+            .is_err());
+
+        // The core itself is not affected
+        assert_eq!(core.info().length, 12);
+        assert_eq!(core.get(11).await?, Some(b"#11".to_vec()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn snapshot_invalidated_by_truncate() -> Result<(), HypercoreError> {
+        let mut core = create_hypercore_with_data(10).await?;
+        let snapshot = core.snapshot();
+        core.truncate(10, None).await?;
+        assert!(matches!(
+            snapshot.get(&mut core, 1).await,
+            Err(HypercoreError::SnapshotNotAvailable { .. })
+        ));
+        assert!(matches!(
+            snapshot.info(&core),
+            Err(HypercoreError::SnapshotNotAvailable { .. })
+        ));
+
+        let other = create_hypercore_with_data(10).await?;
+        assert!(matches!(
+            core.snapshot().info(&other),
+            Err(HypercoreError::BadArgument { .. })
+        ));
+        Ok(())
+    }
+}
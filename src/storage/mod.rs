@@ -19,6 +19,20 @@ use crate::{
 pub trait StorageTraits: RandomAccess + Debug {}
 impl<T: RandomAccess + Debug> StorageTraits for T {}
 
+/// When appends and flushes are synced to the underlying storage, e.g. with `fsync` for
+/// disk storage. Set with [`crate::HypercoreBuilder::durability`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Never sync, except with [`crate::Hypercore::flush`]. Writes may be lost on power loss.
+    #[default]
+    None,
+    /// Sync when the bitfield, tree and header are flushed, i.e. every few operations
+    /// depending on [`crate::HypercoreBuilder::flush_interval`].
+    SyncOnFlush,
+    /// Sync the blocks and the oplog on every append, and on every flush.
+    SyncEveryAppend,
+}
+
 /// Save data to a desired storage backend.
 #[derive(Debug)]
 pub struct Storage {
@@ -233,6 +247,17 @@ impl Storage {
         Ok(())
     }
 
+    /// Sync given stores to the underlying storage
+    pub(crate) async fn sync(&mut self, stores: &[Store]) -> Result<(), HypercoreError> {
+        for store in stores {
+            self.get_random_access(store)
+                .sync_all()
+                .await
+                .map_err(map_random_access_err)?;
+        }
+        Ok(())
+    }
+
     fn get_random_access(&mut self, store: &Store) -> &mut Box<dyn StorageTraits + Send> {
         match store {
             Store::Tree => &mut self.tree,
@@ -1,21 +1,19 @@
 use compact_encoding::{
     as_array, map_decode, to_encoded_bytes, EncodingError, FixedWidthEncoding, FixedWidthU64,
 };
-use ed25519_dalek::Signature;
 use futures::future::Either;
 use intmap::IntMap;
 #[cfg(feature = "cache")]
 use moka::sync::Cache;
-use std::convert::TryFrom;
 
 #[cfg(feature = "cache")]
 use crate::common::cache::CacheOptions;
 use crate::common::{HypercoreError, NodeByteRange, Proof, ValuelessProof};
-use crate::crypto::Hash;
+use crate::crypto::{Hash, Verifier};
 use crate::oplog::HeaderTree;
 use crate::{
     common::{StoreInfo, StoreInfoInstruction},
-    Node, VerifyingKey,
+    Node,
 };
 use crate::{
     DataBlock, DataHash, DataSeek, DataUpgrade, RequestBlock, RequestSeek, RequestUpgrade, Store,
@@ -23,6 +21,29 @@ use crate::{
 
 use super::MerkleTreeChangeset;
 
+/// Hypercore index and a byte offset relative to the start of it
+pub(crate) type IndexAndOffset = (u64, u64);
+
+/// Byte lengths of values, None for those whose leaf node is not available
+pub(crate) type LeafLengths = Vec<Option<u64>>;
+
+/// Nodes at tree indices, None for those that are not available
+pub(crate) type OptionalNodes = Vec<Option<Node>>;
+
+/// Roots of a tree, None if they are not all stored
+pub(crate) type StoredRoots = Option<Vec<Node>>;
+
+/// Head of a merkle tree, i.e. the roots and the length, fork and signature they
+/// correspond to.
+#[derive(Debug, Clone)]
+pub(crate) struct MerkleTreeHead {
+    pub(crate) roots: Vec<Node>,
+    pub(crate) length: u64,
+    pub(crate) byte_length: u64,
+    pub(crate) fork: u64,
+    pub(crate) signature: Option<Box<[u8]>>,
+}
+
 /// Merkle tree.
 /// See https://github.com/hypercore-protocol/hypercore/blob/master/lib/merkle-tree.js
 #[derive(Debug)]
@@ -31,7 +52,7 @@ pub(crate) struct MerkleTree {
     pub(crate) length: u64,
     pub(crate) byte_length: u64,
     pub(crate) fork: u64,
-    pub(crate) signature: Option<Signature>,
+    pub(crate) signature: Option<Box<[u8]>>,
     unflushed: IntMap<Node>,
     truncated: bool,
     truncate_to: u64,
@@ -40,6 +61,8 @@ pub(crate) struct MerkleTree {
 }
 
 const NODE_SIZE: u64 = 40;
+/// How many nodes are read at a time when searching the tree store for a tree to recover
+const STORED_TREES_BATCH_NODES: u64 = 1024;
 
 impl MerkleTree {
     /// Opens MerkleTree, based on read infos.
@@ -94,14 +117,8 @@ impl MerkleTree {
                 if length > 0 {
                     length /= 2;
                 }
-                let signature: Option<Signature> = if !header_tree.signature.is_empty() {
-                    Some(
-                        Signature::try_from(&*header_tree.signature).map_err(|_err| {
-                            HypercoreError::InvalidSignature {
-                                context: "Could not parse signature".to_string(),
-                            }
-                        })?,
-                    )
+                let signature: Option<Box<[u8]>> = if !header_tree.signature.is_empty() {
+                    Some(header_tree.signature.clone())
                 } else {
                     None
                 };
@@ -124,6 +141,82 @@ impl MerkleTree {
         }
     }
 
+    /// Roots of the tree of given length, if they are all stored. Used to recover a header
+    /// whose oplog slots are both lost.
+    pub(crate) fn stored_roots(
+        length: u64,
+        infos: Option<&[StoreInfo]>,
+    ) -> Result<Either<Box<[StoreInfoInstruction]>, StoredRoots>, HypercoreError> {
+        let root_indices = get_root_indices(&length);
+        let infos = match infos {
+            Some(infos) => infos,
+            None => {
+                return Ok(Either::Left(
+                    root_indices
+                        .iter()
+                        .map(|&index| {
+                            StoreInfoInstruction::new_content_allow_miss(
+                                Store::Tree,
+                                NODE_SIZE * index,
+                                NODE_SIZE,
+                            )
+                        })
+                        .collect(),
+                ))
+            }
+        };
+        let mut roots: Vec<Node> = Vec::with_capacity(root_indices.len());
+        for (index, info) in root_indices.into_iter().zip(infos) {
+            if info.miss {
+                return Ok(Either::Right(None));
+            }
+            let node = node_from_bytes(&index, info.data.as_ref().unwrap())?;
+            if node.blank {
+                return Ok(Either::Right(None));
+            }
+            roots.push(node);
+        }
+        Ok(Either::Right(Some(roots)))
+    }
+
+    /// Instruction to read the batch of nodes right before node index `end`, when searching
+    /// the tree store backwards for the longest tree whose roots are all stored. Returns
+    /// the index of the first node of the batch, which is where the next batch ends.
+    pub(crate) fn stored_trees_batch(end: u64) -> (u64, StoreInfoInstruction) {
+        let start = end.saturating_sub(STORED_TREES_BATCH_NODES);
+        (
+            start,
+            StoreInfoInstruction::new_content(
+                Store::Tree,
+                NODE_SIZE * start,
+                NODE_SIZE * (end - start),
+            ),
+        )
+    }
+
+    /// Lengths of the trees whose last root is one of the stored nodes of a batch read with
+    /// [`Self::stored_trees_batch`], longest first
+    pub(crate) fn stored_trees_lengths(info: &StoreInfo) -> Result<Vec<u64>, HypercoreError> {
+        let data = info.data.as_ref().unwrap();
+        let start = index_from_info(info);
+        let mut lengths: Vec<u64> = vec![];
+        for (i, node_data) in data.chunks_exact(NODE_SIZE as usize).enumerate() {
+            let index = start + i as u64;
+            if !node_from_bytes(&index, node_data)?.blank {
+                // The last root of a tree spans up to its last leaf
+                lengths.push(flat_tree::right_span(index) / 2 + 1);
+            }
+        }
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        lengths.dedup();
+        Ok(lengths)
+    }
+
+    /// Number of nodes in a tree store of given byte length
+    pub(crate) fn stored_node_count(store_length: u64) -> u64 {
+        store_length / NODE_SIZE
+    }
+
     /// Initialize a changeset for this tree.
     /// This is called batch() in Javascript, see:
     /// https://github.com/hypercore-protocol/hypercore/blob/master/lib/merkle-tree.js
@@ -259,7 +352,7 @@ impl MerkleTree {
                 .position(|root| root.index == parent.index);
             if let Some(r) = r {
                 for i in 0..r {
-                    tree_offset += self.roots[i].length;
+                    tree_offset += changeset.roots[i].length;
                 }
                 return Ok(Either::Right(tree_offset));
             }
@@ -274,6 +367,137 @@ impl MerkleTree {
         }
     }
 
+    /// Get a copy of the current head of the tree
+    pub(crate) fn head(&self) -> MerkleTreeHead {
+        MerkleTreeHead {
+            roots: self.roots.clone(),
+            length: self.length,
+            byte_length: self.byte_length,
+            fork: self.fork,
+            signature: self.signature.clone(),
+        }
+    }
+
+    /// Swap the head of the tree with the given one. Only valid for heads of the same fork
+    /// that are not longer than the tree, because the nodes are shared.
+    pub(crate) fn swap_head(&mut self, head: &mut MerkleTreeHead) {
+        std::mem::swap(&mut self.roots, &mut head.roots);
+        std::mem::swap(&mut self.length, &mut head.length);
+        std::mem::swap(&mut self.byte_length, &mut head.byte_length);
+        std::mem::swap(&mut self.fork, &mut head.fork);
+        std::mem::swap(&mut self.signature, &mut head.signature);
+    }
+
+    /// Find the hypercore index containing the given byte offset, and the offset relative
+    /// to the start of that index. A byte offset equal to the byte length resolves to the
+    /// length. Returns None if the byte offset is beyond that, or if the tree nodes needed
+    /// to resolve it are not available locally. Byte offsets exclude `padding` bytes at the
+    /// start of every block.
+    pub(crate) fn seek(
+        &mut self,
+        bytes: u64,
+        padding: u64,
+        infos: Option<&[StoreInfo]>,
+    ) -> Result<Either<Box<[StoreInfoInstruction]>, Option<IndexAndOffset>>, HypercoreError> {
+        if bytes == 0 {
+            return Ok(Either::Right(Some((0, 0))));
+        }
+        let nodes: IntMap<Option<Node>> = self.infos_to_nodes(infos)?;
+        let mut bytes = bytes;
+        for root in &self.roots {
+            let root_length = root.length - padding * count_leaves(root.index);
+            if bytes == root_length {
+                return Ok(Either::Right(Some((
+                    (flat_tree::right_span(root.index) + 2) / 2,
+                    0,
+                ))));
+            }
+            if bytes > root_length {
+                bytes -= root_length;
+                continue;
+            }
+            let mut iter = flat_tree::Iterator::new(root.index);
+            while iter.index() & 1 != 0 {
+                match self.optional_node(iter.left_child(), &nodes)? {
+                    Either::Left(instruction) => {
+                        // Need to return immediately, see seek_trusted_tree
+                        return Ok(Either::Left(vec![instruction].into_boxed_slice()));
+                    }
+                    Either::Right(Some(node)) => {
+                        let node_length = node.length - padding * count_leaves(node.index);
+                        if node_length == bytes {
+                            return Ok(Either::Right(Some(((iter.right_span() + 2) / 2, 0))));
+                        }
+                        if node_length > bytes {
+                            continue;
+                        }
+                        bytes -= node_length;
+                        iter.sibling();
+                    }
+                    Either::Right(None) => {
+                        return Ok(Either::Right(None));
+                    }
+                }
+            }
+            return Ok(Either::Right(Some((iter.index() / 2, bytes))));
+        }
+        Ok(Either::Right(None))
+    }
+
+    /// Get the byte lengths of the values between start and end (exclusive), None for
+    /// those whose leaf node is not available locally.
+    pub(crate) fn leaf_lengths(
+        &mut self,
+        start: u64,
+        end: u64,
+        infos: Option<&[StoreInfo]>,
+    ) -> Result<Either<Box<[StoreInfoInstruction]>, LeafLengths>, HypercoreError> {
+        let nodes: IntMap<Option<Node>> = self.infos_to_nodes(infos)?;
+        let mut instructions: Vec<StoreInfoInstruction> = Vec::new();
+        let mut lengths: Vec<Option<u64>> = Vec::with_capacity((end - start) as usize);
+        for index in start..end {
+            match self.optional_node(hypercore_index_into_merkle_tree_index(index), &nodes)? {
+                Either::Left(instruction) => {
+                    instructions.push(instruction);
+                }
+                Either::Right(node) => {
+                    lengths.push(node.map(|node| node.length));
+                }
+            }
+        }
+        if instructions.is_empty() {
+            Ok(Either::Right(lengths))
+        } else {
+            Ok(Either::Left(instructions.into_boxed_slice()))
+        }
+    }
+
+    /// Get the nodes at the given tree indices, None for those that are not available
+    pub(crate) fn nodes(
+        &mut self,
+        indices: &[u64],
+        infos: Option<&[StoreInfo]>,
+    ) -> Result<Either<Box<[StoreInfoInstruction]>, OptionalNodes>, HypercoreError> {
+        let nodes: IntMap<Option<Node>> = self.infos_to_nodes(infos)?;
+        let mut instructions: Vec<StoreInfoInstruction> = Vec::new();
+        let mut result: OptionalNodes = Vec::with_capacity(indices.len());
+        for index in indices {
+            match self.optional_node(*index, &nodes)? {
+                Either::Left(instruction) => {
+                    instructions.push(instruction);
+                }
+                Either::Right(node) => {
+                    result.push(node);
+                }
+            }
+        }
+        if instructions.is_empty() {
+            Ok(Either::Right(result))
+        } else {
+            Ok(Either::Left(instructions.into_boxed_slice()))
+        }
+    }
+
     pub(crate) fn add_node(&mut self, node: Node) {
         self.unflushed.insert(node.index, node);
     }
@@ -328,11 +552,36 @@ impl MerkleTree {
         }
     }
 
+    /// Creates valueless proof from requests against the given head, see [`Self::swap_head`],
+    /// or the current one. The head is swapped back before returning, so that the tree never
+    /// keeps it while the caller awaits storage reads.
+    pub(crate) fn create_valueless_proof(
+        &mut self,
+        head: Option<&MerkleTreeHead>,
+        block: Option<&RequestBlock>,
+        hash: Option<&RequestBlock>,
+        seek: Option<&RequestSeek>,
+        upgrade: Option<&RequestUpgrade>,
+        infos: Option<&[StoreInfo]>,
+    ) -> Result<Either<Box<[StoreInfoInstruction]>, ValuelessProof>, HypercoreError> {
+        match head {
+            Some(head) => {
+                let mut head = head.clone();
+                self.swap_head(&mut head);
+                let result =
+                    self.create_valueless_proof_at_current_head(block, hash, seek, upgrade, infos);
+                self.swap_head(&mut head);
+                result
+            }
+            None => self.create_valueless_proof_at_current_head(block, hash, seek, upgrade, infos),
+        }
+    }
+
     /// Creates valueless proof from requests.
     /// TODO: This is now just a clone of javascript's
     /// https://github.com/holepunchto/hypercore/blob/9ce03363cb8938dbab53baba7d7cc9dde0508a7e/lib/merkle-tree.js#L1181
     /// The implementation should be rewritten to make it clearer.
-    pub(crate) fn create_valueless_proof(
+    fn create_valueless_proof_at_current_head(
         &mut self,
         block: Option<&RequestBlock>,
         hash: Option<&RequestBlock>,
@@ -343,7 +592,7 @@ impl MerkleTree {
         let nodes: IntMap<Option<Node>> = self.infos_to_nodes(infos)?;
         let mut instructions: Vec<StoreInfoInstruction> = Vec::new();
         let fork = self.fork;
-        let signature = self.signature;
+        let signature = self.signature.clone();
         let head = 2 * self.length;
         let (from, to) = if let Some(upgrade) = upgrade.as_ref() {
             let from = upgrade.start * 2;
@@ -482,10 +731,8 @@ impl MerkleTree {
                     length: upgrade.length,
                     nodes: p.upgrade.expect("nodes need to be set"),
                     additional_nodes: p.additional_upgrade.unwrap_or_default(),
-                    signature: signature
-                        .expect("signature needs to be set")
-                        .to_bytes()
-                        .to_vec(),
+                    // NB: Static hypercores have no signature
+                    signature: signature.map(<[u8]>::into_vec).unwrap_or_default(),
                 })
             } else {
                 None
@@ -507,7 +754,7 @@ impl MerkleTree {
     pub(crate) fn verify_proof(
         &mut self,
         proof: &Proof,
-        public_key: &VerifyingKey,
+        verifier: &Verifier,
         infos: Option<&[StoreInfo]>,
     ) -> Result<Either<Box<[StoreInfoInstruction]>, MerkleTreeChangeset>, HypercoreError> {
         let nodes: IntMap<Option<Node>> = self.infos_to_nodes(infos)?;
@@ -525,7 +772,7 @@ impl MerkleTree {
                 proof.fork,
                 upgrade,
                 unverified_block_root_node.as_ref(),
-                public_key,
+                verifier,
                 &mut changeset,
             )? {
                 unverified_block_root_node = None;
@@ -560,6 +807,96 @@ impl MerkleTree {
         }
     }
 
+    /// Verifies a proof received from a peer on a newer fork, which needs to upgrade from
+    /// length zero, and finds how much of this tree is shared with the new one. This is
+    /// `reorg` in Javascript, except that the shared ancestors are narrowed down only with the
+    /// nodes of the proof, instead of requesting more from the peer. NB: must be called in a
+    /// loop.
+    pub(crate) fn reorg(
+        &mut self,
+        proof: &Proof,
+        verifier: &Verifier,
+        infos: Option<&[StoreInfo]>,
+    ) -> Result<Either<Box<[StoreInfoInstruction]>, MerkleTreeChangeset>, HypercoreError> {
+        let upgrade = match proof.upgrade.as_ref() {
+            Some(upgrade) if upgrade.start == 0 => upgrade,
+            _ => {
+                return Err(HypercoreError::InvalidOperation {
+                    context: "Fork proof needs to upgrade from length zero".to_string(),
+                });
+            }
+        };
+        let nodes: IntMap<Option<Node>> = self.infos_to_nodes(infos)?;
+        let mut changeset = MerkleTreeChangeset::new_reorg(self.length, self.fork);
+
+        let unverified_block_root_node = verify_tree(
+            proof.block.as_ref(),
+            proof.hash.as_ref(),
+            proof.seek.as_ref(),
+            &mut changeset,
+        )?;
+        if !verify_upgrade(
+            proof.fork,
+            upgrade,
+            unverified_block_root_node.as_ref(),
+            verifier,
+            &mut changeset,
+        )? {
+            return Err(HypercoreError::InvalidOperation {
+                context: "Fork proof not verifiable".to_string(),
+            });
+        }
+
+        // The first root that differs from this tree contains the fork point
+        let mut diff: Option<Node> = None;
+        for root in &changeset.roots {
+            match self.optional_node(root.index, &nodes)? {
+                Either::Left(instruction) => {
+                    return Ok(Either::Left(vec![instruction].into_boxed_slice()));
+                }
+                Either::Right(Some(node)) if node.hash == root.hash => {}
+                Either::Right(_) => {
+                    diff = Some(root.clone());
+                    break;
+                }
+            }
+        }
+
+        // Narrow the fork point down the differing root as far as the proof has nodes
+        let mut ancestors = changeset.length;
+        while let Some(node) = diff.take() {
+            ancestors = flat_tree::left_span(node.index) / 2;
+            if node.index & 1 == 0 || ancestors >= self.length {
+                break;
+            }
+            let mut iter = flat_tree::Iterator::new(node.index);
+            let left_index = iter.left_child();
+            let left = match changeset.nodes.iter().find(|n| n.index == left_index) {
+                Some(left) => left,
+                None => break,
+            };
+            match self.optional_node(left_index, &nodes)? {
+                Either::Left(instruction) => {
+                    return Ok(Either::Left(vec![instruction].into_boxed_slice()));
+                }
+                Either::Right(Some(existing)) if existing.hash == left.hash => {
+                    let right_index = iter.sibling();
+                    ancestors = flat_tree::left_span(right_index) / 2;
+                    diff = changeset
+                        .nodes
+                        .iter()
+                        .find(|n| n.index == right_index)
+                        .cloned();
+                }
+                Either::Right(_) => {
+                    diff = Some(left.clone());
+                }
+            }
+        }
+        changeset.ancestors = std::cmp::min(ancestors, self.length);
+        Ok(Either::Right(changeset))
+    }
+
     /// Attempts to get missing nodes from given index. NB: must be called in a loop.
     pub(crate) fn missing_nodes(
         &mut self,
@@ -639,6 +976,12 @@ impl MerkleTree {
             for index_to_delete in unflushed_indices_to_delete {
                 self.unflushed.remove(index_to_delete);
             }
+
+            // Cached nodes past the truncation point are no longer valid
+            #[cfg(feature = "cache")]
+            if let Some(node_cache) = &self.node_cache {
+                node_cache.invalidate_all();
+            }
         }
     }
 
@@ -1325,6 +1668,11 @@ impl MerkleTree {
     }
 }
 
+/// Counts the leaves under the node of given merkle tree index.
+fn count_leaves(index: u64) -> u64 {
+    (flat_tree::right_span(index) - flat_tree::left_span(index)) / 2 + 1
+}
+
 /// Converts a hypercore index into a merkle tree index. In the flat tree
 /// representation, the leaves are in the even numbers, and the parents
 /// odd. That's why we need to double the hypercore index value to get
@@ -1399,7 +1747,7 @@ fn verify_upgrade(
     fork: u64,
     upgrade: &DataUpgrade,
     block_root: Option<&Node>,
-    public_key: &VerifyingKey,
+    verifier: &Verifier,
     changeset: &mut MerkleTreeChangeset,
 ) -> Result<bool, HypercoreError> {
     let mut q = if let Some(block_root) = block_root {
@@ -1457,7 +1805,7 @@ fn verify_upgrade(
         iter.sibling();
     }
     changeset.fork = fork;
-    changeset.verify_and_set_signature(&upgrade.signature, public_key)?;
+    changeset.verify_and_set_signature(&upgrade.signature, verifier)?;
     Ok(q.extra.is_none())
 }
 
@@ -1,9 +1,8 @@
-use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
-use std::convert::TryFrom;
+use ed25519_dalek::SigningKey;
 
 use crate::{
-    crypto::{signable_tree, verify, Hash},
-    sign, HypercoreError, Node,
+    crypto::{Hash, Verifier},
+    HypercoreError, Node,
 };
 
 /// Changeset for a `MerkleTree`. This allows to incrementally change a `MerkleTree` in two steps:
@@ -22,7 +21,7 @@ pub(crate) struct MerkleTreeChangeset {
     pub(crate) roots: Vec<Node>,
     pub(crate) nodes: Vec<Node>,
     pub(crate) hash: Option<Box<[u8]>>,
-    pub(crate) signature: Option<Signature>,
+    pub(crate) signature: Option<Box<[u8]>>,
     pub(crate) upgraded: bool,
 
     // Safeguarding values
@@ -53,6 +52,15 @@ impl MerkleTreeChangeset {
         }
     }
 
+    /// Changeset replacing a tree of given length and fork with one on a newer fork, which is
+    /// built up from length zero. This is called "ReorgBatch" in Javascript.
+    pub(crate) fn new_reorg(original_tree_length: u64, original_tree_fork: u64) -> Self {
+        Self {
+            original_tree_length,
+            ..Self::new(0, 0, original_tree_fork, vec![])
+        }
+    }
+
     pub(crate) fn append(&mut self, data: &[u8]) -> usize {
         let len = data.len();
         let head = self.length * 2;
@@ -91,31 +99,32 @@ impl MerkleTreeChangeset {
     }
 
     /// Hashes and signs the changeset
-    pub(crate) fn hash_and_sign(&mut self, signing_key: &SigningKey) {
+    pub(crate) fn hash_and_sign(
+        &mut self,
+        verifier: &Verifier,
+        signing_key: &SigningKey,
+    ) -> Result<(), HypercoreError> {
         let hash = self.hash();
-        let signable = self.signable(&hash);
-        let signature = sign(signing_key, &signable);
+        let signature = verifier.sign(signing_key, &hash, self.length, self.fork)?;
         self.hash = Some(hash);
         self.signature = Some(signature);
+        Ok(())
     }
 
-    /// Verify and set signature with given public key
+    /// Verify and set signature with given verifier. For hypercores with several signers,
+    /// the signature is a multisig that needs to verify for a quorum of them.
     pub(crate) fn verify_and_set_signature(
         &mut self,
         signature: &[u8],
-        public_key: &VerifyingKey,
+        verifier: &Verifier,
     ) -> Result<(), HypercoreError> {
-        // Verify that the received signature matches the public key
-        let signature =
-            Signature::try_from(signature).map_err(|_| HypercoreError::InvalidSignature {
-                context: "Could not parse signature".to_string(),
-            })?;
+        // Verify that the received signature matches the manifest
         let hash = self.hash();
-        verify(public_key, &self.signable(&hash), Some(&signature))?;
+        verifier.verify(signature, &hash, self.length, self.fork)?;
 
         // Set values to changeset
         self.hash = Some(hash);
-        self.signature = Some(signature);
+        self.signature = (!signature.is_empty()).then(|| signature.into());
         Ok(())
     }
 
@@ -123,9 +132,4 @@ impl MerkleTreeChangeset {
     pub(crate) fn hash(&self) -> Box<[u8]> {
         Hash::tree(&self.roots).as_bytes().into()
     }
-
-    /// Creates a signable slice from given hash
-    pub(crate) fn signable(&self, hash: &[u8]) -> Box<[u8]> {
-        signable_tree(hash, self.length, self.fork)
-    }
 }
@@ -1,5 +1,5 @@
 mod merkle_tree;
 mod merkle_tree_changeset;
 
-pub(crate) use merkle_tree::MerkleTree;
+pub(crate) use merkle_tree::{MerkleTree, MerkleTreeHead};
 pub(crate) use merkle_tree_changeset::MerkleTreeChangeset;
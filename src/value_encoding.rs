@@ -0,0 +1,289 @@
+//! Typed values stored in a [`Hypercore`]. A [`ValueEncoding`] converts values to and from
+//! the bytes stored in the hypercore, and [`TypedHypercore`] wraps a hypercore to append and
+//! read such values directly.
+//!
+//! ## Example
+//! ```rust
+//! # #[cfg(feature = "tokio")]
+//! # tokio_test::block_on(async {
+//! # example().await;
+//! # });
+//! # #[cfg(feature = "async-std")]
+//! # async_std::task::block_on(async {
+//! # example().await;
+//! # });
+//! # async fn example() {
+//! use hypercore::{value_encoding::Utf8, HypercoreBuilder, Storage, TypedHypercore};
+//!
+//! let hypercore = HypercoreBuilder::new(Storage::new_memory().await.unwrap())
+//!     .build()
+//!     .await
+//!     .unwrap();
+//! let mut hypercore: TypedHypercore<Utf8> = TypedHypercore::new(hypercore);
+//! hypercore.append(&"Hello".to_string()).await.unwrap();
+//! assert_eq!(hypercore.get(0).await.unwrap().unwrap(), "Hello");
+//! # }
+//! ```
+use compact_encoding::CompactEncoding;
+use futures::stream::{Stream, StreamExt};
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::ops::Range;
+
+use crate::{AppendOutcome, Hypercore, HypercoreError, Info, ReadStreamOptions};
+
+/// Encoding of values stored in a hypercore
+pub trait ValueEncoding {
+    /// Type of the encoded values
+    type Value;
+
+    /// Encode a value into bytes
+    fn encode(value: &Self::Value) -> Result<Vec<u8>, HypercoreError>;
+
+    /// Decode a value from bytes, failing with [`HypercoreError::Decode`]
+    fn decode(bytes: &[u8]) -> Result<Self::Value, HypercoreError>;
+}
+
+/// Values are raw bytes
+#[derive(Debug, Clone, Copy)]
+pub struct Binary;
+
+impl ValueEncoding for Binary {
+    type Value = Vec<u8>;
+
+    fn encode(value: &Self::Value) -> Result<Vec<u8>, HypercoreError> {
+        Ok(value.clone())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self::Value, HypercoreError> {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Values are UTF-8 strings
+#[derive(Debug, Clone, Copy)]
+pub struct Utf8;
+
+impl ValueEncoding for Utf8 {
+    type Value = String;
+
+    fn encode(value: &Self::Value) -> Result<Vec<u8>, HypercoreError> {
+        Ok(value.as_bytes().to_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self::Value, HypercoreError> {
+        String::from_utf8(bytes.to_vec()).map_err(|err| HypercoreError::Decode {
+            context: format!("Invalid UTF-8: {err}"),
+        })
+    }
+}
+
+/// Values are JSON documents of type `T`
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy)]
+pub struct Json<T>(PhantomData<fn() -> T>);
+
+#[cfg(feature = "json")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> ValueEncoding for Json<T> {
+    type Value = T;
+
+    fn encode(value: &Self::Value) -> Result<Vec<u8>, HypercoreError> {
+        serde_json::to_vec(value).map_err(|err| HypercoreError::BadArgument {
+            context: format!("Could not encode value as JSON: {err}"),
+        })
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self::Value, HypercoreError> {
+        serde_json::from_slice(bytes).map_err(|err| HypercoreError::Decode {
+            context: format!("Invalid JSON: {err}"),
+        })
+    }
+}
+
+/// Values of type `T` encoded with [`CompactEncoding`]
+#[derive(Debug, Clone, Copy)]
+pub struct Compact<T>(PhantomData<fn() -> T>);
+
+impl<T: CompactEncoding> ValueEncoding for Compact<T> {
+    type Value = T;
+
+    fn encode(value: &Self::Value) -> Result<Vec<u8>, HypercoreError> {
+        Ok(value.to_encoded_bytes()?.into_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self::Value, HypercoreError> {
+        let (value, rest) = T::decode(bytes).map_err(|err| HypercoreError::Decode {
+            context: format!("Invalid compact encoding: {err}"),
+        })?;
+        if !rest.is_empty() {
+            return Err(HypercoreError::Decode {
+                context: format!("{} trailing bytes after value", rest.len()),
+            });
+        }
+        Ok(value)
+    }
+}
+
+/// [`Hypercore`] that appends and reads values of a [`ValueEncoding`]
+#[derive(Debug)]
+pub struct TypedHypercore<E> {
+    core: Hypercore,
+    encoding: PhantomData<fn() -> E>,
+}
+
+impl<E: ValueEncoding> From<Hypercore> for TypedHypercore<E> {
+    fn from(core: Hypercore) -> Self {
+        Self::new(core)
+    }
+}
+
+impl<E: ValueEncoding> TypedHypercore<E> {
+    /// Wrap a hypercore
+    pub fn new(core: Hypercore) -> Self {
+        Self {
+            core,
+            encoding: PhantomData,
+        }
+    }
+
+    /// Access the wrapped hypercore
+    pub fn inner(&self) -> &Hypercore {
+        &self.core
+    }
+
+    /// Access the wrapped hypercore mutably
+    pub fn inner_mut(&mut self) -> &mut Hypercore {
+        &mut self.core
+    }
+
+    /// Unwrap the hypercore
+    pub fn into_inner(self) -> Hypercore {
+        self.core
+    }
+
+    /// Gets basic info about the hypercore, see [`Hypercore::info`]
+    pub fn info(&self) -> Info {
+        self.core.info()
+    }
+
+    /// Appends a value, see [`Hypercore::append`]
+    pub async fn append(&mut self, value: &E::Value) -> Result<AppendOutcome, HypercoreError> {
+        let data = E::encode(value)?;
+        self.core.append(&data).await
+    }
+
+    /// Appends a batch of values, see [`Hypercore::append_batch`]
+    pub async fn append_batch(
+        &mut self,
+        values: &[E::Value],
+    ) -> Result<AppendOutcome, HypercoreError> {
+        let batch = values
+            .iter()
+            .map(E::encode)
+            .collect::<Result<Vec<Vec<u8>>, HypercoreError>>()?;
+        self.core.append_batch(&batch).await
+    }
+
+    /// Read value at given index, if any, see [`Hypercore::get`]
+    pub async fn get(&mut self, index: u64) -> Result<Option<E::Value>, HypercoreError> {
+        self.core
+            .get(index)
+            .await?
+            .map(|data| E::decode(&data))
+            .transpose()
+    }
+
+    /// Read values between start and end (exclusive), see [`Hypercore::get_range`]
+    pub async fn get_range(
+        &mut self,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<Option<E::Value>>, HypercoreError> {
+        self.core
+            .get_range(start, end)
+            .await?
+            .into_iter()
+            .map(|data| data.map(|data| E::decode(&data)).transpose())
+            .collect()
+    }
+
+    /// Stream values in the given index range, see [`Hypercore::read_stream`]
+    pub fn read_stream(
+        &mut self,
+        range: Range<u64>,
+        options: ReadStreamOptions,
+    ) -> impl Stream<Item = Result<E::Value, HypercoreError>> + '_ {
+        self.core
+            .read_stream(range, options)
+            .map(|data| data.and_then(|data| E::decode(&data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::tests::create_hypercore_with_data;
+    use crate::Node;
+    use futures::TryStreamExt;
+
+    #[async_std::test]
+    async fn typed_utf8() -> Result<(), HypercoreError> {
+        let mut core: TypedHypercore<Utf8> = create_hypercore_with_data(0).await?.into();
+        core.append(&"foo".to_string()).await?;
+        let outcome = core
+            .append_batch(&["bar".to_string(), "baz".to_string()])
+            .await?;
+        assert_eq!(outcome.length, 3);
+        assert_eq!(core.get(0).await?, Some("foo".to_string()));
+        assert_eq!(core.get(3).await?, None);
+        assert_eq!(
+            core.get_range(1, 4).await?,
+            [Some("bar".to_string()), Some("baz".to_string()), None]
+        );
+        let values: Vec<String> = core
+            .read_stream(0..3, ReadStreamOptions::default())
+            .try_collect()
+            .await?;
+        assert_eq!(values, ["foo", "bar", "baz"]);
+
+        // Invalid values fail to decode
+        core.inner_mut().append(&[0xff, 0xfe]).await?;
+        assert!(matches!(
+            core.get(3).await,
+            Err(HypercoreError::Decode { .. })
+        ));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn typed_binary_and_compact() -> Result<(), HypercoreError> {
+        let mut core: TypedHypercore<Binary> = create_hypercore_with_data(1).await?.into();
+        assert_eq!(core.get(0).await?, Some(b"#0".to_vec()));
+
+        let node = Node::new(4, vec![1; 32], 10);
+        let mut core: TypedHypercore<Compact<Node>> = core.into_inner().into();
+        core.append(&node).await?;
+        assert_eq!(core.get(1).await?, Some(node));
+        assert!(matches!(
+            core.get(0).await,
+            Err(HypercoreError::Decode { .. })
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[async_std::test]
+    async fn typed_json() -> Result<(), HypercoreError> {
+        let mut core: TypedHypercore<Json<serde_json::Value>> =
+            create_hypercore_with_data(0).await?.into();
+        let value = serde_json::json!({ "hello": "world", "list": [1, 2] });
+        core.append(&value).await?;
+        assert_eq!(core.get(0).await?, Some(value));
+        core.inner_mut().append(b"{").await?;
+        assert!(matches!(
+            core.get(1).await,
+            Err(HypercoreError::Decode { .. })
+        ));
+        Ok(())
+    }
+}
@@ -2,7 +2,12 @@ pub mod common;
 
 use anyhow::Result;
 use common::{create_hypercore, get_test_key_pair, open_hypercore, storage_contains_data};
-use hypercore::{HypercoreBuilder, Storage};
+use hypercore::{
+    generate_signing_key, CoreId, Durability, HypercoreBuilder, Manifest, PartialKeypair,
+    PartialSignature, RequestBlock, RequestUpgrade, SigningKey, Storage, VerifyingKey,
+};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
 use tempfile::Builder;
 use test_log::test;
 
@@ -77,3 +82,524 @@ async fn hypercore_make_read_only() -> Result<()> {
     assert_eq!(&hypercore.get(1).await?.unwrap(), b"World!");
     Ok(())
 }
+
+#[test(async_test)]
+async fn hypercore_truncate() -> Result<()> {
+    let dir = Builder::new()
+        .prefix("hypercore_truncate")
+        .tempdir()
+        .unwrap();
+    {
+        let mut hypercore = create_hypercore(&dir.path().to_string_lossy()).await?;
+        hypercore.append_batch(&[b"a", b"b", b"c", b"d"]).await?;
+        hypercore.truncate(2, None).await?;
+        hypercore.append(b"e").await?;
+    }
+
+    let mut hypercore = open_hypercore(&dir.path().to_string_lossy()).await?;
+    let info = hypercore.info();
+    assert_eq!(info.length, 3);
+    assert_eq!(info.byte_length, 3);
+    assert_eq!(info.fork, 1);
+    assert_eq!(&hypercore.get(1).await?.unwrap(), b"b");
+    assert_eq!(&hypercore.get(2).await?.unwrap(), b"e");
+    assert!(hypercore.get(3).await?.is_none());
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_user_data() -> Result<()> {
+    let dir = Builder::new()
+        .prefix("hypercore_user_data")
+        .tempdir()
+        .unwrap();
+    {
+        let mut hypercore = create_hypercore(&dir.path().to_string_lossy()).await?;
+        hypercore.set_user_data("a", Some(b"1")).await?;
+        hypercore.set_user_data("b", Some(b"2")).await?;
+        hypercore.set_user_data("a", None).await?;
+        hypercore.append(b"Hello").await?;
+        hypercore.set_user_data("c", Some(b"3")).await?;
+    }
+
+    let hypercore = open_hypercore(&dir.path().to_string_lossy()).await?;
+    assert_eq!(hypercore.get_user_data("a"), None);
+    assert_eq!(hypercore.get_user_data("b"), Some(&b"2"[..]));
+    assert_eq!(hypercore.get_user_data("c"), Some(&b"3"[..]));
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_open_by_id() -> Result<()> {
+    let dir = Builder::new()
+        .prefix("hypercore_open_by_id")
+        .tempdir()
+        .unwrap();
+    let id = {
+        let mut hypercore = create_hypercore(&dir.path().to_string_lossy()).await?;
+        hypercore.append(b"Hello").await?;
+        hypercore.id()
+    };
+    assert_eq!(id.discovery_key(), hypercore::discovery_key(id.key()));
+    let id: CoreId = id.to_url().parse()?;
+
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    let mut hypercore = HypercoreBuilder::new(storage).key(id).build().await?;
+    assert_eq!(hypercore.discovery_key(), id.discovery_key());
+    assert_eq!(hypercore.id(), id);
+    assert_eq!(&hypercore.get(0).await?.unwrap(), b"Hello");
+    drop(hypercore);
+
+    // Opening with the key of another hypercore fails
+    let other = CoreId::from(generate_signing_key().verifying_key());
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    assert!(HypercoreBuilder::new(storage)
+        .key(other)
+        .build()
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_manifest() -> Result<()> {
+    let key_pair = get_test_key_pair();
+    let manifest = Manifest::new_single_signer(&key_pair.public);
+    let mut main = HypercoreBuilder::new(Storage::new_memory().await?)
+        .key_pair(key_pair.clone())
+        .manifest(manifest.clone())
+        .build()
+        .await?;
+    assert_eq!(main.id().key(), &manifest.key()?);
+    assert_ne!(main.id().key(), key_pair.public.as_bytes());
+    assert_eq!(main.manifest(), &manifest);
+    main.append_batch(&[b"a", b"b", b"c"]).await?;
+
+    // A replica verifies the signatures against the manifest
+    let mut clone = HypercoreBuilder::new(Storage::new_memory().await?)
+        .key(main.id())
+        .manifest(manifest.clone())
+        .build()
+        .await?;
+    assert!(clone.key_pair().secret.is_none());
+    let proof = main
+        .create_proof(
+            Some(RequestBlock { index: 1, nodes: 0 }),
+            None,
+            None,
+            Some(RequestUpgrade {
+                start: 0,
+                length: 3,
+            }),
+        )
+        .await?
+        .unwrap();
+    assert!(clone.verify_and_apply_proof(&proof).await?);
+    assert_eq!(clone.info().length, 3);
+    assert_eq!(&clone.get(1).await?.unwrap(), b"b");
+
+    // A compat replica with the same key pair does not accept the signatures
+    let mut compat = HypercoreBuilder::new(Storage::new_memory().await?)
+        .key_pair(PartialKeypair {
+            public: key_pair.public,
+            secret: None,
+        })
+        .build()
+        .await?;
+    assert!(compat.verify_and_apply_proof(&proof).await.is_err());
+
+    // Only signers of the manifest can append
+    let mut other = HypercoreBuilder::new(Storage::new_memory().await?)
+        .key_pair(get_test_key_pair())
+        .manifest(Manifest::new_single_signer(
+            &generate_signing_key().verifying_key(),
+        ))
+        .build()
+        .await?;
+    assert!(other.append(b"a").await.is_err());
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_open_manifest_by_id() -> Result<()> {
+    let dir = Builder::new()
+        .prefix("hypercore_open_manifest_by_id")
+        .tempdir()
+        .unwrap();
+    let key_pair = get_test_key_pair();
+    let manifest = Manifest::new_single_signer(&key_pair.public);
+    let id = {
+        let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+        let mut hypercore = HypercoreBuilder::new(storage)
+            .key_pair(key_pair)
+            .manifest(manifest.clone())
+            .build()
+            .await?;
+        hypercore.append(b"Hello").await?;
+        hypercore.id()
+    };
+
+    // The key is the manifest hash, the key pair and manifest come from storage
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    let mut hypercore = HypercoreBuilder::new(storage).key(id).build().await?;
+    assert_eq!(hypercore.id(), id);
+    assert_eq!(hypercore.manifest(), &manifest);
+    assert!(hypercore.key_pair().secret.is_some());
+    assert_eq!(&hypercore.get(0).await?.unwrap(), b"Hello");
+    hypercore.append(b"World").await?;
+    drop(hypercore);
+
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_multi_signer() -> Result<()> {
+    let signing_keys: Vec<SigningKey> = (0..3).map(|_| generate_signing_key()).collect();
+    let public_keys: Vec<VerifyingKey> =
+        signing_keys.iter().map(|key| key.verifying_key()).collect();
+    let manifest = Manifest::new_multi_signer(2, &public_keys)?;
+    let mut main = HypercoreBuilder::new(Storage::new_memory().await?)
+        .key(manifest.key()?.into())
+        .manifest(manifest.clone())
+        .build()
+        .await?;
+    assert!(main.key_pair().secret.is_none());
+    assert!(main.append(b"a").await.is_err());
+
+    // Signers sign the tree after the append, and a quorum of them is assembled
+    let batch: &[&[u8]] = &[b"a", b"b", b"c"];
+    let partials: Vec<PartialSignature> = signing_keys
+        .iter()
+        .map(|key| main.sign_batch(batch, key))
+        .collect::<Result<_, _>>()?;
+    let below_quorum = manifest.assemble_signature(&partials[..1])?;
+    assert!(main
+        .append_batch_with_signature(batch, &below_quorum)
+        .await
+        .is_err());
+    assert_eq!(main.info().length, 0);
+    let signature = manifest.assemble_signature(&[partials[2].clone(), partials[0].clone()])?;
+    main.append_batch_with_signature(batch, &signature).await?;
+    assert_eq!(main.info().length, 3);
+
+    // A replica verifies the quorum of the upgrade
+    let mut clone = HypercoreBuilder::new(Storage::new_memory().await?)
+        .key(main.id())
+        .manifest(manifest.clone())
+        .build()
+        .await?;
+    let proof = main
+        .create_proof(
+            Some(RequestBlock { index: 2, nodes: 0 }),
+            None,
+            None,
+            Some(RequestUpgrade {
+                start: 0,
+                length: 3,
+            }),
+        )
+        .await?
+        .unwrap();
+    assert!(clone.verify_and_apply_proof(&proof).await?);
+    assert_eq!(clone.info().length, 3);
+    assert_eq!(&clone.get(2).await?.unwrap(), b"c");
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_static() -> Result<()> {
+    let dir = Builder::new().prefix("hypercore_static").tempdir().unwrap();
+    let original_id = {
+        let mut hypercore = create_hypercore(&dir.path().to_string_lossy()).await?;
+        hypercore.append_batch(&[b"a", b"b", b"c"]).await?;
+        hypercore.id()
+    };
+
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    let mut main = HypercoreBuilder::new(storage)
+        .open(true)
+        .freeze(true)
+        .build()
+        .await?;
+    let manifest = main.manifest().clone();
+    assert!(manifest.is_static());
+    assert_eq!(main.id().key(), &manifest.key()?);
+    assert_ne!(main.id(), original_id);
+    assert!(!main.info().writeable);
+    assert!(main.append(b"d").await.is_err());
+    assert!(main.truncate(2, None).await.is_err());
+    // The secret key of the writer is not erased
+    assert!(main.key_pair().secret.is_some());
+    drop(main);
+
+    let mut main = open_hypercore(&dir.path().to_string_lossy()).await?;
+    assert_eq!(main.manifest(), &manifest);
+    assert_eq!(main.info().length, 3);
+    assert_eq!(&main.get(2).await?.unwrap(), b"c");
+
+    // A replica verifies the upgrade against the pinned tree hash
+    let mut clone = HypercoreBuilder::new(Storage::new_memory().await?)
+        .key(main.id())
+        .manifest(manifest.clone())
+        .build()
+        .await?;
+    let proof = main
+        .create_proof(
+            Some(RequestBlock { index: 1, nodes: 0 }),
+            None,
+            None,
+            Some(RequestUpgrade {
+                start: 0,
+                length: 3,
+            }),
+        )
+        .await?
+        .unwrap();
+    assert!(clone.verify_and_apply_proof(&proof).await?);
+    assert_eq!(clone.info().length, 3);
+    assert_eq!(&clone.get(1).await?.unwrap(), b"b");
+
+    // A static hypercore pinning another tree does not accept the upgrade
+    let other = Manifest::new_static([0; 32], 3);
+    let mut other = HypercoreBuilder::new(Storage::new_memory().await?)
+        .key(other.key()?.into())
+        .manifest(other)
+        .build()
+        .await?;
+    assert!(other.verify_and_apply_proof(&proof).await.is_err());
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_static_encrypted() -> Result<()> {
+    let dir = Builder::new()
+        .prefix("hypercore_static_encrypted")
+        .tempdir()
+        .unwrap();
+    let id = {
+        let storage = Storage::new_disk(&dir.path().to_owned(), true).await?;
+        let mut hypercore = HypercoreBuilder::new(storage)
+            .key_pair(get_test_key_pair())
+            .encryption_key([7; 32])
+            .build()
+            .await?;
+        hypercore.append_batch(&[b"a", b"b"]).await?;
+        assert!(hypercore.get_user_data("hypercore/encrypted").is_some());
+        hypercore.id()
+    };
+
+    // Freezing would change the key the blocks are encrypted with
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    assert!(HypercoreBuilder::new(storage)
+        .open(true)
+        .freeze(true)
+        .build()
+        .await
+        .is_err());
+
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    let mut hypercore = HypercoreBuilder::new(storage)
+        .open(true)
+        .encryption_key([7; 32])
+        .build()
+        .await?;
+    assert_eq!(hypercore.id(), id);
+    assert_eq!(&hypercore.get(1).await?.unwrap(), b"b");
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_batch() -> Result<()> {
+    let dir = Builder::new().prefix("hypercore_batch").tempdir().unwrap();
+    {
+        let mut hypercore = create_hypercore(&dir.path().to_string_lossy()).await?;
+        hypercore.append(b"a").await?;
+        let mut batch = hypercore.batch();
+        batch.append(b"b")?;
+        batch.append(b"c")?;
+        assert_eq!(&batch.get(2).await?.unwrap(), b"c");
+        batch.flush().await?;
+
+        let mut batch = hypercore.batch();
+        batch.append(b"d")?;
+        batch.discard();
+    }
+
+    let mut hypercore = open_hypercore(&dir.path().to_string_lossy()).await?;
+    assert_eq!(hypercore.info().length, 3);
+    assert_eq!(&hypercore.get(1).await?.unwrap(), b"b");
+    assert_eq!(&hypercore.get(2).await?.unwrap(), b"c");
+    assert!(hypercore.get(3).await?.is_none());
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_flush_and_close() -> Result<()> {
+    let dir = Builder::new()
+        .prefix("hypercore_flush_and_close")
+        .tempdir()
+        .unwrap();
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    let mut hypercore = HypercoreBuilder::new(storage)
+        .flush_interval(100)
+        .build()
+        .await?;
+    hypercore.append_batch(&[b"a", b"b"]).await?;
+    hypercore.flush().await?;
+    hypercore.append(b"c").await?;
+    hypercore.close().await?;
+
+    let mut hypercore = open_hypercore(&dir.path().to_string_lossy()).await?;
+    assert_eq!(hypercore.info().length, 3);
+    assert_eq!(&hypercore.get(2).await?.unwrap(), b"c");
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_durability() -> Result<()> {
+    let dir = Builder::new()
+        .prefix("hypercore_durability")
+        .tempdir()
+        .unwrap();
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    let mut hypercore = HypercoreBuilder::new(storage)
+        .durability(Durability::SyncEveryAppend)
+        .flush_interval(100)
+        .build()
+        .await?;
+    assert!(hypercore.append(b"a").await?.durable);
+    assert!(hypercore.append(b"b").await?.durable);
+    drop(hypercore);
+
+    let mut hypercore = open_hypercore(&dir.path().to_string_lossy()).await?;
+    assert_eq!(hypercore.info().length, 2);
+    assert_eq!(&hypercore.get(1).await?.unwrap(), b"b");
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_bitfield_memory_budget() -> Result<()> {
+    let dir = Builder::new()
+        .prefix("hypercore_bitfield_memory_budget")
+        .tempdir()
+        .unwrap();
+    let mut hypercore = create_hypercore(&dir.path().to_string_lossy()).await?;
+    // Spans two pages of the bitfield
+    let batch = vec![b"a"; 10_000];
+    for _ in 0..5 {
+        hypercore.append_batch(&batch).await?;
+    }
+    hypercore.clear(40_000, 40_010).await?;
+    drop(hypercore);
+
+    // Memory for one page, the others are read from storage when needed
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    let mut hypercore = HypercoreBuilder::new(storage)
+        .bitfield_memory_budget(4096)
+        .open(true)
+        .build()
+        .await?;
+    assert!(hypercore.has(0).await?);
+    assert!(!hypercore.has(40_005).await?);
+    assert!(hypercore.has(49_999).await?);
+    assert_eq!(hypercore.first_missing(0).await?, Some(40_000));
+    assert_eq!(hypercore.last_present(40_010).await?, Some(39_999));
+    assert!(hypercore.has_range(40_010, 50_000).await?);
+    assert_eq!(hypercore.available_blocks().await?, 49_990);
+    let exported = hypercore.export_bitfield(0, 50_000).await?;
+    let mut remote = hypercore::RemoteBitfield::new();
+    remote.import(&exported)?;
+    assert_eq!(remote.count(0, 50_000), 49_990);
+
+    hypercore.clear(45_000, 45_001).await?;
+    hypercore.append(b"b").await?;
+    drop(hypercore);
+
+    let mut hypercore = open_hypercore(&dir.path().to_string_lossy()).await?;
+    assert_eq!(hypercore.available_blocks().await?, 49_990);
+    assert!(!hypercore.has(45_000).await?);
+    assert_eq!(&hypercore.get(50_000).await?.unwrap(), b"b");
+    Ok(())
+}
+
+#[test(async_test)]
+async fn hypercore_recover_oplog() -> Result<()> {
+    let dir = Builder::new()
+        .prefix("hypercore_recover_oplog")
+        .tempdir()
+        .unwrap();
+    let path = dir.path().to_string_lossy();
+    {
+        let mut hypercore = create_hypercore(&path).await?;
+        hypercore.append_batch(&[b"a", b"b", b"c"]).await?;
+        hypercore.set_user_data("a", Some(b"1")).await?;
+        hypercore.flush().await?;
+        hypercore.append(b"d").await?;
+    }
+    let public_key = get_test_key_pair().public;
+    let overwrite_oplog = |index: u64, data: &[u8]| -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(dir.path().join("oplog"))?;
+        file.seek(SeekFrom::Start(index))?;
+        file.write_all(data)?;
+        Ok(())
+    };
+
+    // Both checksums broken, the damaged headers still have a valid signature
+    overwrite_oplog(0, &[0xff; 4])?;
+    overwrite_oplog(4096, &[0xff; 4])?;
+    assert!(open_hypercore(&path).await.is_err());
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    let mut hypercore = HypercoreBuilder::new(storage)
+        .key(CoreId::from(public_key))
+        .recover(true)
+        .build()
+        .await?;
+    assert_eq!(hypercore.info().length, 4);
+    assert_eq!(hypercore.info().contiguous_length, 4);
+    assert_eq!(&hypercore.get(3).await?.unwrap(), b"d");
+    assert_eq!(hypercore.get_user_data("a"), Some(&b"1"[..]));
+    assert!(hypercore.verify_integrity(0..4).await?.is_ok());
+    // The secret key is taken from the damaged headers
+    assert!(hypercore.info().writeable);
+    drop(hypercore);
+    assert!(open_hypercore(&path).await?.info().writeable);
+
+    // Both header slots wiped, the tree is rebuilt from the tree store
+    overwrite_oplog(0, &[0; 8192])?;
+    assert!(open_hypercore(&path).await.is_err());
+    // Signing it again needs the fork, which is lost
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    assert!(HypercoreBuilder::new(storage)
+        .key_pair(get_test_key_pair())
+        .recover(true)
+        .build()
+        .await
+        .is_err());
+    // Without the secret key the tree can not be signed at all
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    assert!(HypercoreBuilder::new(storage)
+        .key(CoreId::from(public_key))
+        .recover(true)
+        .recovery_fork(0)
+        .build()
+        .await
+        .is_err());
+    let storage = Storage::new_disk(&dir.path().to_owned(), false).await?;
+    let mut hypercore = HypercoreBuilder::new(storage)
+        .key_pair(get_test_key_pair())
+        .recover(true)
+        .recovery_fork(0)
+        .build()
+        .await?;
+    assert_eq!(hypercore.info().length, 4);
+    assert!(hypercore.verify_integrity(0..4).await?.is_ok());
+    hypercore.append(b"e").await?;
+    drop(hypercore);
+
+    let mut hypercore = open_hypercore(&path).await?;
+    assert_eq!(hypercore.info().length, 5);
+    assert_eq!(&hypercore.get(4).await?.unwrap(), b"e");
+    Ok(())
+}
@@ -0,0 +1,81 @@
+#![cfg(feature = "shared-core")]
+
+use anyhow::Result;
+use hypercore::replication::{CoreInfo, CoreMethods};
+use hypercore::Corestore;
+use tempfile::Builder;
+use test_log::test;
+
+#[cfg(feature = "async-std")]
+use async_std::test as async_test;
+#[cfg(feature = "tokio")]
+use tokio::test as async_test;
+
+#[test(async_test)]
+async fn corestore_disk() -> Result<()> {
+    let dir = Builder::new().prefix("corestore_disk").tempdir().unwrap();
+    let (primary_key, id) = {
+        let store = Corestore::new_disk(dir.path()).await?;
+        let core = store.namespace("ns").get_by_name("a").await?;
+        core.append(b"hello").await?;
+        let id = core.0.lock().await.id();
+        (*store.primary_key(), id)
+    };
+    let discovery_key = hypercore::CoreId::new(id.discovery_key()).to_hex();
+    assert!(dir
+        .path()
+        .join("cores")
+        .join(&discovery_key[0..2])
+        .join(&discovery_key[2..4])
+        .join(&discovery_key)
+        .join("oplog")
+        .exists());
+
+    let store = Corestore::new_disk(dir.path()).await?;
+    assert_eq!(store.primary_key(), &primary_key);
+    let core = store.get_by_key(id).await?;
+    assert_eq!(core.get(0).await?, Some(b"hello".to_vec()));
+    assert!(core.key_pair().await.secret.is_some());
+    let same = store.namespace("ns").get_by_name("a").await?;
+    assert_eq!(same.info().await.length, 1);
+    Ok(())
+}
+
+#[test(async_test)]
+async fn corestore_disk_invalid_primary_key() -> Result<()> {
+    let dir = Builder::new()
+        .prefix("corestore_disk_invalid_primary_key")
+        .tempdir()
+        .unwrap();
+    let path = dir.path().join("primary-key");
+    std::fs::write(&path, [1; 16])?;
+    assert!(Corestore::new_disk(dir.path()).await.is_err());
+    // The primary key is not overwritten
+    assert_eq!(std::fs::read(&path)?, [1; 16]);
+    Ok(())
+}
+
+#[test(async_test)]
+async fn corestore_disk_opened_by_key_first() -> Result<()> {
+    let dir = Builder::new()
+        .prefix("corestore_disk_opened_by_key_first")
+        .tempdir()
+        .unwrap();
+    let id = {
+        let store = Corestore::new_disk(dir.path()).await?;
+        let id = hypercore::CoreId::from(store.create_key_pair("a").public);
+        let core = store.get_by_key(id).await?;
+        assert!(core.key_pair().await.secret.is_none());
+        let core = store.get_by_name("a").await?;
+        core.append(b"hello").await?;
+        core.0.lock().await.flush().await?;
+        id
+    };
+
+    // The secret key is stored in the hypercore
+    let store = Corestore::new_disk(dir.path()).await?;
+    let core = store.get_by_key(id).await?;
+    assert!(core.key_pair().await.secret.is_some());
+    assert_eq!(core.get(0).await?, Some(b"hello".to_vec()));
+    Ok(())
+}
@@ -22,6 +22,10 @@ enum Op {
         #[proptest(strategy(divisor_strategy))]
         len_divisor_for_length: u8,
     },
+    Truncate {
+        #[proptest(strategy(divisor_strategy))]
+        len_divisor_for_length: u8,
+    },
 }
 
 fn index_strategy() -> impl Strategy<Value = u64> {
@@ -121,6 +125,16 @@ async fn assert_implementation_matches_model(ops: Vec<Op>) -> bool {
                     .expect("Clear should be successful");
                 model[start as usize..model_end as usize].fill(None);
             }
+            Op::Truncate {
+                len_divisor_for_length,
+            } => {
+                let length = model.len() as u64 / len_divisor_for_length as u64;
+                hypercore
+                    .truncate(length, None)
+                    .await
+                    .expect("Truncate should be successful");
+                model.truncate(length as usize);
+            }
         }
     }
     true